@@ -0,0 +1,271 @@
+//! Protocol compliance checklist
+//!
+//! Connects to an SMTP server as a client and exercises a battery of
+//! RFC 5321 behaviors, reporting pass/fail for each one.
+//!
+//! ```rust,no_run
+//! let report = mogimail::compliance::run_checklist("127.0.0.1:2525");
+//! for check in &report.checks {
+//!     println!("{check}");
+//! }
+//! assert!(report.all_passed());
+//! ```
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// How long to wait for a reply before failing a check
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The outcome of a single compliance check
+#[derive(Debug, Clone)]
+pub struct ComplianceCheck {
+    /// Short description of the behavior being checked
+    pub name: &'static str,
+    /// Whether the server behaved as expected
+    pub passed: bool,
+    /// What was observed, useful when the check fails
+    pub detail: String,
+}
+
+impl fmt::Display for ComplianceCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.passed { "PASS" } else { "FAIL" };
+        write!(f, "[{status}] {}: {}", self.name, self.detail)
+    }
+}
+
+/// Results of running the compliance checklist
+#[derive(Debug, Clone, Default)]
+pub struct ComplianceReport {
+    /// Individual check results, in the order they were run
+    pub checks: Vec<ComplianceCheck>,
+}
+
+impl ComplianceReport {
+    /// Check if every check passed
+    pub fn all_passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+
+    /// Get the checks that failed
+    pub fn failures(&self) -> impl Iterator<Item = &ComplianceCheck> {
+        self.checks.iter().filter(|check| !check.passed)
+    }
+
+    /// Look up a check by name
+    pub fn get(&self, name: &str) -> Option<&ComplianceCheck> {
+        self.checks.iter().find(|check| check.name == name)
+    }
+}
+
+/// Run the compliance checklist against the server at `addr`
+///
+/// Each check uses its own connection, so one misbehaving check does not
+/// affect the others. Connection failures are reported as failed checks.
+pub fn run_checklist(addr: &str) -> ComplianceReport {
+    type Check = fn(&str) -> io::Result<(bool, String)>;
+
+    let checklist: [(&'static str, Check); 10] = [
+        ("greeting is 220", check_greeting),
+        ("HELO accepted", check_helo),
+        ("MAIL before HELO rejected with 503", check_mail_before_helo),
+        ("RCPT before MAIL rejected with 503", check_rcpt_before_mail),
+        ("DATA before RCPT rejected with 503", check_data_before_rcpt),
+        ("unknown command rejected with 500", check_unknown_command),
+        ("NOOP replies 250", check_noop),
+        ("RSET replies 250", check_rset),
+        ("mail transaction accepted", check_transaction),
+        ("QUIT replies 221", check_quit),
+    ];
+
+    let checks = checklist
+        .into_iter()
+        .map(|(name, check)| {
+            let (passed, detail) = check(addr).unwrap_or_else(|e| (false, e.to_string()));
+            ComplianceCheck {
+                name,
+                passed,
+                detail,
+            }
+        })
+        .collect();
+
+    ComplianceReport { checks }
+}
+
+/// Minimal SMTP client used to drive the checks
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    greeting: String,
+}
+
+impl Client {
+    /// Connect and read the greeting
+    fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        let mut client = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            stream,
+            greeting: String::new(),
+        };
+        client.greeting = client.read_reply()?;
+        Ok(client)
+    }
+
+    /// Connect and identify with HELO
+    fn connect_with_helo(addr: &str) -> io::Result<Self> {
+        let mut client = Self::connect(addr)?;
+        client.command("HELO compliance.local")?;
+        Ok(client)
+    }
+
+    /// Send a command line and return the full reply
+    fn command(&mut self, line: &str) -> io::Result<String> {
+        write!(self.stream, "{line}\r\n")?;
+        self.stream.flush()?;
+        self.read_reply()
+    }
+
+    /// Read a possibly multiline reply, returning its last line
+    fn read_reply(&mut self) -> io::Result<String> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed",
+                ));
+            }
+
+            // Continuation lines have a '-' after the code
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(line.trim_end().to_string());
+            }
+        }
+    }
+}
+
+/// Compare the code of `reply` against `expected`
+fn expect_code(reply: &str, expected: &str) -> (bool, String) {
+    (reply.starts_with(expected), reply.to_string())
+}
+
+fn check_greeting(addr: &str) -> io::Result<(bool, String)> {
+    let client = Client::connect(addr)?;
+    Ok(expect_code(&client.greeting, "220"))
+}
+
+fn check_helo(addr: &str) -> io::Result<(bool, String)> {
+    let mut client = Client::connect(addr)?;
+    let reply = client.command("HELO compliance.local")?;
+    Ok(expect_code(&reply, "250"))
+}
+
+fn check_mail_before_helo(addr: &str) -> io::Result<(bool, String)> {
+    let mut client = Client::connect(addr)?;
+    let reply = client.command("MAIL FROM:<sender@example.com>")?;
+    Ok(expect_code(&reply, "503"))
+}
+
+fn check_rcpt_before_mail(addr: &str) -> io::Result<(bool, String)> {
+    let mut client = Client::connect_with_helo(addr)?;
+    let reply = client.command("RCPT TO:<recipient@example.com>")?;
+    Ok(expect_code(&reply, "503"))
+}
+
+fn check_data_before_rcpt(addr: &str) -> io::Result<(bool, String)> {
+    let mut client = Client::connect_with_helo(addr)?;
+    client.command("MAIL FROM:<sender@example.com>")?;
+    let reply = client.command("DATA")?;
+    Ok(expect_code(&reply, "503"))
+}
+
+fn check_unknown_command(addr: &str) -> io::Result<(bool, String)> {
+    let mut client = Client::connect(addr)?;
+    let reply = client.command("XYZZY")?;
+    Ok(expect_code(&reply, "500"))
+}
+
+fn check_noop(addr: &str) -> io::Result<(bool, String)> {
+    let mut client = Client::connect(addr)?;
+    let reply = client.command("NOOP")?;
+    Ok(expect_code(&reply, "250"))
+}
+
+fn check_rset(addr: &str) -> io::Result<(bool, String)> {
+    let mut client = Client::connect_with_helo(addr)?;
+    client.command("MAIL FROM:<sender@example.com>")?;
+    let reply = client.command("RSET")?;
+    Ok(expect_code(&reply, "250"))
+}
+
+fn check_transaction(addr: &str) -> io::Result<(bool, String)> {
+    let mut client = Client::connect_with_helo(addr)?;
+    for (command, expected) in [
+        ("MAIL FROM:<sender@example.com>", "250"),
+        ("RCPT TO:<recipient@example.com>", "250"),
+        ("DATA", "354"),
+        ("Subject: Compliance\r\n\r\nChecklist message.\r\n.", "250"),
+    ] {
+        let reply = client.command(command)?;
+        if !reply.starts_with(expected) {
+            return Ok((false, reply));
+        }
+    }
+    Ok((true, "message accepted".to_string()))
+}
+
+fn check_quit(addr: &str) -> io::Result<(bool, String)> {
+    let mut client = Client::connect(addr)?;
+    let reply = client.command("QUIT")?;
+    Ok(expect_code(&reply, "221"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SmtpServer;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn test_default_server_passes_checklist() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, _rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            SmtpServer::new("test.local")
+                .start_with_listener(listener, tx)
+                .unwrap();
+        });
+
+        let report = run_checklist(&addr);
+        assert_eq!(report.checks.len(), 10);
+        for check in &report.checks {
+            assert!(check.passed, "{check}");
+        }
+        assert!(report.all_passed());
+        assert_eq!(report.failures().count(), 0);
+    }
+
+    #[test]
+    fn test_unreachable_server_fails_checks() {
+        // Bind and drop to get a port with nothing listening
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+
+        let report = run_checklist(&addr);
+        assert!(!report.all_passed());
+        assert!(!report.get("greeting is 220").unwrap().passed);
+    }
+}
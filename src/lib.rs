@@ -43,10 +43,33 @@
 //!
 //! Enabling the `ehlo` feature also allows you to use the `EHLO` command.
 //!
+//! Enabling the `chunking` feature advertises `CHUNKING` in the `EHLO`
+//! response and accepts message content via `BDAT` (RFC 3030).
+//!
+//! Enabling the `binarymime` feature additionally advertises `BINARYMIME` and
+//! accepts `BODY=BINARYMIME` on `MAIL FROM`. Such messages must be sent with
+//! `BDAT`; `DATA` is rejected.
+//!
+//! Enabling the `smtputf8` feature advertises `SMTPUTF8` (RFC 6531) and
+//! accepts the `SMTPUTF8` parameter on `MAIL FROM`, recorded in
+//! [`Email::smtputf8`].
+//!
+//! Enabling the `test-utils` feature provides `TestServer`, which runs a
+//! server on an ephemeral loopback port for client tests (e.g. with `lettre`)
+//! and stops it when dropped.
+//!
+//! Enabling the `tokio` feature provides `AsyncMailbox`, which awaits emails
+//! forwarded to a tokio channel, with timeouts and predicate matching.
+//!
+//! Enabling the `tracing` feature wraps each connection in an `smtp_conn`
+//! span carrying the peer address, logs every command and response, and
+//! records an event for each accepted message.
+//!
 //! ## Notes
 //!
 //! - Only the "minimal implementation" defined in RFC 821 is implemented.
-//! - Runs in-memory only. Email persistence is not supported.
+//! - Emails are kept in memory and not persisted. Only `with_spool_dir`
+//!   writes large message content to temporary files, removed with the email.
 //! - SMTP authentication is not supported.
 //! - SSL/TLS connection is not supported.
 //! - Mail relay is not supported.
@@ -60,15 +83,46 @@
 //! - Command lines: 512 characters max
 //! - Text lines: 1000 characters max
 //! - Recipients: 100 max per message
+//! - Message content: 10 MiB max
+//!
+//! The recipient and content limits are configurable with
+//! `SmtpServer::with_max_recipients` and `SmtpServer::with_max_data_size`;
+//! `SmtpServer::limits` reports the values in effect. Messages spooled to
+//! disk with `SmtpServer::with_spool_dir` are exempt from the content limit;
+//! cap them with `SmtpServer::with_max_spool_size` instead.
 //!
 //! ## Email Handling
 //!
 //! Emails are sent directly to the channel provided when starting the server.
+//! Each email is sent before its final `250` reply, so emails from one
+//! connection always arrive in the order they were sent, even when
+//! connections are served concurrently. Emails from different connections
+//! may interleave.
 //!
 //! Use `recv_timeout()` on the receiver to wait for emails with a timeout.
 //! This avoids the need for `thread::sleep()` and provides deterministic
 //! behavior when testing email functionality.
+//!
+//! Wrapping the receiver in a [`Mailbox`] adds helpers such as
+//! [`Mailbox::drain`], which takes every email received so far.
+//!
+//! ## Compliance Checklist
+//!
+//! [`compliance::run_checklist`] connects to a running server as a client and
+//! reports pass/fail for a set of core RFC 5321 behaviors.
 
+pub mod compliance;
 mod smtp;
 
-pub use smtp::{Email, SmtpError, SmtpLimits, SmtpResponse, SmtpServer, SmtpSession, SmtpState};
+pub use smtp::{
+    BodyType, Command, CommandInterceptor, CommandMatcher, ConnectionId, DataLine, Direction,
+    Email, EmailBuilder, Eviction, GreetingDelayFn, InvalidHostname, Job, LineOverflowPolicy,
+    MailStore, Mailbox, MultiServerHandle, RejectedEmail, ServerMetrics, SmtpError, SmtpLimits,
+    SmtpResponse, SmtpServer, SmtpSession, SmtpState, Spawn, ThreadPerConnection, assert_empty,
+};
+
+#[cfg(feature = "test-utils")]
+pub use smtp::TestServer;
+
+#[cfg(feature = "tokio")]
+pub use smtp::AsyncMailbox;
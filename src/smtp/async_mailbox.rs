@@ -0,0 +1,88 @@
+//! Awaitable mailbox over a tokio channel
+
+use crate::smtp::email::Email;
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Receives emails in async code, like [`Mailbox`](crate::Mailbox) does in
+/// blocking code
+///
+/// The server delivers to a `std` channel, so forward emails to the tokio
+/// channel from a callback. The callback runs on the server's thread, where
+/// blocking on a full channel is fine:
+///
+/// ```rust,no_run
+/// use mogimail::{AsyncMailbox, SmtpServer};
+///
+/// let (tx, rx) = tokio::sync::mpsc::channel(16);
+/// let server = SmtpServer::new("localhost").with_on_email(Box::new(move |email| {
+///     let _ = tx.blocking_send(email.clone());
+/// }));
+/// let mut mailbox = AsyncMailbox::new(rx);
+/// ```
+///
+/// The timed helpers must be awaited on a tokio runtime with the time
+/// driver enabled.
+#[derive(Debug)]
+pub struct AsyncMailbox {
+    /// Receiving end of the email channel
+    receiver: mpsc::Receiver<Email>,
+    /// Emails passed over by `wait_for_matching`, oldest first
+    skipped: VecDeque<Email>,
+}
+
+impl AsyncMailbox {
+    /// Wrap the receiving end of a tokio email channel
+    pub fn new(receiver: mpsc::Receiver<Email>) -> Self {
+        Self {
+            receiver,
+            skipped: VecDeque::new(),
+        }
+    }
+
+    /// Wait for the next email
+    ///
+    /// Returns `None` once the channel is closed and empty.
+    pub async fn recv(&mut self) -> Option<Email> {
+        match self.skipped.pop_front() {
+            Some(email) => Some(email),
+            None => self.receiver.recv().await,
+        }
+    }
+
+    /// Wait up to `timeout` for the next email
+    pub async fn wait_for(&mut self, timeout: Duration) -> Option<Email> {
+        tokio::time::timeout(timeout, self.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Wait up to `timeout` for the next email matching `predicate`
+    ///
+    /// Emails that do not match are kept, in order, for later calls.
+    pub async fn wait_for_matching(
+        &mut self,
+        timeout: Duration,
+        mut predicate: impl FnMut(&Email) -> bool,
+    ) -> Option<Email> {
+        if let Some(index) = self.skipped.iter().position(&mut predicate) {
+            return self.skipped.remove(index);
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let email = tokio::time::timeout_at(deadline, self.receiver.recv())
+                .await
+                .ok()
+                .flatten()?;
+            if predicate(&email) {
+                return Some(email);
+            }
+            self.skipped.push_back(email);
+        }
+    }
+}
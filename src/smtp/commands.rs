@@ -2,18 +2,92 @@
 
 use crate::smtp::error::{SmtpError, SmtpLimits};
 use crate::smtp::response::SmtpResponse;
-use crate::smtp::session::SmtpSession;
+use crate::smtp::session::{BodyType, SmtpSession, SmtpState};
+
+use std::fmt;
+
+/// Maximum number of characters of an unrecognized verb echoed in the reply
+const MAX_ECHOED_VERB_LENGTH: usize = 32;
+
+/// Decides whether a recipient is accepted, returning the reply code and
+/// message to reject it
+pub(crate) type RcptValidator =
+    dyn Fn(&str, &SmtpSession) -> Result<(), (String, String)> + Send + Sync;
 
 /// Handles SMTP commands and returns appropriate responses
-#[derive(Debug)]
 pub struct SmtpCommandHandler<'a> {
     hostname: &'a str,
+    /// Capabilities advertised in EHLO replies, replacing the defaults
+    #[cfg(feature = "ehlo")]
+    capabilities: Option<&'a [String]>,
+    /// Whether HELO and EHLO are accepted without a domain
+    lenient_helo: bool,
+    /// Whether MAIL and RCPT accept an address without angle brackets
+    optional_angle_brackets: bool,
+    /// Text of the NOOP reply, replacing `OK`
+    noop_message: Option<&'a str>,
+    /// Check applied to each recipient that passes basic validation
+    rcpt_validator: Option<&'a RcptValidator>,
+}
+
+impl fmt::Debug for SmtpCommandHandler<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("SmtpCommandHandler");
+        debug.field("hostname", &self.hostname);
+        #[cfg(feature = "ehlo")]
+        debug.field("capabilities", &self.capabilities);
+        debug
+            .field("lenient_helo", &self.lenient_helo)
+            .field("optional_angle_brackets", &self.optional_angle_brackets)
+            .field("noop_message", &self.noop_message)
+            .field("rcpt_validator", &self.rcpt_validator.is_some())
+            .finish()
+    }
 }
 
 impl<'a> SmtpCommandHandler<'a> {
     /// Create a new command handler
     pub fn new(hostname: &'a str) -> Self {
-        Self { hostname }
+        Self {
+            hostname,
+            #[cfg(feature = "ehlo")]
+            capabilities: None,
+            lenient_helo: false,
+            optional_angle_brackets: false,
+            noop_message: None,
+            rcpt_validator: None,
+        }
+    }
+
+    /// Advertise exactly these capabilities in EHLO replies
+    #[cfg(feature = "ehlo")]
+    pub fn with_capabilities(mut self, capabilities: &'a [String]) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// Accept HELO and EHLO without a domain argument
+    pub fn with_lenient_helo(mut self, lenient: bool) -> Self {
+        self.lenient_helo = lenient;
+        self
+    }
+
+    /// Accept MAIL and RCPT addresses that are not enclosed in angle brackets
+    pub fn with_optional_angle_brackets(mut self, optional: bool) -> Self {
+        self.optional_angle_brackets = optional;
+        self
+    }
+
+    /// Reply to NOOP with this text instead of `OK`
+    pub fn with_noop_message(mut self, message: &'a str) -> Self {
+        self.noop_message = Some(message);
+        self
+    }
+
+    /// Accept or reject each recipient with `validator`
+    pub(crate) fn with_rcpt_validator(mut self, validator: &'a RcptValidator) -> Self {
+        self.rcpt_validator = Some(validator);
+        self
     }
 
     /// Process a command line and return a response
@@ -22,47 +96,71 @@ impl<'a> SmtpCommandHandler<'a> {
         command_line: &str,
         session: &mut SmtpSession,
     ) -> Result<SmtpResponse, SmtpError> {
-        // Check command line length
-        if command_line.len() > SmtpLimits::COMMAND_LINE_MAX_LENGTH {
-            return Err(SmtpError::LineTooLong {
-                max: SmtpLimits::COMMAND_LINE_MAX_LENGTH,
-            });
-        }
-
-        let parts: Vec<&str> = command_line.split_whitespace().collect();
-        if parts.is_empty() {
-            return Err(SmtpError::InvalidCommand);
-        }
-
-        let cmd = parts[0].to_uppercase();
+        let options = ParseOptions {
+            lenient_helo: self.lenient_helo,
+            optional_angle_brackets: self.optional_angle_brackets,
+        };
+        let mut warnings = Vec::new();
+        let command = match Command::parse_with(command_line, options, &mut warnings) {
+            Ok(command) => command,
+            Err(e) => {
+                // A command out of sequence is reported as such, even when
+                // its arguments are malformed too
+                let verb = command_line.split_whitespace().next().unwrap_or_default();
+                if let Some(e) = sequence_error(verb, session) {
+                    return Err(e);
+                }
+                // Recipients rejected for their syntax count as attempted too
+                if let Some(path) = rcpt_path(command_line) {
+                    session.reject_recipient(path.to_string());
+                }
+                return Err(e);
+            }
+        };
 
-        match cmd.as_str() {
+        let response = match command {
             #[cfg(feature = "ehlo")]
-            "EHLO" => self.handle_ehlo(parts, session),
-            "HELO" => self.handle_helo(parts, session),
-            "MAIL" => self.handle_mail(parts, session),
-            "RCPT" => self.handle_rcpt(parts, session),
-            "DATA" => self.handle_data(parts, session),
-            "RSET" => self.handle_rset(session),
-            "NOOP" => self.handle_noop(),
-            "QUIT" => self.handle_quit(),
-            _ => Err(SmtpError::InvalidCommand),
+            Command::Ehlo(domain) => self.handle_ehlo(&domain, session),
+            Command::Helo(domain) => self.handle_helo(&domain, session),
+            Command::MailFrom {
+                addr,
+                route,
+                params,
+            } => self.handle_mail(addr, route, &params, session),
+            Command::RcptTo { addr, params } => self.handle_rcpt(addr, &params, session),
+            Command::Data => self.handle_data(session),
+            #[cfg(feature = "chunking")]
+            Command::Bdat { size, last } => self.handle_bdat(size, last, session),
+            Command::Rset => self.handle_rset(session),
+            Command::Noop(_) => self.handle_noop(),
+            Command::Quit => self.handle_quit(),
+            // Known commands this server does not support (RFC 5321 section 4.2.4)
+            Command::Unknown(verb)
+                if matches!(verb.as_str(), "SEND" | "SOML" | "SAML" | "TURN" | "ETRN") =>
+            {
+                Err(SmtpError::CommandNotImplemented(verb))
+            }
+            #[cfg(not(feature = "ehlo"))]
+            Command::Ehlo(_) => Err(SmtpError::UnrecognizedCommand("EHLO".to_string())),
+            Command::Unknown(verb) => Err(SmtpError::UnrecognizedCommand(
+                verb.chars().take(MAX_ECHOED_VERB_LENGTH).collect(),
+            )),
+        }?;
+
+        // Note accepted deviations once the command has taken effect
+        if response.is_success() {
+            session.warnings.append(&mut warnings);
         }
+        Ok(response)
     }
 
     /// Handle HELO command
     fn handle_helo(
         &self,
-        parts: Vec<&str>,
+        domain: &str,
         session: &mut SmtpSession,
     ) -> Result<SmtpResponse, SmtpError> {
-        if parts.len() < 2 {
-            return Err(SmtpError::InvalidSyntax(
-                "HELO requires domain argument".to_string(),
-            ));
-        }
-
-        let client_domain = parts[1].to_string();
+        let client_domain = self.client_domain(domain, session);
         session.set_client_domain(client_domain.clone())?;
 
         Ok(SmtpResponse::helo(self.hostname, &client_domain))
@@ -72,64 +170,59 @@ impl<'a> SmtpCommandHandler<'a> {
     #[cfg(feature = "ehlo")]
     fn handle_ehlo(
         &self,
-        parts: Vec<&str>,
+        domain: &str,
         session: &mut SmtpSession,
     ) -> Result<SmtpResponse, SmtpError> {
-        if parts.len() < 2 {
-            return Err(SmtpError::InvalidSyntax(
-                "EHLO requires domain argument".to_string(),
-            ));
-        }
-
-        let client_domain = parts[1].to_string();
+        let client_domain = self.client_domain(domain, session);
         session.set_client_domain(client_domain.clone())?;
 
-        Ok(SmtpResponse::ehlo(self.hostname, &client_domain))
+        Ok(match self.capabilities {
+            Some(capabilities) => {
+                SmtpResponse::ehlo_with(self.hostname, &client_domain, capabilities)
+            }
+            None => SmtpResponse::ehlo_with(
+                self.hostname,
+                &client_domain,
+                &SmtpResponse::capabilities_with_size(session.max_data_size),
+            ),
+        })
+    }
+
+    /// Get the client domain from the argument of a HELO or EHLO command
+    ///
+    /// A missing domain, only parsed in lenient mode, is replaced by the
+    /// peer address as an address literal, e.g. `[127.0.0.1]`.
+    fn client_domain(&self, domain: &str, session: &SmtpSession) -> String {
+        if !domain.is_empty() {
+            return domain.to_string();
+        }
+        session.peer_addr.map_or_else(
+            || "[unknown]".to_string(),
+            |addr| format!("[{}]", addr.ip()),
+        )
     }
 
     /// Handle MAIL command
     fn handle_mail(
         &self,
-        parts: Vec<&str>,
+        addr: String,
+        route: Option<String>,
+        params: &[String],
         session: &mut SmtpSession,
     ) -> Result<SmtpResponse, SmtpError> {
-        if !session.can_execute_command("MAIL") {
-            return Err(SmtpError::InvalidState(
-                "MAIL command requires HELO first".to_string(),
-            ));
-        }
-
-        if parts.len() < 2 {
-            return Err(SmtpError::InvalidSyntax(
-                "MAIL requires FROM argument".to_string(),
-            ));
-        }
-
-        let from_part = parts[1..].join(" ");
-        if !from_part.to_uppercase().starts_with("FROM:") {
-            return Err(SmtpError::InvalidSyntax(
-                "MAIL command must be 'MAIL FROM:<address>'".to_string(),
-            ));
-        }
-
-        let from_addr = from_part[5..].trim();
-        if !from_addr.starts_with('<') || !from_addr.ends_with('>') {
-            return Err(SmtpError::InvalidSyntax(
-                "FROM address must be enclosed in angle brackets".to_string(),
-            ));
-        }
-
-        let addr = from_addr[1..from_addr.len() - 1].to_string();
-        if addr.is_empty() {
-            return Err(SmtpError::InvalidSyntax(
-                "FROM address cannot be empty".to_string(),
-            ));
+        if let Some(e) = sequence_error("MAIL", session) {
+            return Err(e);
         }
 
         // Validate email address components
         self.validate_email_address(&addr)?;
 
+        let params = self.parse_mail_parameters(params, session)?;
+
         session.set_sender(addr)?;
+        session.body_type = params.body_type;
+        session.smtputf8 = params.smtputf8;
+        session.sender_route = route;
 
         Ok(SmtpResponse::ok())
     }
@@ -137,66 +230,52 @@ impl<'a> SmtpCommandHandler<'a> {
     /// Handle RCPT command
     fn handle_rcpt(
         &self,
-        parts: Vec<&str>,
+        addr: String,
+        params: &[String],
         session: &mut SmtpSession,
     ) -> Result<SmtpResponse, SmtpError> {
-        if !session.can_execute_command("RCPT") {
-            return Err(SmtpError::InvalidState(
-                "RCPT command requires MAIL first".to_string(),
-            ));
+        if let Some(e) = sequence_error("RCPT", session) {
+            return Err(e);
         }
 
-        if parts.len() < 2 {
-            return Err(SmtpError::InvalidSyntax(
-                "RCPT requires TO argument".to_string(),
-            ));
+        if let Some(param) = params.first() {
+            session.reject_recipient(addr);
+            return Err(SmtpError::InvalidSyntax(format!(
+                "Unrecognized RCPT parameter: {param}"
+            )));
         }
 
-        let to_part = parts[1..].join(" ");
-        if !to_part.to_uppercase().starts_with("TO:") {
-            return Err(SmtpError::InvalidSyntax(
-                "RCPT command must be 'RCPT TO:<address>'".to_string(),
-            ));
+        // Validate email address components
+        if let Err(e) = self.validate_email_address(&addr) {
+            session.reject_recipient(addr);
+            return Err(e);
         }
 
-        let to_addr = to_part[3..].trim();
-        if !to_addr.starts_with('<') || !to_addr.ends_with('>') {
-            return Err(SmtpError::InvalidSyntax(
-                "TO address must be enclosed in angle brackets".to_string(),
-            ));
+        // Let the validator decide, given the rest of the transaction
+        if let Some(validator) = self.rcpt_validator
+            && let Err((code, message)) = validator(&addr, session)
+        {
+            session.reject_recipient(addr);
+            return Ok(SmtpResponse::error(&code, &message));
         }
 
-        let addr = to_addr[1..to_addr.len() - 1].to_string();
-        if addr.is_empty() {
-            return Err(SmtpError::InvalidSyntax(
-                "TO address cannot be empty".to_string(),
-            ));
+        if let Err(e) = session.add_recipient(addr.clone()) {
+            session.reject_recipient(addr);
+            return Err(e);
         }
 
-        // Validate email address components
-        self.validate_email_address(&addr)?;
-
-        session.add_recipient(addr)?;
-
         Ok(SmtpResponse::ok())
     }
 
     /// Handle DATA command
-    fn handle_data(
-        &self,
-        parts: Vec<&str>,
-        session: &mut SmtpSession,
-    ) -> Result<SmtpResponse, SmtpError> {
-        if !session.can_execute_command("DATA") {
-            return Err(SmtpError::InvalidState(
-                "DATA command requires RCPT first".to_string(),
-            ));
+    fn handle_data(&self, session: &mut SmtpSession) -> Result<SmtpResponse, SmtpError> {
+        if let Some(e) = sequence_error("DATA", session) {
+            return Err(e);
         }
 
-        if parts.len() > 1 {
-            return Err(SmtpError::InvalidSyntax(
-                "DATA command takes no arguments".to_string(),
-            ));
+        #[cfg(feature = "binarymime")]
+        if session.body_type == BodyType::BinaryMime {
+            return Ok(SmtpResponse::error("503", "BINARYMIME requires BDAT"));
         }
 
         session.start_data_mode()?;
@@ -204,21 +283,49 @@ impl<'a> SmtpCommandHandler<'a> {
         Ok(SmtpResponse::data_start())
     }
 
+    /// Handle BDAT command
+    ///
+    /// Only records the announced chunk; the server reads the payload.
+    #[cfg(feature = "chunking")]
+    fn handle_bdat(
+        &self,
+        size: usize,
+        last: bool,
+        session: &mut SmtpSession,
+    ) -> Result<SmtpResponse, SmtpError> {
+        if let Some(e) = sequence_error("BDAT", session) {
+            return Err(e);
+        }
+
+        session.start_chunk(size, last)?;
+
+        if last {
+            Ok(SmtpResponse::ok())
+        } else {
+            Ok(SmtpResponse::chunk_received(size))
+        }
+    }
+
     /// Handle RSET command
+    ///
+    /// RSET is allowed at any time. Before HELO there is no transaction to
+    /// clear, so it succeeds without changing the session; afterwards it
+    /// clears the transaction but keeps the client domain.
     fn handle_rset(&self, session: &mut SmtpSession) -> Result<SmtpResponse, SmtpError> {
-        if !session.can_execute_command("RSET") {
-            return Err(SmtpError::InvalidState(
-                "RSET command requires HELO first".to_string(),
-            ));
+        if session.state != SmtpState::Initial {
+            session.reset();
         }
-
-        session.reset();
         Ok(SmtpResponse::ok())
     }
 
     /// Handle NOOP command
+    ///
+    /// RFC 5321 permits an argument, which is ignored. Keep this permissive.
     fn handle_noop(&self) -> Result<SmtpResponse, SmtpError> {
-        Ok(SmtpResponse::ok())
+        Ok(match self.noop_message {
+            Some(message) => SmtpResponse::new("250", message),
+            None => SmtpResponse::ok(),
+        })
     }
 
     /// Handle QUIT command
@@ -226,22 +333,87 @@ impl<'a> SmtpCommandHandler<'a> {
         Ok(SmtpResponse::quit())
     }
 
+    /// Parse the ESMTP parameters following the MAIL FROM path
+    fn parse_mail_parameters(
+        &self,
+        params: &[String],
+        session: &SmtpSession,
+    ) -> Result<MailParameters, SmtpError> {
+        let mut parsed = MailParameters::default();
+
+        for param in params {
+            let (key, value) = param.split_once('=').unwrap_or((param.as_str(), ""));
+            match key.to_uppercase().as_str() {
+                "BODY" => {
+                    parsed.body_type = match value.to_uppercase().as_str() {
+                        "7BIT" => BodyType::SevenBit,
+                        "8BITMIME" => BodyType::EightBitMime,
+                        #[cfg(feature = "binarymime")]
+                        "BINARYMIME" => BodyType::BinaryMime,
+                        #[cfg(not(feature = "binarymime"))]
+                        "BINARYMIME" => {
+                            return Err(SmtpError::InvalidSyntax(
+                                "BODY=BINARYMIME requires CHUNKING".to_string(),
+                            ));
+                        }
+                        _ => {
+                            return Err(SmtpError::InvalidSyntax(format!(
+                                "Unsupported BODY value: {value}"
+                            )));
+                        }
+                    };
+                }
+                "SIZE" => {
+                    // Declared message size (RFC 1870); zero is allowed
+                    let size: usize = value.parse().map_err(|_| {
+                        SmtpError::InvalidSyntax(format!("Invalid SIZE value: {value}"))
+                    })?;
+                    // Spooled messages have their own limit, if any
+                    let max = match session.spool_dir {
+                        Some(_) => session.max_spool_size,
+                        None => Some(session.max_data_size),
+                    };
+                    if let Some(max) = max
+                        && size > max
+                    {
+                        return Err(SmtpError::TooMuchData { max });
+                    }
+                }
+                #[cfg(feature = "smtputf8")]
+                "SMTPUTF8" if value.is_empty() => parsed.smtputf8 = true,
+                _ => {
+                    return Err(SmtpError::InvalidSyntax(format!(
+                        "Unrecognized MAIL parameter: {param}"
+                    )));
+                }
+            }
+        }
+
+        Ok(parsed)
+    }
+
     /// Validate email address format and size limits
     fn validate_email_address(&self, addr: &str) -> Result<(), SmtpError> {
-        // Check for @ symbol
-        if let Some(at_pos) = addr.find('@') {
+        // Check for @ symbol, which may also appear in a quoted local part
+        if let Some(at_pos) = addr.rfind('@') {
             let user_part = &addr[..at_pos];
             let domain_part = &addr[at_pos + 1..];
 
-            // Check user part length
-            if user_part.len() > SmtpLimits::USER_MAX_LENGTH {
+            let quoted =
+                user_part.len() >= 2 && user_part.starts_with('"') && user_part.ends_with('"');
+            if user_part.contains('@') && !quoted {
+                return Err(SmtpError::MultipleAtSigns);
+            }
+
+            // Check user part length, counting characters for UTF-8 addresses
+            if user_part.chars().count() > SmtpLimits::USER_MAX_LENGTH {
                 return Err(SmtpError::UserTooLong {
                     max: SmtpLimits::USER_MAX_LENGTH,
                 });
             }
 
             // Check domain part length
-            if domain_part.len() > SmtpLimits::DOMAIN_MAX_LENGTH {
+            if domain_part.chars().count() > SmtpLimits::DOMAIN_MAX_LENGTH {
                 return Err(SmtpError::DomainTooLong {
                     max: SmtpLimits::DOMAIN_MAX_LENGTH,
                 });
@@ -254,15 +426,373 @@ impl<'a> SmtpCommandHandler<'a> {
                 ));
             }
         } else {
-            return Err(SmtpError::InvalidSyntax(
-                "Email address must contain @ symbol".to_string(),
-            ));
+            return Err(SmtpError::NoAtSign);
         }
 
         Ok(())
     }
 }
 
+/// A parsed SMTP command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `HELO <domain>`; the domain is empty only when parsed leniently
+    Helo(String),
+    /// `EHLO <domain>`; the domain is empty only when parsed leniently
+    Ehlo(String),
+    /// `MAIL FROM:<addr> [params]`
+    MailFrom {
+        /// The sender mailbox, without brackets, comments or source route
+        addr: String,
+        /// The deprecated source route, such as `@relay1,@relay2`, if any
+        route: Option<String>,
+        /// ESMTP parameters such as `SIZE=1000`, as given
+        params: Vec<String>,
+    },
+    /// `RCPT TO:<addr> [params]`
+    RcptTo {
+        /// The recipient mailbox, without brackets, comments or source route
+        addr: String,
+        /// ESMTP parameters, as given
+        params: Vec<String>,
+    },
+    /// `DATA`
+    Data,
+    /// `BDAT <size> [LAST]`
+    #[cfg(feature = "chunking")]
+    Bdat {
+        /// Size of the chunk in octets
+        size: usize,
+        /// Whether this is the last chunk
+        last: bool,
+    },
+    /// `RSET`
+    Rset,
+    /// `NOOP [string]`
+    Noop(Option<String>),
+    /// `QUIT`
+    Quit,
+    /// Any other verb, in upper case
+    Unknown(String),
+}
+
+/// Syntax relaxations applied when parsing commands
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ParseOptions {
+    /// Whether HELO and EHLO may omit the domain
+    pub(crate) lenient_helo: bool,
+    /// Whether MAIL and RCPT addresses may omit the angle brackets
+    pub(crate) optional_angle_brackets: bool,
+}
+
+impl Command {
+    /// Parse a command line, without its line terminator
+    ///
+    /// Only the syntax is checked; whether the command is acceptable in the
+    /// current state of a session is up to the command handler.
+    pub fn parse(line: &str) -> Result<Self, SmtpError> {
+        Self::parse_with(line, ParseOptions::default(), &mut Vec::new())
+    }
+
+    /// Parse a command line with relaxed syntax
+    ///
+    /// Deviations accepted because of `options` are described in `warnings`.
+    pub(crate) fn parse_with(
+        line: &str,
+        options: ParseOptions,
+        warnings: &mut Vec<String>,
+    ) -> Result<Self, SmtpError> {
+        // Check command line length
+        if line.len() > SmtpLimits::COMMAND_LINE_MAX_LENGTH {
+            return Err(SmtpError::CommandLineTooLong {
+                max: SmtpLimits::COMMAND_LINE_MAX_LENGTH,
+            });
+        }
+
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() {
+            return Err(SmtpError::EmptyCommand);
+        }
+
+        let verb = parts[0].to_uppercase();
+
+        match verb.as_str() {
+            "HELO" | "EHLO" => {
+                let domain = match parts.get(1) {
+                    Some(domain) => domain.to_string(),
+                    None if options.lenient_helo => String::new(),
+                    None => {
+                        return Err(SmtpError::InvalidSyntax(format!(
+                            "{verb} requires domain argument"
+                        )));
+                    }
+                };
+                Ok(if verb == "HELO" {
+                    Command::Helo(domain)
+                } else {
+                    Command::Ehlo(domain)
+                })
+            }
+            "MAIL" => {
+                if parts.len() < 2 {
+                    return Err(SmtpError::InvalidSyntax(
+                        "MAIL requires FROM argument".to_string(),
+                    ));
+                }
+
+                let from_part = parts[1..].join(" ");
+                if !from_part.to_uppercase().starts_with("FROM:") {
+                    return Err(SmtpError::InvalidSyntax(
+                        "MAIL command must be 'MAIL FROM:<address>'".to_string(),
+                    ));
+                }
+
+                let (path, params) = split_parameters(&from_part[5..]);
+                let path = parse_path(path, "FROM", options, warnings)?;
+                let (route, addr) = split_source_route(&path)?;
+                Ok(Command::MailFrom {
+                    addr: addr.to_string(),
+                    route: route.map(str::to_string),
+                    params: params.into_iter().map(str::to_string).collect(),
+                })
+            }
+            "RCPT" => {
+                if parts.len() < 2 {
+                    return Err(SmtpError::InvalidSyntax(
+                        "RCPT requires TO argument".to_string(),
+                    ));
+                }
+
+                let to_part = parts[1..].join(" ");
+                if !to_part.to_uppercase().starts_with("TO:") {
+                    return Err(SmtpError::InvalidSyntax(
+                        "RCPT command must be 'RCPT TO:<address>'".to_string(),
+                    ));
+                }
+
+                // Forward-path source routes are ignored (RFC 5321 section 3.3)
+                let (path, params) = split_parameters(&to_part[3..]);
+                let path = parse_path(path, "TO", options, warnings)?;
+                let (_, addr) = split_source_route(&path)?;
+                Ok(Command::RcptTo {
+                    addr: addr.to_string(),
+                    params: params.into_iter().map(str::to_string).collect(),
+                })
+            }
+            "DATA" => {
+                if parts.len() > 1 {
+                    return Err(SmtpError::InvalidSyntax(
+                        "DATA command takes no arguments".to_string(),
+                    ));
+                }
+                Ok(Command::Data)
+            }
+            #[cfg(feature = "chunking")]
+            "BDAT" => {
+                if parts.len() < 2 || parts.len() > 3 {
+                    return Err(SmtpError::InvalidSyntax(
+                        "BDAT command must be 'BDAT <size> [LAST]'".to_string(),
+                    ));
+                }
+
+                let size = parts[1].parse::<usize>().map_err(|_| {
+                    SmtpError::InvalidSyntax("BDAT chunk size must be a number".to_string())
+                })?;
+
+                let last = match parts.get(2) {
+                    Some(arg) if arg.eq_ignore_ascii_case("LAST") => true,
+                    Some(_) => {
+                        return Err(SmtpError::InvalidSyntax(
+                            "BDAT command must be 'BDAT <size> [LAST]'".to_string(),
+                        ));
+                    }
+                    None => false,
+                };
+
+                Ok(Command::Bdat { size, last })
+            }
+            "RSET" => Ok(Command::Rset),
+            // RFC 5321 permits an argument, which is ignored
+            "NOOP" => Ok(Command::Noop(
+                line.trim()
+                    .split_once(char::is_whitespace)
+                    .map(|(_, arg)| arg.trim().to_string()),
+            )),
+            "QUIT" => Ok(Command::Quit),
+            _ => Ok(Command::Unknown(verb)),
+        }
+    }
+}
+
+/// ESMTP parameters accepted on MAIL FROM
+#[derive(Debug, Default)]
+struct MailParameters {
+    body_type: BodyType,
+    smtputf8: bool,
+}
+
+/// Get the error for a transaction command sent out of sequence, if any
+fn sequence_error(verb: &str, session: &SmtpSession) -> Option<SmtpError> {
+    let verb = verb.to_ascii_uppercase();
+    let requirement = match verb.as_str() {
+        "MAIL" => "HELO",
+        "RCPT" => "MAIL",
+        "DATA" => "RCPT",
+        #[cfg(feature = "chunking")]
+        "BDAT" => "RCPT",
+        _ => return None,
+    };
+    if session.can_execute_command(&verb) {
+        return None;
+    }
+    // A transaction is already open once a sender has been given
+    if verb == "MAIL" && session.from.is_some() {
+        return Some(SmtpError::SenderAlreadySpecified);
+    }
+    Some(SmtpError::InvalidState(format!(
+        "{verb} command requires {requirement} first"
+    )))
+}
+
+/// Get the path of a RCPT command as sent, without its angle brackets
+///
+/// Used for commands that failed to parse, so the path is not validated.
+fn rcpt_path(line: &str) -> Option<&str> {
+    let (verb, rest) = line.trim().split_once(char::is_whitespace)?;
+    let rest = rest.trim_start();
+    if !verb.eq_ignore_ascii_case("RCPT") || !rest.get(..3)?.eq_ignore_ascii_case("TO:") {
+        return None;
+    }
+    let (path, _) = split_parameters(&rest[3..]);
+    Some(
+        path.trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .trim(),
+    )
+}
+
+/// Split a MAIL/RCPT argument into the bracketed path and its parameters
+///
+/// A path without brackets ends at the first whitespace.
+fn split_parameters(arg: &str) -> (&str, Vec<&str>) {
+    if let Some(end) = arg.find('>') {
+        return (&arg[..=end], arg[end + 1..].split_whitespace().collect());
+    }
+    let arg = arg.trim_start();
+    match arg.split_once(char::is_whitespace) {
+        Some((path, params)) if !path.starts_with('<') => {
+            (path, params.split_whitespace().collect())
+        }
+        _ => (arg, Vec::new()),
+    }
+}
+
+/// Extract the address from a path argument such as ` <a@b>`
+///
+/// Whitespace around the brackets and around the address inside them is
+/// ignored, as are comments. `label` names the argument in error messages.
+fn parse_path(
+    arg: &str,
+    label: &'static str,
+    options: ParseOptions,
+    warnings: &mut Vec<String>,
+) -> Result<String, SmtpError> {
+    let path = arg.trim();
+    let addr = if path.starts_with('<') && path.ends_with('>') {
+        path[1..path.len() - 1].trim()
+    } else if options.optional_angle_brackets && !path.contains(['<', '>']) {
+        warnings.push(format!("{label} address not enclosed in angle brackets"));
+        path
+    } else {
+        return Err(SmtpError::MissingAngleBrackets { field: label });
+    };
+
+    if addr.is_empty() {
+        return Err(SmtpError::EmptyAddress { field: label });
+    }
+
+    strip_comments(addr)
+}
+
+/// Remove RFC 5322 comments such as `(note)` from a path
+///
+/// Comments may nest, and whitespace around them is removed with them.
+/// Parentheses within a quoted string or escaped with `\` are kept.
+fn strip_comments(path: &str) -> Result<String, SmtpError> {
+    let mut stripped = String::with_capacity(path.len());
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut after_comment = false;
+
+    for c in path.chars() {
+        if escaped {
+            escaped = false;
+            if depth == 0 {
+                stripped.push(c);
+            }
+            continue;
+        }
+        match c {
+            '\\' => {
+                escaped = true;
+                if depth == 0 {
+                    stripped.push(c);
+                }
+            }
+            '(' if !quoted => {
+                if depth == 0 {
+                    stripped.truncate(stripped.trim_end().len());
+                }
+                depth += 1;
+            }
+            ')' if !quoted && depth > 0 => {
+                depth -= 1;
+                after_comment = depth == 0;
+            }
+            _ if depth > 0 => {}
+            _ if c.is_whitespace() && after_comment => {}
+            _ => {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                after_comment = false;
+                stripped.push(c);
+            }
+        }
+    }
+
+    if depth > 0 {
+        return Err(SmtpError::InvalidSyntax(
+            "Unterminated comment in address".to_string(),
+        ));
+    }
+    Ok(stripped)
+}
+
+/// Split a deprecated source route such as `@relay1,@relay2:` off a path
+///
+/// Returns the route (without the trailing colon), if any, and the mailbox.
+pub(crate) fn split_source_route(path: &str) -> Result<(Option<&str>, &str), SmtpError> {
+    if !path.starts_with('@') {
+        return Ok((None, path));
+    }
+
+    let malformed = || SmtpError::InvalidSyntax("Malformed source route".to_string());
+    let (route, mailbox) = path.split_once(':').ok_or_else(malformed)?;
+    let valid = route.split(',').all(|hop| {
+        hop.strip_prefix('@').is_some_and(|domain| {
+            !domain.is_empty() && !domain.contains(|c: char| c == '@' || c.is_whitespace())
+        })
+    });
+    if !valid || mailbox.is_empty() {
+        return Err(malformed());
+    }
+
+    Ok((Some(route), mailbox))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -271,6 +801,117 @@ mod tests {
         SmtpCommandHandler::new("test.local")
     }
 
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(
+            Command::parse("helo client.local").unwrap(),
+            Command::Helo("client.local".to_string())
+        );
+        assert_eq!(
+            Command::parse("EHLO client.local").unwrap(),
+            Command::Ehlo("client.local".to_string())
+        );
+        assert_eq!(
+            Command::parse("MAIL FROM:<@relay:user@example.com> SIZE=100 BODY=8BITMIME").unwrap(),
+            Command::MailFrom {
+                addr: "user@example.com".to_string(),
+                route: Some("@relay".to_string()),
+                params: vec!["SIZE=100".to_string(), "BODY=8BITMIME".to_string()],
+            }
+        );
+        assert_eq!(
+            Command::parse("RCPT TO: <rcpt(note)@example.com> NOTIFY=NEVER").unwrap(),
+            Command::RcptTo {
+                addr: "rcpt@example.com".to_string(),
+                params: vec!["NOTIFY=NEVER".to_string()],
+            }
+        );
+        assert_eq!(Command::parse("DATA").unwrap(), Command::Data);
+        assert_eq!(Command::parse("RSET").unwrap(), Command::Rset);
+        assert_eq!(Command::parse("NOOP").unwrap(), Command::Noop(None));
+        assert_eq!(
+            Command::parse("NOOP  keep alive ").unwrap(),
+            Command::Noop(Some("keep alive".to_string()))
+        );
+        assert_eq!(Command::parse("quit").unwrap(), Command::Quit);
+        assert_eq!(
+            Command::parse("VRFY user").unwrap(),
+            Command::Unknown("VRFY".to_string())
+        );
+    }
+
+    #[cfg(feature = "chunking")]
+    #[test]
+    fn test_parse_bdat() {
+        assert_eq!(
+            Command::parse("BDAT 42 LAST").unwrap(),
+            Command::Bdat {
+                size: 42,
+                last: true
+            }
+        );
+        assert!(Command::parse("BDAT many").is_err());
+    }
+
+    #[test]
+    fn test_address_errors() {
+        assert!(matches!(
+            Command::parse("MAIL FROM:user@example.com"),
+            Err(SmtpError::MissingAngleBrackets { field: "FROM" })
+        ));
+        assert!(matches!(
+            Command::parse("RCPT TO:< >"),
+            Err(SmtpError::EmptyAddress { field: "TO" })
+        ));
+        assert_eq!(
+            Command::parse("MAIL FROM:user@example.com")
+                .unwrap_err()
+                .to_response_message(),
+            "Syntax error: FROM address must be enclosed in angle brackets"
+        );
+        assert_eq!(
+            Command::parse("RCPT TO:<>")
+                .unwrap_err()
+                .to_response_message(),
+            "Syntax error: TO address cannot be empty"
+        );
+
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+        let err = handler
+            .process_command("MAIL FROM:<user>", &mut session)
+            .unwrap_err();
+        assert!(matches!(err, SmtpError::NoAtSign));
+        assert_eq!(err.to_response_code(), "501");
+        assert_eq!(
+            err.to_response_message(),
+            "Syntax error: Email address must contain @ symbol"
+        );
+        let err = handler
+            .process_command("MAIL FROM:<a@b@example.com>", &mut session)
+            .unwrap_err();
+        assert!(matches!(err, SmtpError::MultipleAtSigns));
+        assert_eq!(err.to_response_code(), "501");
+    }
+
+    #[test]
+    fn test_parse_syntax_errors() {
+        for line in [
+            "",
+            "HELO",
+            "MAIL",
+            "MAIL TO:<user@example.com>",
+            "MAIL FROM:user@example.com",
+            "RCPT TO:<>",
+            "DATA now",
+        ] {
+            assert!(Command::parse(line).is_err(), "{line:?}");
+        }
+    }
+
     #[test]
     fn test_helo_command() {
         let handler = create_handler();
@@ -320,6 +961,18 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_helo_missing_domain_lenient() {
+        let handler = create_handler().with_lenient_helo(true);
+        let mut session = SmtpSession::new();
+        session.peer_addr = Some("192.0.2.1:2525".parse().unwrap());
+
+        let response = handler.process_command("HELO", &mut session).unwrap();
+        assert_eq!(response.code, "250");
+        assert_eq!(response.message, "test.local Hello [192.0.2.1]");
+        assert_eq!(session.client_domain, Some("[192.0.2.1]".to_string()));
+    }
+
     #[test]
     fn test_mail_command() {
         let handler = create_handler();
@@ -435,6 +1088,245 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_source_routed_paths() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+        handler
+            .process_command("MAIL FROM:<@relay1,@relay2:user@final.com>", &mut session)
+            .unwrap();
+        assert_eq!(session.from, Some("user@final.com".to_string()));
+        assert_eq!(session.sender_route, Some("@relay1,@relay2".to_string()));
+
+        handler
+            .process_command("RCPT TO:<@relay.example:rcpt@example.com>", &mut session)
+            .unwrap();
+        assert_eq!(session.to, ["rcpt@example.com"]);
+    }
+
+    #[test]
+    fn test_comments_in_paths() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+        handler
+            .process_command("MAIL FROM:<user(note)@example.com>", &mut session)
+            .unwrap();
+        assert_eq!(session.from, Some("user@example.com".to_string()));
+
+        handler
+            .process_command("RCPT TO:<rcpt@(nested (note))example.com>", &mut session)
+            .unwrap();
+        handler
+            .process_command("RCPT TO:<other@example.com (trailing note)>", &mut session)
+            .unwrap();
+        assert_eq!(session.to, ["rcpt@example.com", "other@example.com"]);
+
+        let err = handler
+            .process_command("RCPT TO:<third@example.com (unterminated>", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_code(), "501");
+    }
+
+    #[test]
+    fn test_strip_comments_keeps_quoted_parentheses() {
+        assert_eq!(
+            strip_comments("\"a (b)\"(c)@example.com").unwrap(),
+            "\"a (b)\"@example.com"
+        );
+        assert_eq!(
+            strip_comments("a\\(b@example.com").unwrap(),
+            "a\\(b@example.com"
+        );
+    }
+
+    #[test]
+    fn test_paths_without_angle_brackets() {
+        let strict = create_handler();
+        let mut session = SmtpSession::new();
+        strict
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+        let err = strict
+            .process_command("MAIL FROM:user@example.com", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_code(), "501");
+        assert!(session.from.is_none());
+
+        let lenient = create_handler().with_optional_angle_brackets(true);
+        lenient
+            .process_command("MAIL FROM:user@example.com SIZE=100", &mut session)
+            .unwrap();
+        assert_eq!(session.from, Some("user@example.com".to_string()));
+
+        let err = strict
+            .process_command("RCPT TO:rcpt@example.com", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_code(), "501");
+        lenient
+            .process_command("RCPT TO: rcpt@example.com", &mut session)
+            .unwrap();
+        assert_eq!(session.to, ["rcpt@example.com"]);
+        assert_eq!(
+            session.warnings,
+            [
+                "FROM address not enclosed in angle brackets",
+                "TO address not enclosed in angle brackets"
+            ]
+        );
+
+        // The address is still validated
+        let err = lenient
+            .process_command("RCPT TO:not-an-address", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_code(), "501");
+    }
+
+    #[test]
+    fn test_malformed_source_routes() {
+        let handler = create_handler();
+
+        for path in [
+            "<@relay1,relay2:user@final.com>",
+            "<@relay1@relay2:user@final.com>",
+            "<@:user@final.com>",
+            "<@relay1,@relay2>",
+            "<@relay1:>",
+        ] {
+            let mut session = SmtpSession::new();
+            handler
+                .process_command("HELO client.local", &mut session)
+                .unwrap();
+            let err = handler
+                .process_command(&format!("MAIL FROM:{path}"), &mut session)
+                .unwrap_err();
+            assert_eq!(err.to_response_code(), "501", "{path}");
+            assert!(session.from.is_none());
+        }
+    }
+
+    #[test]
+    fn test_data_after_rset_without_rcpt() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        for command in [
+            "HELO client.local",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<recipient@example.com>",
+            "RSET",
+            "MAIL FROM:<sender@example.com>",
+        ] {
+            handler.process_command(command, &mut session).unwrap();
+        }
+        assert_eq!(session.state, SmtpState::MailReceived);
+
+        let err = handler.process_command("DATA", &mut session).unwrap_err();
+        assert_eq!(err.to_response_code(), "503");
+        assert!(!session.in_data_mode);
+        assert!(session.to.is_empty());
+    }
+
+    #[test]
+    fn test_unrecognized_command_echoes_verb() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        let err = handler
+            .process_command("foobar baz", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_code(), "500");
+        assert_eq!(err.enhanced_code(), Some("5.5.1"));
+        assert_eq!(
+            err.to_response_message(),
+            "5.5.1 Command \"FOOBAR\" not recognized"
+        );
+
+        let long_verb = "X".repeat(100);
+        let err = handler
+            .process_command(&long_verb, &mut session)
+            .unwrap_err();
+        assert!(
+            err.to_response_message()
+                .contains(&"X".repeat(MAX_ECHOED_VERB_LENGTH))
+        );
+        assert!(
+            !err.to_response_message()
+                .contains(&"X".repeat(MAX_ECHOED_VERB_LENGTH + 1))
+        );
+    }
+
+    #[test]
+    fn test_mail_twice_without_rset() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        let err = handler
+            .process_command("MAIL FROM:<sender@example.com>", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_code(), "503");
+        assert_eq!(
+            err.to_response_message(),
+            "Bad sequence of commands: MAIL command requires HELO first"
+        );
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+        handler
+            .process_command("MAIL FROM:<first@example.com>", &mut session)
+            .unwrap();
+
+        let err = handler
+            .process_command("MAIL FROM:<second@example.com>", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_code(), "503");
+        assert_eq!(err.to_response_message(), "Sender already specified");
+        assert_eq!(session.from, Some("first@example.com".to_string()));
+
+        handler
+            .process_command("RCPT TO:<recipient@example.com>", &mut session)
+            .unwrap();
+        let err = handler
+            .process_command("MAIL FROM:<second@example.com>", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_message(), "Sender already specified");
+    }
+
+    #[test]
+    fn test_rset_before_helo() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        let response = handler.process_command("RSET", &mut session).unwrap();
+        assert_eq!(response.code, "250");
+        assert_eq!(session.state, SmtpState::Initial);
+
+        // RSET does not stand in for HELO
+        assert!(
+            handler
+                .process_command("MAIL FROM:<sender@example.com>", &mut session)
+                .is_err()
+        );
+
+        for command in [
+            "HELO client.local",
+            "MAIL FROM:<sender@example.com>",
+            "RCPT TO:<recipient@example.com>",
+        ] {
+            handler.process_command(command, &mut session).unwrap();
+        }
+        let response = handler.process_command("DATA", &mut session).unwrap();
+        assert_eq!(response.code, "354");
+    }
+
     #[test]
     fn test_rset_command() {
         let handler = create_handler();
@@ -468,6 +1360,69 @@ mod tests {
         assert_eq!(response.code, "250");
     }
 
+    #[test]
+    fn test_obsolete_commands_not_implemented() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        for command in [
+            "SEND FROM:<a@b>",
+            "SOML",
+            "SAML",
+            "TURN",
+            "ETRN example.com",
+        ] {
+            let err = handler.process_command(command, &mut session).unwrap_err();
+            assert_eq!(err.to_response_code(), "502", "{command}");
+        }
+
+        let err = handler
+            .process_command("etrn example.com", &mut session)
+            .unwrap_err();
+        assert_eq!(
+            err.to_response_message(),
+            "5.5.1 Command \"ETRN\" not implemented"
+        );
+
+        let err = handler.process_command("XYZZY", &mut session).unwrap_err();
+        assert_eq!(err.to_response_code(), "500");
+    }
+
+    #[test]
+    fn test_noop_custom_message() {
+        let handler = create_handler().with_noop_message("Ready");
+        let mut session = SmtpSession::new();
+
+        let response = handler.process_command("NOOP", &mut session).unwrap();
+        assert_eq!(response.code, "250");
+        assert_eq!(response.message, "Ready");
+    }
+
+    #[test]
+    fn test_noop_with_argument() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        let response = handler
+            .process_command("NOOP some argument", &mut session)
+            .unwrap();
+        assert_eq!(response.code, "250");
+        assert_eq!(session.state, SmtpState::Initial);
+
+        // The argument must not affect an ongoing transaction
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+        handler
+            .process_command("MAIL FROM:<sender@example.com>", &mut session)
+            .unwrap();
+        let response = handler
+            .process_command("NOOP keepalive", &mut session)
+            .unwrap();
+        assert_eq!(response.code, "250");
+        assert_eq!(session.from, Some("sender@example.com".to_string()));
+    }
+
     #[test]
     fn test_quit_command() {
         let handler = create_handler();
@@ -493,7 +1448,7 @@ mod tests {
 
         let long_command = "HELO ".to_string() + &"a".repeat(SmtpLimits::COMMAND_LINE_MAX_LENGTH);
         let result = handler.process_command(&long_command, &mut session);
-        assert!(matches!(result, Err(SmtpError::LineTooLong { .. })));
+        assert!(matches!(result, Err(SmtpError::CommandLineTooLong { .. })));
     }
 
     #[test]
@@ -502,10 +1457,22 @@ mod tests {
 
         // Valid addresses
         assert!(handler.validate_email_address("user@example.com").is_ok());
+        assert!(
+            handler
+                .validate_email_address("\"user@home\"@example.com")
+                .is_ok()
+        );
         assert!(handler.validate_email_address("test@test.local").is_ok());
 
         // Invalid addresses
-        assert!(handler.validate_email_address("invalid").is_err());
+        assert!(matches!(
+            handler.validate_email_address("invalid"),
+            Err(SmtpError::NoAtSign)
+        ));
+        assert!(matches!(
+            handler.validate_email_address("user@relay@example.com"),
+            Err(SmtpError::MultipleAtSigns)
+        ));
         assert!(handler.validate_email_address("@example.com").is_err());
         assert!(handler.validate_email_address("user@").is_err());
 
@@ -535,13 +1502,334 @@ mod tests {
 
         // Empty FROM address
         let result = handler.process_command("MAIL FROM:<>", &mut session);
-        assert!(result.is_err());
+        assert!(matches!(result, Err(SmtpError::EmptyAddress { .. })));
 
         // Empty TO address
         session
             .set_sender("sender@example.com".to_string())
             .unwrap();
         let result = handler.process_command("RCPT TO:<>", &mut session);
-        assert!(result.is_err());
+        assert!(matches!(result, Err(SmtpError::EmptyAddress { .. })));
+    }
+
+    #[test]
+    fn test_mail_with_space_after_colon() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+
+        let response = handler
+            .process_command("MAIL FROM: <a@b>", &mut session)
+            .unwrap();
+        assert_eq!(response.code, "250");
+        assert_eq!(session.from, Some("a@b".to_string()));
+    }
+
+    #[test]
+    fn test_rcpt_with_spaces_inside_brackets() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+        handler
+            .process_command("MAIL FROM:<sender@example.com>", &mut session)
+            .unwrap();
+
+        let response = handler
+            .process_command("RCPT TO:<  a@b  >", &mut session)
+            .unwrap();
+        assert_eq!(response.code, "250");
+        assert_eq!(session.to, vec!["a@b".to_string()]);
+    }
+
+    #[test]
+    fn test_malformed_command_out_of_sequence() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        // The sequence error wins over the syntax error
+        let err = handler
+            .process_command("MAIL FROM:bad", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_code(), "503");
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+        let err = handler
+            .process_command("RCPT TO:bad", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_code(), "503");
+        assert!(session.recipient_status.is_empty());
+
+        // Once in sequence, the syntax error is reported
+        let err = handler
+            .process_command("MAIL FROM:bad", &mut session)
+            .unwrap_err();
+        assert_eq!(err.to_response_code(), "501");
+    }
+
+    #[test]
+    fn test_unbracketed_address_rejected() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+
+        let result = handler.process_command("MAIL FROM: a@b", &mut session);
+        assert!(matches!(
+            result,
+            Err(SmtpError::MissingAngleBrackets { .. })
+        ));
+
+        let result = handler.process_command("MAIL FROM:<", &mut session);
+        assert!(matches!(
+            result,
+            Err(SmtpError::MissingAngleBrackets { .. })
+        ));
+    }
+
+    #[cfg(feature = "chunking")]
+    #[test]
+    fn test_bdat_command() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+        handler
+            .process_command("MAIL FROM:<sender@example.com>", &mut session)
+            .unwrap();
+
+        // BDAT requires recipients
+        let result = handler.process_command("BDAT 10", &mut session);
+        assert!(matches!(result, Err(SmtpError::InvalidState(_))));
+
+        handler
+            .process_command("RCPT TO:<recipient@example.com>", &mut session)
+            .unwrap();
+
+        let result = handler.process_command("BDAT ten", &mut session);
+        assert!(matches!(result, Err(SmtpError::InvalidSyntax(_))));
+
+        let response = handler.process_command("BDAT 10", &mut session).unwrap();
+        assert_eq!(response.code, "250");
+        assert_eq!(
+            session.pending_chunk,
+            Some(crate::smtp::session::PendingChunk {
+                size: 10,
+                last: false
+            })
+        );
+
+        // DATA cannot be mixed with BDAT
+        let result = handler.process_command("DATA", &mut session);
+        assert!(matches!(result, Err(SmtpError::InvalidState(_))));
+    }
+
+    #[test]
+    fn test_unrecognized_mail_parameter() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+
+        let result = handler.process_command("MAIL FROM:<a@b> FOO=BAR", &mut session);
+        assert!(matches!(result, Err(SmtpError::InvalidSyntax(_))));
+        assert!(session.from.is_none());
+    }
+
+    #[cfg(feature = "binarymime")]
+    #[test]
+    fn test_binarymime_requires_bdat() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("EHLO client.local", &mut session)
+            .unwrap();
+        handler
+            .process_command("MAIL FROM:<a@b> BODY=BINARYMIME", &mut session)
+            .unwrap();
+        assert_eq!(session.body_type, BodyType::BinaryMime);
+
+        handler
+            .process_command("RCPT TO:<recipient@example.com>", &mut session)
+            .unwrap();
+
+        let response = handler.process_command("DATA", &mut session).unwrap();
+        assert_eq!(response.format(), "503 BINARYMIME requires BDAT\r\n");
+        assert_eq!(session.state, SmtpState::RecipientsReceived);
+
+        let response = handler
+            .process_command("BDAT 10 LAST", &mut session)
+            .unwrap();
+        assert_eq!(response.code, "250");
+    }
+
+    #[test]
+    fn test_mail_body_parameter() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+
+        handler
+            .process_command("MAIL FROM:<a@b> BODY=8BITMIME", &mut session)
+            .unwrap();
+        assert_eq!(session.body_type, BodyType::EightBitMime);
+
+        handler.process_command("RSET", &mut session).unwrap();
+        assert_eq!(session.body_type, BodyType::SevenBit);
+
+        handler
+            .process_command("MAIL FROM:<a@b> body=7bit", &mut session)
+            .unwrap();
+        assert_eq!(session.body_type, BodyType::SevenBit);
+
+        handler.process_command("RSET", &mut session).unwrap();
+        let result = handler.process_command("MAIL FROM:<a@b> BODY=9BIT", &mut session);
+        assert!(matches!(result, Err(SmtpError::InvalidSyntax(_))));
+    }
+
+    #[test]
+    fn test_mail_size_parameter() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+
+        for command in ["MAIL FROM:<a@b> SIZE=0", "MAIL FROM:<a@b> size=1024"] {
+            handler.process_command(command, &mut session).unwrap();
+            handler.process_command("RSET", &mut session).unwrap();
+        }
+
+        let too_big = format!("MAIL FROM:<a@b> SIZE={}", SmtpLimits::MAX_DATA_SIZE + 1);
+        let err = handler.process_command(&too_big, &mut session).unwrap_err();
+        assert_eq!(err.to_response_code(), "552");
+
+        let result = handler.process_command("MAIL FROM:<a@b> SIZE=-1", &mut session);
+        assert!(matches!(result, Err(SmtpError::InvalidSyntax(_))));
+        let result = handler.process_command("MAIL FROM:<a@b> SIZE", &mut session);
+        assert!(matches!(result, Err(SmtpError::InvalidSyntax(_))));
+    }
+
+    #[cfg(not(feature = "binarymime"))]
+    #[test]
+    fn test_binarymime_rejected_without_chunking() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+
+        let result = handler.process_command("MAIL FROM:<a@b> BODY=BINARYMIME", &mut session);
+        assert!(matches!(result, Err(SmtpError::InvalidSyntax(_))));
+    }
+
+    #[test]
+    fn test_utf8_address_length_counts_characters() {
+        let handler = create_handler();
+
+        // 64 two-byte characters fit within the 64 character limit
+        let user = "é".repeat(SmtpLimits::USER_MAX_LENGTH);
+        assert!(user.len() > SmtpLimits::USER_MAX_LENGTH);
+        assert!(
+            handler
+                .validate_email_address(&format!("{user}@example.com"))
+                .is_ok()
+        );
+
+        let user = "é".repeat(SmtpLimits::USER_MAX_LENGTH + 1);
+        assert!(matches!(
+            handler.validate_email_address(&format!("{user}@example.com")),
+            Err(SmtpError::UserTooLong { .. })
+        ));
+    }
+
+    #[cfg(feature = "smtputf8")]
+    #[test]
+    fn test_smtputf8_parameter() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("EHLO client.local", &mut session)
+            .unwrap();
+        let response = handler
+            .process_command("MAIL FROM:<tëst@exämple.com> SMTPUTF8", &mut session)
+            .unwrap();
+
+        assert_eq!(response.code, "250");
+        assert_eq!(session.from, Some("tëst@exämple.com".to_string()));
+        assert!(session.smtputf8);
+    }
+
+    #[test]
+    fn test_recipient_status() {
+        let handler = create_handler();
+        let mut session = SmtpSession::new();
+
+        handler
+            .process_command("HELO client.local", &mut session)
+            .unwrap();
+        handler
+            .process_command("MAIL FROM:<sender@example.com>", &mut session)
+            .unwrap();
+        handler
+            .process_command("RCPT TO:<first@example.com>", &mut session)
+            .unwrap();
+        assert!(
+            handler
+                .process_command("RCPT TO:<no-domain>", &mut session)
+                .is_err()
+        );
+        // Syntax errors are recorded as well
+        assert!(
+            handler
+                .process_command("RCPT TO:unbracketed@example.com", &mut session)
+                .is_err()
+        );
+        assert!(
+            handler
+                .process_command("RCPT TO:<param@example.com> NOTIFY=NEVER", &mut session)
+                .is_err()
+        );
+        handler
+            .process_command("RCPT TO:<second@example.com>", &mut session)
+            .unwrap();
+        handler.process_command("DATA", &mut session).unwrap();
+
+        let email = session.finish_data_collection().unwrap();
+        assert_eq!(email.to, vec!["first@example.com", "second@example.com"]);
+        assert_eq!(
+            email.recipient_status,
+            vec![
+                ("first@example.com".to_string(), true),
+                ("no-domain".to_string(), false),
+                ("unbracketed@example.com".to_string(), false),
+                ("param@example.com".to_string(), false),
+                ("second@example.com".to_string(), true),
+            ]
+        );
+        assert_eq!(
+            email.rejected_recipients().collect::<Vec<_>>(),
+            ["no-domain", "unbracketed@example.com", "param@example.com"]
+        );
     }
 }
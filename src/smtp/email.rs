@@ -1,6 +1,36 @@
 //! Email data structures and functionality
 
-use std::time::SystemTime;
+use crate::smtp::commands::split_source_route;
+use crate::smtp::error::SmtpError;
+use crate::smtp::session::ConnectionId;
+use crate::smtp::spool::SpoolFile;
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A single line of DATA content as it arrived on the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataLine {
+    /// The line content, without its terminator or stuffed leading dot
+    pub content: String,
+    /// Whether the line ended with CRLF rather than a bare LF
+    pub had_crlf: bool,
+    /// Whether a leading dot was removed (RFC 5321 section 4.5.2)
+    pub was_dot_stuffed: bool,
+}
+
+/// Which side of the conversation sent a transcript line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A line sent by the client
+    Client,
+    /// A reply sent by the server
+    Server,
+}
 
 /// Represents an email message received by the SMTP server
 #[derive(Debug, Clone)]
@@ -11,11 +41,66 @@ pub struct Email {
     /// List of recipient email addresses
     pub to: Vec<String>,
 
+    /// Every recipient attempted in the transaction, in order, with whether
+    /// it was accepted
+    pub recipient_status: Vec<(String, bool)>,
+
     /// The email content including headers and body
     pub data: String,
 
+    /// Per-line details of content received with DATA
+    ///
+    /// Empty for messages sent with BDAT or built with [`Email::new`], and
+    /// limited to the header section for spooled messages.
+    pub data_lines: Vec<DataLine>,
+
+    /// The email content as raw bytes, before lossy UTF-8 decoding
+    ///
+    /// This preserves 8-bit content sent with `BODY=8BITMIME` or
+    /// `BODY=BINARYMIME` that is not valid UTF-8. For received emails every
+    /// line, the last included, keeps its original CRLF or bare LF ending,
+    /// so the length matches the content sent on the wire. For an email
+    /// built with [`Email::new`] this is the given data.
+    pub raw: Vec<u8>,
+
+    /// Whether `raw` holds the content exactly as received
+    ///
+    /// Set for emails received by a server built with
+    /// `with_verbatim_data(true)`, and for content sent with BDAT. Otherwise
+    /// bare CRs within lines have been removed.
+    pub verbatim: bool,
+
+    /// Whether the client requested internationalized addressing (`SMTPUTF8`)
+    pub smtputf8: bool,
+
+    /// ID of the connection the email was received on, if any
+    pub connection_id: Option<ConnectionId>,
+
+    /// Label of the listener the email was received on
+    ///
+    /// Set for servers started with [`SmtpServer::start_multi_labeled`];
+    /// `None` otherwise.
+    ///
+    /// [`SmtpServer::start_multi_labeled`]: crate::SmtpServer::start_multi_labeled
+    pub listener_label: Option<String>,
+
+    /// Anomalies noticed while receiving the email that did not reject it
+    pub warnings: Vec<String>,
+
+    /// The conversation that produced the email, in wire order
+    ///
+    /// Only recorded when the server was built with
+    /// `with_capture_transcript(true)`; empty otherwise.
+    pub transcript: Vec<(Direction, String)>,
+
     /// When the email was received by the server
     pub timestamp: SystemTime,
+
+    /// File holding the complete content of a spooled message
+    pub(crate) spool: Option<Arc<SpoolFile>>,
+
+    /// Headers added to a spooled message after it was written to disk
+    pub(crate) spool_prefix: Vec<u8>,
 }
 
 impl Email {
@@ -23,74 +108,904 @@ impl Email {
     pub fn new(from: String, to: Vec<String>, data: String) -> Self {
         Self {
             from,
+            recipient_status: to.iter().map(|addr| (addr.clone(), true)).collect(),
             to,
+            raw: data.as_bytes().to_vec(),
             data,
+            data_lines: Vec::new(),
+            verbatim: false,
+            smtputf8: false,
+            connection_id: None,
+            listener_label: None,
+            warnings: Vec::new(),
+            transcript: Vec::new(),
+            spool: None,
+            spool_prefix: Vec::new(),
             timestamp: SystemTime::now(),
         }
     }
 
+    /// Start building an email, e.g. as a fixture in tests
+    ///
+    /// ```
+    /// use mogimail::Email;
+    ///
+    /// let email = Email::builder()
+    ///     .from("sender@example.com")
+    ///     .to("rcpt@example.com")
+    ///     .header("Subject", "Hello")
+    ///     .body("Hi there")
+    ///     .build();
+    /// assert_eq!(email.get_subject(), Some("Hello"));
+    /// assert_eq!(email.get_body(), Some("Hi there"));
+    /// ```
+    pub fn builder() -> EmailBuilder {
+        EmailBuilder::default()
+    }
+
     /// Check if this email was sent to a specific recipient
+    ///
+    /// The comparison is case-insensitive over the whole address. Domains are
+    /// case-insensitive, but local parts are technically case-sensitive per
+    /// RFC 5321; use [`Email::has_recipient_exact`] for byte-exact matching.
     pub fn has_recipient(&self, recipient: &str) -> bool {
+        self.to
+            .iter()
+            .any(|addr| addr.eq_ignore_ascii_case(recipient))
+    }
+
+    /// Check if this email was sent to a specific recipient, byte-for-byte
+    pub fn has_recipient_exact(&self, recipient: &str) -> bool {
         self.to.iter().any(|addr| addr == recipient)
     }
 
+    /// Get the recipients that were rejected during the transaction
+    pub fn rejected_recipients(&self) -> impl Iterator<Item = &str> {
+        self.recipient_status
+            .iter()
+            .filter(|(_, accepted)| !accepted)
+            .map(|(addr, _)| addr.as_str())
+    }
+
     /// Check if this email was sent from a specific sender
+    ///
+    /// The comparison is case-insensitive, like [`Email::has_recipient`].
     pub fn is_from_sender(&self, sender: &str) -> bool {
-        self.from == sender
+        self.from.eq_ignore_ascii_case(sender)
+    }
+
+    /// Get the domain of the sender address
+    ///
+    /// Returns `None` for the null reverse-path. IP literals such as
+    /// `[192.0.2.1]` are returned including the brackets.
+    pub fn sender_domain(&self) -> Option<&str> {
+        address_domain(&self.from)
+    }
+
+    /// Get the domain of each recipient address
+    pub fn recipient_domains(&self) -> Vec<&str> {
+        self.to
+            .iter()
+            .filter_map(|addr| address_domain(addr))
+            .collect()
+    }
+
+    /// Check if any recipient is at the given domain (case-insensitive)
+    ///
+    /// IP literals match on their text, including the brackets.
+    pub fn has_recipient_domain(&self, domain: &str) -> bool {
+        !self.recipients_for_domain(domain).is_empty()
+    }
+
+    /// Get the recipients at the given domain (case-insensitive)
+    pub fn recipients_for_domain(&self, domain: &str) -> Vec<&str> {
+        self.to
+            .iter()
+            .filter(|addr| address_domain(addr).is_some_and(|d| d.eq_ignore_ascii_case(domain)))
+            .map(String::as_str)
+            .collect()
     }
 
     /// Get the size of the email data in bytes
+    ///
+    /// This is the length of the UTF-8 `data` string, in which invalid
+    /// sequences have been replaced. Use [`Email::raw_size`] for the number of
+    /// bytes actually received.
     pub fn data_size(&self) -> usize {
         self.data.len()
     }
 
+    /// Get the size of the raw email content in bytes
+    ///
+    /// For spooled messages this is the size of the complete content,
+    /// most of which is on disk.
+    pub fn raw_size(&self) -> usize {
+        match &self.spool {
+            Some(file) => self.spool_prefix.len() + file.size(),
+            None => self.raw.len(),
+        }
+    }
+
+    /// Check if the content was written to the spool directory
+    ///
+    /// A spooled email only holds its header section in `data` and `raw`;
+    /// read the body with [`Email::body_reader`] or the complete content with
+    /// [`Email::content_reader`].
+    pub fn is_spooled(&self) -> bool {
+        self.spool.is_some()
+    }
+
+    /// Read the complete raw message, headers included
+    ///
+    /// Lines end as in [`Email::raw`]. For spooled messages
+    /// the content is read from disk, after any header added by the server
+    /// once it was spooled, otherwise from memory. The spool file is removed
+    /// once the email and all its clones are dropped.
+    pub fn content_reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        Ok(match &self.spool {
+            Some(file) => {
+                let content = BufReader::new(File::open(file.path())?);
+                Box::new(self.spool_prefix.as_slice().chain(content))
+            }
+            None => Box::new(self.raw.as_slice()),
+        })
+    }
+
+    /// Read the message body, the raw content after the first empty line
+    ///
+    /// This is the streaming counterpart of [`Email::get_body`], and works
+    /// the same for spooled and in-memory messages. A message without an
+    /// empty line has an empty body. Use [`Email::content_reader`] to read the
+    /// headers too.
+    pub fn body_reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        let mut content = BufReader::new(self.content_reader()?);
+        let mut line = Vec::new();
+        while content.read_until(b'\n', &mut line)? > 0 && line != b"\n" && line != b"\r\n" {
+            line.clear();
+        }
+        Ok(Box::new(content))
+    }
+
+    /// Get the receive time in milliseconds since the Unix epoch
+    pub fn timestamp_unix_ms(&self) -> u128 {
+        self.timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+    }
+
+    /// Format the receive time as an RFC 2822 date in UTC
+    ///
+    /// For example `Tue, 01 Jul 2025 12:34:56 +0000`.
+    pub fn timestamp_rfc2822(&self) -> String {
+        const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+
+        let secs = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let days = secs / 86400;
+        let (year, month, day) = civil_from_days(days);
+        let time = secs % 86400;
+
+        format!(
+            "{}, {day:02} {} {year} {:02}:{:02}:{:02} +0000",
+            WEEKDAYS[(days % 7) as usize],
+            MONTHS[month as usize - 1],
+            time / 3600,
+            time % 3600 / 60,
+            time % 60,
+        )
+    }
+
+    /// Get the value of the first header with the given name (case-insensitive)
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Count the headers with the given name (case-insensitive)
+    pub fn header_count(&self, name: &str) -> usize {
+        self.headers()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .count()
+    }
+
+    /// Get the names of headers that appear more than once
+    ///
+    /// Names are compared case-insensitively and returned as first spelled,
+    /// in order of first appearance. Some headers, such as `Received`, are
+    /// expected to repeat; a repeated `From` or `Date` can indicate header
+    /// injection.
+    pub fn has_duplicate_headers(&self) -> Vec<String> {
+        let mut names: Vec<(&str, usize)> = Vec::new();
+        for (key, _) in self.headers() {
+            match names
+                .iter_mut()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            {
+                Some((_, count)) => *count += 1,
+                None => names.push((key, 1)),
+            }
+        }
+        names
+            .into_iter()
+            .filter(|&(_, count)| count > 1)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    /// Insert a header field at the top of the message
+    pub(crate) fn prepend_header(&mut self, name: &str, value: &str) {
+        self.data.insert_str(0, &format!("{name}: {value}\n"));
+        let raw_field = format!("{name}: {value}\r\n").into_bytes();
+        if self.spool.is_some() {
+            self.spool_prefix.splice(0..0, raw_field.iter().copied());
+        }
+        self.raw.splice(0..0, raw_field);
+    }
+
+    /// Iterate over `(name, value)` pairs in the header section
+    ///
+    /// Continuation lines of folded headers are skipped, even if they
+    /// contain a colon.
+    fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.data
+            .lines()
+            .take_while(|line| !line.is_empty()) // End of headers
+            .filter(|line| !line.starts_with([' ', '\t']))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim(), value.trim()))
+    }
+
     /// Get the subject line from the email headers (if present)
     pub fn get_subject(&self) -> Option<&str> {
-        for line in self.data.lines() {
-            if line.is_empty() {
-                // End of headers
-                break;
-            }
-            if let Some(subject) = line.strip_prefix("Subject: ") {
-                return Some(subject);
+        self.get_header("Subject")
+    }
+
+    /// Get the value of the `MIME-Version` header (if present)
+    pub fn mime_version(&self) -> Option<&str> {
+        self.get_header("MIME-Version")
+    }
+
+    /// Check if this email declares itself a MIME message
+    pub fn is_mime(&self) -> bool {
+        self.mime_version().is_some()
+    }
+
+    /// Get the raw value of the `Content-Type` header (if present)
+    ///
+    /// Only the first line of a folded header is returned.
+    pub fn content_type(&self) -> Option<&str> {
+        self.get_header("Content-Type")
+    }
+
+    /// Check if the content type is `multipart/*`
+    pub fn is_multipart(&self) -> bool {
+        self.content_type().is_some_and(|value| {
+            value
+                .get(..10)
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case("multipart/"))
+        })
+    }
+
+    /// Get the `boundary` parameter of the `Content-Type` header
+    ///
+    /// Both quoted and unquoted values are supported, including parameters
+    /// on continuation lines of a folded header.
+    pub fn mime_boundary(&self) -> Option<String> {
+        let content_type = self.unfolded_header("Content-Type")?;
+        content_type.split(';').skip(1).find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            if !key.trim().eq_ignore_ascii_case("boundary") {
+                return None;
             }
-            if let Some(subject) = line.strip_prefix("subject: ") {
-                return Some(subject);
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            Some(value.to_string())
+        })
+    }
+
+    /// Get the value of the first header with the given name, joining any
+    /// continuation lines
+    fn unfolded_header(&self, name: &str) -> Option<String> {
+        let mut lines = self.data.lines().take_while(|line| !line.is_empty());
+        let mut value = lines.find_map(|line| {
+            if line.starts_with([' ', '\t']) {
+                return None;
             }
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim().to_string())
+        })?;
+        for line in lines.take_while(|line| line.starts_with([' ', '\t'])) {
+            value.push(' ');
+            value.push_str(line.trim());
         }
-        None
+        Some(value)
+    }
+
+    /// Get the addresses listed in the first header with the given name,
+    /// including those on continuation lines
+    fn header_addresses(&self, name: &str) -> Vec<String> {
+        self.unfolded_header(name)
+            .map(|list| {
+                extract_address_list(&list)
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Get the address in the `Sender` header (if present)
+    ///
+    /// This identifies the actual submitter when it differs from the author.
+    pub fn sender(&self) -> Option<String> {
+        self.unfolded_header("Sender")
+            .map(|mailbox| extract_address(&mailbox).to_string())
+    }
+
+    /// Get the address in the `Resent-From` header (if present)
+    pub fn resent_from(&self) -> Option<String> {
+        self.unfolded_header("Resent-From")
+            .map(|mailbox| extract_address(&mailbox).to_string())
+    }
+
+    /// Get the addresses in the `Resent-To` header
+    pub fn resent_to(&self) -> Vec<String> {
+        self.header_addresses("Resent-To")
+    }
+
+    /// Get the addresses in the `To` header
+    ///
+    /// These can differ from the envelope recipients in [`Email::to`].
+    pub fn header_to(&self) -> Vec<String> {
+        self.header_addresses("To")
+    }
+
+    /// Get the addresses in the `Cc` header
+    pub fn header_cc(&self) -> Vec<String> {
+        self.header_addresses("Cc")
+    }
+
+    /// Get the addresses in the `Bcc` header
+    ///
+    /// Usually empty, since clients strip `Bcc` before sending.
+    pub fn header_bcc(&self) -> Vec<String> {
+        self.header_addresses("Bcc")
     }
 
     /// Get the message body (content after the first empty line)
     pub fn get_body(&self) -> Option<&str> {
-        let mut in_body = false;
         let mut body_start = 0;
+        for line in self.data.split_inclusive('\n') {
+            body_start += line.len();
+            if line == "\n" || line == "\r\n" {
+                return (body_start < self.data.len()).then(|| &self.data[body_start..]);
+            }
+        }
+        None
+    }
 
-        for (i, line) in self.data.lines().enumerate() {
-            if !in_body && line.is_empty() {
-                in_body = true;
-                // Calculate byte offset for the body start
-                body_start = self.data.lines().take(i + 1).map(|l| l.len() + 1).sum();
-                break;
+    /// Render the message in canonical RFC 5322 form
+    ///
+    /// Unlike [`Email::raw`], the result is normalized: folded headers are
+    /// joined onto one line, whitespace around each header name and value
+    /// is trimmed, and every line ends with CRLF. Header order is kept. For
+    /// spooled emails only the headers are in memory, so the body is empty.
+    pub fn to_rfc5322(&self) -> String {
+        let mut message = String::new();
+        let mut lines = self.data.lines();
+
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                break; // End of headers
             }
+            if line.starts_with([' ', '\t']) && !message.is_empty() {
+                // Unfold onto the previous header line
+                message.truncate(message.len() - 2);
+                message.push(' ');
+                message.push_str(line.trim());
+            } else if let Some((name, value)) = line.split_once(':') {
+                message.push_str(&format!("{}: {}", name.trim(), value.trim()));
+            } else {
+                message.push_str(line);
+            }
+            message.push_str("\r\n");
         }
 
-        if in_body && body_start < self.data.len() {
-            Some(&self.data[body_start..])
-        } else {
-            None
+        message.push_str("\r\n");
+        for line in lines {
+            message.push_str(line);
+            message.push_str("\r\n");
         }
+        message
     }
 
     /// Check if the email contains a specific text in headers or body
     pub fn contains_text(&self, text: &str) -> bool {
         self.data.contains(text)
     }
+
+    /// Compute the CRC-32 (IEEE) of the content as sent on the wire
+    ///
+    /// Each line is terminated with CRLF and leading dots are unstuffed, so a
+    /// client can compute the same value over the DATA it sent, excluding
+    /// the final `.` line.
+    pub fn content_crc32(&self) -> io::Result<u32> {
+        let mut crc = !0u32;
+        self.for_each_line(|line| {
+            crc = crc32_update(crc32_update(crc, line), b"\r\n");
+            Ok(())
+        })?;
+        Ok(!crc)
+    }
+
+    /// Call `f` with each line of the raw content, without its line ending
+    fn for_each_line(&self, mut f: impl FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+        let mut content = BufReader::new(self.content_reader()?);
+        let mut line = Vec::new();
+        while content.read_until(b'\n', &mut line)? > 0 {
+            let text = line.strip_suffix(b"\n").unwrap_or(&line);
+            let text = text.strip_suffix(b"\r").unwrap_or(text);
+            f(text)?;
+            line.clear();
+        }
+        Ok(())
+    }
+
+    /// Send this email to the SMTP server at `addr`
+    ///
+    /// Replays the stored envelope and raw content with HELO, MAIL, RCPT and
+    /// DATA, dot-stuffing lines as needed, then QUITs. Any reply other than
+    /// the expected one aborts with [`SmtpError::UnexpectedReply`].
+    ///
+    /// Connecting, reading and writing each time out after
+    /// [`SEND_TIMEOUT`](Self::SEND_TIMEOUT); see
+    /// [`send_via_with_timeout`](Self::send_via_with_timeout).
+    pub fn send_via(&self, addr: &str) -> Result<(), SmtpError> {
+        self.send_via_with_timeout(addr, Self::SEND_TIMEOUT)
+    }
+
+    /// Default timeout of [`send_via`](Self::send_via)
+    pub const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Send this email to the SMTP server at `addr`, giving up on an
+    /// unresponsive server
+    ///
+    /// The same as [`send_via`](Self::send_via), except that connecting and
+    /// each read and write time out after `timeout`. A timeout is returned as
+    /// [`SmtpError::Io`].
+    pub fn send_via_with_timeout(&self, addr: &str, timeout: Duration) -> Result<(), SmtpError> {
+        let mut stream = connect_timeout(addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        read_reply(&mut reader, "220")?;
+        command(&mut stream, &mut reader, "HELO localhost", "250")?;
+        command(
+            &mut stream,
+            &mut reader,
+            &format!("MAIL FROM:<{}>", self.from),
+            "250",
+        )?;
+        for recipient in &self.to {
+            command(
+                &mut stream,
+                &mut reader,
+                &format!("RCPT TO:<{recipient}>"),
+                "250",
+            )?;
+        }
+        command(&mut stream, &mut reader, "DATA", "354")?;
+
+        let mut writer = io::BufWriter::new(&mut stream);
+        self.for_each_line(|line| {
+            // Stuff a leading dot (RFC 5321 section 4.5.2)
+            if line.starts_with(b".") {
+                writer.write_all(b".")?;
+            }
+            writer.write_all(line)?;
+            writer.write_all(b"\r\n")
+        })?;
+        writer.write_all(b".\r\n")?;
+        writer.flush()?;
+        drop(writer);
+        read_reply(&mut reader, "250")?;
+
+        command(&mut stream, &mut reader, "QUIT", "221")
+    }
+}
+
+/// Feed `bytes` into a running CRC-32 (IEEE, reflected)
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    crc
+}
+
+/// Connect to the first address `addr` resolves to that accepts within
+/// `timeout`
+fn connect_timeout(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    }))
+}
+
+/// Send a command and check the code of its reply
+fn command(
+    stream: &mut TcpStream,
+    reader: &mut impl BufRead,
+    line: &str,
+    expected: &str,
+) -> Result<(), SmtpError> {
+    stream.write_all(format!("{line}\r\n").as_bytes())?;
+    read_reply(reader, expected)
+}
+
+/// Read a possibly multiline reply and check its code
+fn read_reply(reader: &mut impl BufRead, expected: &str) -> Result<(), SmtpError> {
+    loop {
+        let mut reply = String::new();
+        if reader.read_line(&mut reply)? == 0 {
+            return Err(SmtpError::ConnectionClosed);
+        }
+        let reply = reply.trim_end();
+        if !reply.starts_with(expected) {
+            return Err(SmtpError::UnexpectedReply(reply.to_string()));
+        }
+        // A hyphen after the code marks a continuation line
+        if reply.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// A message the server rejected, with what the client had sent of it
+///
+/// Sent to the channel given to
+/// [`SmtpServer::with_rejection_sender`](crate::SmtpServer::with_rejection_sender).
+#[derive(Debug, Clone)]
+pub struct RejectedEmail {
+    /// The sender address, if `MAIL FROM` was accepted
+    pub from: Option<String>,
+    /// The recipients accepted before the rejection
+    pub to: Vec<String>,
+    /// Reply code sent to the client
+    pub code: String,
+    /// Reply text sent to the client
+    pub message: String,
+    /// The message content received before the rejection, if any
+    ///
+    /// Lines end as in [`Email::raw`].
+    pub raw: Option<Vec<u8>>,
+    /// ID of the connection the message was sent on, if any
+    pub connection_id: Option<ConnectionId>,
+}
+
+/// Builder for an [`Email`], created with [`Email::builder`]
+///
+/// The content is assembled as an RFC 5322 message: each header field on
+/// its own CRLF-terminated line, then an empty line and the body.
+#[derive(Debug, Clone, Default)]
+pub struct EmailBuilder {
+    from: String,
+    to: Vec<String>,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl EmailBuilder {
+    /// Set the envelope sender
+    pub fn from(mut self, from: impl Into<String>) -> Self {
+        self.from = from.into();
+        self
+    }
+
+    /// Add an envelope recipient
+    pub fn to(mut self, to: impl Into<String>) -> Self {
+        self.to.push(to.into());
+        self
+    }
+
+    /// Add a header field, after any added before
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set the message body
+    pub fn body(mut self, body: impl Into<String>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Assemble the email
+    pub fn build(self) -> Email {
+        let mut data = String::new();
+        for (name, value) in &self.headers {
+            data.push_str(&format!("{name}: {value}\r\n"));
+        }
+        data.push_str("\r\n");
+        data.push_str(&self.body);
+        Email::new(self.from, self.to, data)
+    }
+}
+
+/// Renders a concise summary of the envelope, subject, size and timestamp
+///
+/// RFC 2047 encoded-words in the subject are decoded.
+impl fmt::Display for Email {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "From: {}", self.from)?;
+        writeln!(f, "To: {}", self.to.join(", "))?;
+        if let Some(subject) = self.get_subject() {
+            writeln!(f, "Subject: {}", decode_encoded_words(subject))?;
+        }
+        writeln!(f, "Size: {} bytes", self.data_size())?;
+        write!(f, "Received: {}", self.timestamp_rfc2822())
+    }
+}
+
+/// Decode the RFC 2047 encoded-words in a header value
+///
+/// Words in an unsupported charset or encoding are left as they are.
+/// Whitespace between two adjacent encoded-words is dropped.
+fn decode_encoded_words(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    let mut after_word = false;
+
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match decode_encoded_word(candidate) {
+            Some((word, len)) => {
+                if !(after_word && before.chars().all(char::is_whitespace)) {
+                    decoded.push_str(before);
+                }
+                decoded.push_str(&word);
+                rest = &candidate[len..];
+                after_word = true;
+            }
+            None => {
+                decoded.push_str(before);
+                decoded.push_str("=?");
+                rest = &candidate[2..];
+                after_word = false;
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    decoded
+}
+
+/// Decode the encoded-word `=?charset?encoding?text?=` at the start of `text`
+///
+/// Returns the decoded text and the length of the encoded-word.
+fn decode_encoded_word(text: &str) -> Option<(String, usize)> {
+    let inner = text.strip_prefix("=?")?;
+    let (charset, rest) = inner.split_once('?')?;
+    let (encoding, rest) = rest.split_once('?')?;
+    let end = rest.find("?=")?;
+    let encoded = &rest[..end];
+    let len = text.len() - rest[end + 2..].len();
+    if charset.contains(char::is_whitespace) || encoded.contains(char::is_whitespace) {
+        return None;
+    }
+
+    let bytes = match encoding {
+        "B" | "b" => decode_base64(encoded)?,
+        "Q" | "q" => decode_q(encoded)?,
+        _ => return None,
+    };
+    // RFC 2231 allows a language suffix such as `UTF-8*en`
+    let charset = charset.split('*').next().unwrap_or_default();
+    let word = if charset.eq_ignore_ascii_case("UTF-8") || charset.eq_ignore_ascii_case("US-ASCII")
+    {
+        String::from_utf8_lossy(&bytes).into_owned()
+    } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+        bytes.iter().map(|&b| char::from(b)).collect()
+    } else {
+        return None;
+    };
+
+    Some((word, len))
+}
+
+/// Decode the `B` encoding of RFC 2047, which is standard base64
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in text.bytes().take_while(|&c| c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Decode the `Q` encoding of RFC 2047: `_` is a space and `=XX` a hex byte
+fn decode_q(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut i = 0;
+
+    while let Some(&c) = text.as_bytes().get(i) {
+        match c {
+            b'_' => bytes.push(b' '),
+            b'=' => {
+                let hex = text.get(i + 1..i + 3)?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 2;
+            }
+            _ => bytes.push(c),
+        }
+        i += 1;
+    }
+
+    Some(bytes)
+}
+
+/// Convert days since the Unix epoch to a `(year, month, day)` UTC date
+///
+/// Uses Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Get the substring after the last `@` of an address
+fn address_domain(addr: &str) -> Option<&str> {
+    addr.rsplit_once('@')
+        .map(|(_, domain)| domain)
+        .filter(|domain| !domain.is_empty())
+}
+
+/// Extract the bare address from a mailbox such as `Name <user@example.com>`
+///
+/// An obsolete route such as `<@relay1,@relay2:user@example.com>` (RFC 5322
+/// obs-route) is dropped.
+fn extract_address(mailbox: &str) -> &str {
+    let address = match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => mailbox[start + 1..end].trim(),
+        _ => mailbox.trim(),
+    };
+    split_source_route(address).map_or(address, |(_, address)| address)
+}
+
+/// Split a comma-separated mailbox list and extract each address
+///
+/// Commas inside quoted display names or angle brackets do not separate
+/// entries.
+fn extract_address_list(list: &str) -> Vec<&str> {
+    let mut addresses = Vec::new();
+    let mut in_quotes = false;
+    let mut in_brackets = false;
+    let mut start = 0;
+
+    for (i, c) in list.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_brackets = true,
+            '>' if !in_quotes => in_brackets = false,
+            ',' if !in_quotes && !in_brackets => {
+                addresses.push(&list[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    addresses.push(&list[start..]);
+
+    addresses
+        .into_iter()
+        .map(extract_address)
+        .filter(|addr| !addr.is_empty())
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
+
+    /// Parse an RFC 2822 date produced by `timestamp_rfc2822` back to Unix seconds
+    fn parse_rfc2822(date: &str) -> u64 {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+
+        let parts: Vec<&str> = date.split_whitespace().collect();
+        assert_eq!(parts.len(), 6, "{date}");
+        assert_eq!(parts[5], "+0000");
+
+        let day: i64 = parts[1].parse().unwrap();
+        let month = MONTHS.iter().position(|m| *m == parts[2]).unwrap() as i64 + 1;
+        let year: i64 = parts[3].parse().unwrap();
+        let hms: Vec<u64> = parts[4].split(':').map(|n| n.parse().unwrap()).collect();
+
+        // Howard Hinnant's days_from_civil
+        let y = if month <= 2 { year - 1 } else { year };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let mp = (month + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = (era * 146_097 + doe - 719_468) as u64;
+
+        days * 86400 + hms[0] * 3600 + hms[1] * 60 + hms[2]
+    }
+
+    #[test]
+    fn test_timestamp_unix_ms() {
+        let email = Email::new("a@example.com".to_string(), vec![], String::new());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        assert!(now.abs_diff(email.timestamp_unix_ms()) < 1000);
+    }
+
+    #[test]
+    fn test_timestamp_rfc2822() {
+        let mut email = Email::new("a@example.com".to_string(), vec![], String::new());
+
+        email.timestamp = UNIX_EPOCH + Duration::from_secs(1_751_373_296);
+        assert_eq!(email.timestamp_rfc2822(), "Tue, 01 Jul 2025 12:34:56 +0000");
+
+        email.timestamp = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(email.timestamp_rfc2822(), "Tue, 29 Feb 2000 00:00:00 +0000");
+
+        email.timestamp = SystemTime::now();
+        let secs = email.timestamp_unix_ms() / 1000;
+        assert_eq!(u128::from(parse_rfc2822(&email.timestamp_rfc2822())), secs);
+    }
 
     #[test]
     fn test_email_creation() {
@@ -103,6 +1018,7 @@ mod tests {
         assert_eq!(email.from, "sender@example.com");
         assert_eq!(email.to, vec!["recipient@example.com"]);
         assert_eq!(email.data, "Subject: Test\n\nHello World");
+        assert_eq!(email.raw, b"Subject: Test\n\nHello World");
         assert!(email.timestamp <= SystemTime::now());
     }
 
@@ -122,6 +1038,24 @@ mod tests {
         assert!(!email.has_recipient("user3@example.com"));
     }
 
+    #[test]
+    fn test_has_recipient_case_insensitive() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["user@example.com".to_string()],
+            "Test email".to_string(),
+        );
+
+        // Mixed-case domain
+        assert!(email.has_recipient("user@Example.COM"));
+        // Differing local-part casing
+        assert!(email.has_recipient("User@Example.com"));
+
+        assert!(email.has_recipient_exact("user@example.com"));
+        assert!(!email.has_recipient_exact("User@Example.com"));
+        assert!(!email.has_recipient_exact("user@Example.com"));
+    }
+
     #[test]
     fn test_is_from_sender() {
         let email = Email::new(
@@ -131,6 +1065,7 @@ mod tests {
         );
 
         assert!(email.is_from_sender("sender@example.com"));
+        assert!(email.is_from_sender("Sender@EXAMPLE.com"));
         assert!(!email.is_from_sender("other@example.com"));
     }
 
@@ -153,6 +1088,126 @@ mod tests {
         assert_eq!(email_no_subject.get_subject(), None);
     }
 
+    #[test]
+    fn test_get_header() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "subject: lower\nX-Custom:  value \n\nX-Body: not a header".to_string(),
+        );
+
+        assert_eq!(email.get_header("Subject"), Some("lower"));
+        assert_eq!(email.get_header("x-custom"), Some("value"));
+        assert_eq!(email.get_header("X-Body"), None);
+    }
+
+    #[test]
+    fn test_sender_and_resent_headers() {
+        let email = Email::new(
+            "list@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            concat!(
+                "From: Author <author@example.com>\n",
+                "Sender: List Bot <list@example.com>\n",
+                "Resent-From: resender@example.com\n",
+                "Resent-To: \"Doe, Jane\" <jane@example.com>, bob@example.com\n",
+                "Subject: Resent\n",
+                "\n",
+                "Body"
+            )
+            .to_string(),
+        );
+
+        assert_eq!(email.sender().as_deref(), Some("list@example.com"));
+        assert_eq!(email.resent_from().as_deref(), Some("resender@example.com"));
+        assert_eq!(
+            email.resent_to(),
+            vec!["jane@example.com", "bob@example.com"]
+        );
+
+        let plain = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Plain\n\nBody".to_string(),
+        );
+
+        assert_eq!(plain.sender(), None);
+        assert_eq!(plain.resent_from(), None);
+        assert!(plain.resent_to().is_empty());
+
+        let folded = Email::new(
+            "list@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            concat!(
+                "Sender: List Bot\n",
+                " <list@example.com>\n",
+                "Resent-From: Resender\n",
+                "\t<resender@example.com>\n",
+                "Resent-To: jane@example.com,\n",
+                " bob@example.com\n",
+                "\n",
+                "Body"
+            )
+            .to_string(),
+        );
+
+        assert_eq!(folded.sender().as_deref(), Some("list@example.com"));
+        assert_eq!(
+            folded.resent_from().as_deref(),
+            Some("resender@example.com")
+        );
+        assert_eq!(
+            folded.resent_to(),
+            vec!["jane@example.com", "bob@example.com"]
+        );
+    }
+
+    #[test]
+    fn test_header_recipients() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec![
+                "j@d.com".to_string(),
+                "k@d.com".to_string(),
+                "hidden@d.com".to_string(),
+            ],
+            concat!(
+                "To: \"Doe, John\" <j@d.com>, k@d.com\n",
+                "Cc: Team <@relay1,@relay2:team@d.com>\n",
+                "Subject: Header recipients\n",
+                "\n",
+                "Body"
+            )
+            .to_string(),
+        );
+
+        assert_eq!(email.header_to(), vec!["j@d.com", "k@d.com"]);
+        assert_eq!(email.header_cc(), vec!["team@d.com"]);
+        assert!(email.header_bcc().is_empty());
+    }
+
+    #[test]
+    fn test_folded_header_recipients() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["a@d.com".to_string()],
+            concat!(
+                "To: A <a@d.com>,\n",
+                " B <b@d.com>,\n",
+                "\tc@d.com\n",
+                "Cc: x@d.com,\n",
+                "  y@d.com\n",
+                "Subject: Folded\n",
+                "\n",
+                "Body"
+            )
+            .to_string(),
+        );
+
+        assert_eq!(email.header_to(), vec!["a@d.com", "b@d.com", "c@d.com"]);
+        assert_eq!(email.header_cc(), vec!["x@d.com", "y@d.com"]);
+    }
+
     #[test]
     fn test_get_body() {
         let email = Email::new(
@@ -172,6 +1227,130 @@ mod tests {
         assert_eq!(email_no_body.get_body(), None);
     }
 
+    #[test]
+    fn test_body_reader() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Test\r\n\r\nHello World\r\n\r\nSecond line".to_string(),
+        );
+
+        let mut body = String::new();
+        email
+            .body_reader()
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "Hello World\r\n\r\nSecond line");
+
+        let mut body = String::new();
+        let headers_only = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Test".to_string(),
+        );
+        headers_only
+            .body_reader()
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_builder() {
+        let email = Email::builder()
+            .from("sender@example.com")
+            .to("a@example.com")
+            .to("b@example.com")
+            .header("Subject", "Built")
+            .header("X-Test", "yes")
+            .body("Hello World\r\nSecond line\r\n")
+            .build();
+
+        assert_eq!(email.from, "sender@example.com");
+        assert_eq!(email.to, vec!["a@example.com", "b@example.com"]);
+        assert_eq!(
+            email.data,
+            "Subject: Built\r\nX-Test: yes\r\n\r\nHello World\r\nSecond line\r\n"
+        );
+        assert_eq!(email.get_subject(), Some("Built"));
+        assert_eq!(email.get_header("x-test"), Some("yes"));
+        assert_eq!(email.get_body(), Some("Hello World\r\nSecond line\r\n"));
+    }
+
+    #[test]
+    fn test_body_leading_blank_lines() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Blank\r\n\r\n\r\n\r\nactual text\r\n".to_string(),
+        );
+
+        // Only the first blank line ends the headers
+        assert_eq!(email.get_subject(), Some("Blank"));
+        assert_eq!(email.get_body(), Some("\r\n\r\nactual text\r\n"));
+    }
+
+    #[test]
+    fn test_folded_header_before_body() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Folded\n\tnote: continued\nX-Other: value\n\n\n  indented: body".to_string(),
+        );
+
+        assert_eq!(email.get_subject(), Some("Folded"));
+        assert_eq!(email.get_header("X-Other"), Some("value"));
+        assert_eq!(email.get_header("note"), None);
+        assert_eq!(email.get_header("indented"), None);
+        assert_eq!(email.get_body(), Some("\n  indented: body"));
+    }
+
+    #[test]
+    fn test_to_rfc5322() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Hello\n\tworld\nFrom:sender@example.com\nTo: recipient@example.com\n\nline one\n\nline two"
+                .to_string(),
+        );
+
+        assert_eq!(
+            email.to_rfc5322(),
+            "Subject: Hello world\r\n\
+             From: sender@example.com\r\n\
+             To: recipient@example.com\r\n\
+             \r\n\
+             line one\r\n\
+             \r\n\
+             line two\r\n"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_headers() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "From: a@example.com\nDate: Thu, 1 Jan 2026 00:00:00 +0000\nfrom: b@example.com\n\nFrom: body"
+                .to_string(),
+        );
+        assert_eq!(email.header_count("From"), 2);
+        assert_eq!(email.header_count("date"), 1);
+        assert_eq!(email.header_count("Subject"), 0);
+        assert_eq!(email.has_duplicate_headers(), vec!["From"]);
+
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "From: a@example.com\nDate: Thu, 1 Jan 2026 00:00:00 +0000\nSubject: Hi\n\nBody"
+                .to_string(),
+        );
+        assert_eq!(email.header_count("From"), 1);
+        assert!(email.has_duplicate_headers().is_empty());
+    }
+
     #[test]
     fn test_contains_text() {
         let email = Email::new(
@@ -195,4 +1374,212 @@ mod tests {
 
         assert_eq!(email.data_size(), 5);
     }
+
+    #[test]
+    fn test_content_crc32() {
+        assert_eq!(!crc32_update(!0, b"123456789"), 0xCBF4_3926);
+
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Check\n\nBody".to_string(),
+        );
+        let wire = b"Subject: Check\r\n\r\nBody\r\n";
+        assert_eq!(email.content_crc32().unwrap(), !crc32_update(!0, wire));
+    }
+
+    #[test]
+    fn test_content_crc32_of_verbatim_content() {
+        let joined = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Check\n\nBody".to_string(),
+        );
+        let mut verbatim = joined.clone();
+        verbatim.raw = b"Subject: Check\r\n\r\nBody\r\n".to_vec();
+        verbatim.verbatim = true;
+        assert_eq!(
+            verbatim.content_crc32().unwrap(),
+            joined.content_crc32().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_multipart_accessors() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Content-Type: multipart/mixed; boundary=\"abc\"\n\n--abc\n\nPart\n--abc--".to_string(),
+        );
+        assert_eq!(
+            email.content_type(),
+            Some("multipart/mixed; boundary=\"abc\"")
+        );
+        assert!(email.is_multipart());
+        assert_eq!(email.mime_boundary(), Some("abc".to_string()));
+
+        let folded = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Content-Type: Multipart/Alternative;\n\tcharset=utf-8; BOUNDARY=b1=_x\n\nBody"
+                .to_string(),
+        );
+        assert!(folded.is_multipart());
+        assert_eq!(folded.mime_boundary(), Some("b1=_x".to_string()));
+    }
+
+    #[test]
+    fn test_plain_text_is_not_multipart() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Content-Type: text/plain; charset=utf-8\n\nHello".to_string(),
+        );
+        assert_eq!(email.content_type(), Some("text/plain; charset=utf-8"));
+        assert!(!email.is_multipart());
+        assert_eq!(email.mime_boundary(), None);
+    }
+
+    #[test]
+    fn test_raw_size() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: こんにちは\n\n日本語の本文".to_string(),
+        );
+
+        assert!(email.raw_size() > email.data.chars().count());
+        assert_eq!(email.raw_size(), email.data.len());
+
+        // Invalid UTF-8 is replaced in `data` but kept in `raw`
+        let mut email = email;
+        email.raw = b"caf\xe9".to_vec();
+        email.data = String::from_utf8_lossy(&email.raw).into_owned();
+        assert_eq!(email.raw_size(), 4);
+        assert_eq!(email.data_size(), 6);
+    }
+
+    #[test]
+    fn test_display() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec![
+                "user1@example.com".to_string(),
+                "user2@example.com".to_string(),
+            ],
+            "Subject: Hello there\n\nBody".to_string(),
+        );
+
+        let rendered = email.to_string();
+        assert!(rendered.contains("From: sender@example.com"));
+        assert!(rendered.contains("To: user1@example.com, user2@example.com"));
+        assert!(rendered.contains("Subject: Hello there"));
+        assert!(rendered.contains("Size: 26 bytes"));
+        assert!(rendered.ends_with(&format!("Received: {}", email.timestamp_rfc2822())));
+    }
+
+    #[test]
+    fn test_display_decodes_encoded_subject() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["user@example.com".to_string()],
+            "Subject: =?UTF-8?B?44GT44KT44Gr44Gh44Gv?= =?utf-8?q?_caf=C3=A9?= and =?ISO-8859-1?Q?gr=FC=DFe?= =?x-unknown?B?AA==?=\n\nBody".to_string(),
+        );
+
+        let rendered = email.to_string();
+        assert!(
+            rendered.contains("Subject: こんにちは café and grüße =?x-unknown?B?AA==?=\n"),
+            "{rendered}"
+        );
+        assert_eq!(decode_encoded_words("=?UTF-8?B?R3LDvMOfZQ==?="), "Grüße");
+        assert_eq!(
+            decode_encoded_words("=?UTF-8?Q?bad=ZZ?="),
+            "=?UTF-8?Q?bad=ZZ?="
+        );
+        assert_eq!(decode_encoded_words("a =? b"), "a =? b");
+    }
+
+    #[test]
+    fn test_sender_and_recipient_domains() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec![
+                "user@example.org".to_string(),
+                "admin@[192.0.2.1]".to_string(),
+            ],
+            "Test email".to_string(),
+        );
+
+        assert_eq!(email.sender_domain(), Some("example.com"));
+        assert_eq!(
+            email.recipient_domains(),
+            vec!["example.org", "[192.0.2.1]"]
+        );
+
+        // Null reverse-path
+        let bounce = Email::new(
+            String::new(),
+            vec!["user@example.org".to_string()],
+            "Bounce".to_string(),
+        );
+
+        assert_eq!(bounce.sender_domain(), None);
+    }
+
+    #[test]
+    fn test_recipients_for_domain() {
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec![
+                "alice@corp.com".to_string(),
+                "bob@other.org".to_string(),
+                "carol@CORP.COM".to_string(),
+                "admin@[192.0.2.1]".to_string(),
+            ],
+            "Test email".to_string(),
+        );
+
+        assert_eq!(
+            email.recipients_for_domain("corp.com"),
+            vec!["alice@corp.com", "carol@CORP.COM"]
+        );
+        assert_eq!(
+            email.recipients_for_domain("Other.org"),
+            vec!["bob@other.org"]
+        );
+        assert!(email.has_recipient_domain("CORP.com"));
+        assert!(email.has_recipient_domain("[192.0.2.1]"));
+        assert!(!email.has_recipient_domain("192.0.2.1"));
+        assert!(!email.has_recipient_domain("corp"));
+    }
+
+    #[test]
+    fn test_mime_version() {
+        let mime = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "MIME-Version: 1.0\nSubject: MIME\n\nBody".to_string(),
+        );
+        assert_eq!(mime.mime_version(), Some("1.0"));
+        assert!(mime.is_mime());
+
+        let plain = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Plain\n\nBody".to_string(),
+        );
+        assert_eq!(plain.mime_version(), None);
+        assert!(!plain.is_mime());
+
+        let unusual = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Mime-Version: 1.0 (produced by MailerX 2.1)\n\nBody".to_string(),
+        );
+        assert_eq!(
+            unusual.mime_version(),
+            Some("1.0 (produced by MailerX 2.1)")
+        );
+        assert!(unusual.is_mime());
+    }
 }
@@ -7,18 +7,50 @@ pub enum SmtpError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("Empty command")]
+    EmptyCommand,
+
+    #[deprecated(note = "use `EmptyCommand` or `UnrecognizedCommand`")]
     #[error("Invalid command")]
     InvalidCommand,
 
+    #[error("Command not recognized: {0}")]
+    UnrecognizedCommand(String),
+
+    #[error("Command not implemented: {0}")]
+    CommandNotImplemented(String),
+
     #[error("Invalid state for command")]
     InvalidState(String),
 
     #[error("Invalid syntax")]
     InvalidSyntax(String),
 
+    #[error("{field} address must be enclosed in angle brackets")]
+    MissingAngleBrackets { field: &'static str },
+
+    #[error("{field} address cannot be empty")]
+    EmptyAddress { field: &'static str },
+
+    #[error("Email address must contain @ symbol")]
+    NoAtSign,
+
+    #[error("Address contains more than one @ symbol")]
+    MultipleAtSigns,
+
+    #[error("Command line too long (max {max} characters)")]
+    CommandLineTooLong { max: usize },
+
+    #[error("Line too long (max {max} characters)")]
+    TextLineTooLong { max: usize },
+
+    #[deprecated(note = "use `CommandLineTooLong` or `TextLineTooLong`")]
     #[error("Line too long (max {max} characters)")]
     LineTooLong { max: usize },
 
+    #[error("Invalid line ending")]
+    InvalidLineEnding(String),
+
     #[error("Path too long (max {max} characters)")]
     PathTooLong { max: usize },
 
@@ -42,10 +74,49 @@ pub enum SmtpError {
 
     #[error("Protocol violation")]
     ProtocolViolation,
+
+    #[error("Mailbox full")]
+    MailboxFull,
+
+    #[error("Sender already specified")]
+    SenderAlreadySpecified,
+
+    #[error("Malformed message")]
+    MalformedMessage(String),
+
+    #[error("Unexpected reply: {0}")]
+    UnexpectedReply(String),
 }
 
+/// Hostname rejected by [`SmtpServer::try_new`](crate::SmtpServer::try_new)
+///
+/// Holds the rejected hostname. This is a configuration error, so unlike
+/// [`SmtpError`] it has no SMTP reply.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid hostname: {0}")]
+pub struct InvalidHostname(pub String);
+
 /// SMTP size limits as defined in RFC 821
-pub struct SmtpLimits;
+///
+/// The associated constants are the fixed protocol limits and defaults. A
+/// value of this type holds the limits a server is configured with; see
+/// [`SmtpServer::limits`](crate::SmtpServer::limits).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmtpLimits {
+    /// Maximum total size of email data, unless content is spooled
+    pub max_data_size: usize,
+    /// Maximum number of recipients per message
+    pub max_recipients: usize,
+}
+
+impl Default for SmtpLimits {
+    fn default() -> Self {
+        Self {
+            max_data_size: Self::MAX_DATA_SIZE,
+            max_recipients: Self::MAX_RECIPIENTS,
+        }
+    }
+}
 
 impl SmtpLimits {
     /// Maximum length of a user name
@@ -78,10 +149,22 @@ impl SmtpError {
     pub fn to_response_code(&self) -> &'static str {
         match self {
             SmtpError::Io(_) => "421",
+            SmtpError::EmptyCommand => "500",
+            #[allow(deprecated)]
             SmtpError::InvalidCommand => "500",
+            SmtpError::UnrecognizedCommand(_) => "500",
+            SmtpError::CommandNotImplemented(_) => "502",
             SmtpError::InvalidState(_) => "503",
             SmtpError::InvalidSyntax(_) => "501",
+            SmtpError::MissingAngleBrackets { .. } => "501",
+            SmtpError::EmptyAddress { .. } => "501",
+            SmtpError::NoAtSign => "501",
+            SmtpError::MultipleAtSigns => "501",
+            SmtpError::CommandLineTooLong { .. } => "500",
+            SmtpError::TextLineTooLong { .. } => "552",
+            #[allow(deprecated)]
             SmtpError::LineTooLong { .. } => "500",
+            SmtpError::InvalidLineEnding(_) => "500",
             SmtpError::PathTooLong { .. } => "501",
             SmtpError::TooManyRecipients { .. } => "552",
             SmtpError::TooMuchData { .. } => "552",
@@ -90,16 +173,54 @@ impl SmtpError {
             SmtpError::NonUtf8Data => "500",
             SmtpError::ConnectionClosed => "421",
             SmtpError::ProtocolViolation => "500",
+            SmtpError::MailboxFull => "452",
+            SmtpError::SenderAlreadySpecified => "503",
+            SmtpError::MalformedMessage(_) => "554",
+            SmtpError::UnexpectedReply(_) => "554",
+        }
+    }
+
+    /// Enhanced status code (RFC 3463) given before the reply text, if any
+    pub fn enhanced_code(&self) -> Option<&'static str> {
+        match self {
+            SmtpError::UnrecognizedCommand(_) => Some("5.5.1"),
+            SmtpError::CommandNotImplemented(_) => Some("5.5.1"),
+            _ => None,
         }
     }
 
     pub fn to_response_message(&self) -> String {
+        let text = self.reply_text();
+        match self.enhanced_code() {
+            Some(code) => format!("{code} {text}"),
+            None => text,
+        }
+    }
+
+    /// Reply text, without the enhanced status code
+    fn reply_text(&self) -> String {
         match self {
             SmtpError::Io(_) => "Service not available".to_string(),
+            SmtpError::EmptyCommand => "Syntax error, command unrecognized".to_string(),
+            #[allow(deprecated)]
             SmtpError::InvalidCommand => "Syntax error, command unrecognized".to_string(),
+            SmtpError::UnrecognizedCommand(verb) => format!("Command \"{verb}\" not recognized"),
+            SmtpError::CommandNotImplemented(verb) => {
+                format!("Command \"{verb}\" not implemented")
+            }
             SmtpError::InvalidState(msg) => format!("Bad sequence of commands: {msg}"),
             SmtpError::InvalidSyntax(msg) => format!("Syntax error: {msg}"),
+            SmtpError::MissingAngleBrackets { .. }
+            | SmtpError::EmptyAddress { .. }
+            | SmtpError::NoAtSign
+            | SmtpError::MultipleAtSigns => format!("Syntax error: {self}"),
+            SmtpError::CommandLineTooLong { max } => {
+                format!("Command line too long (max {max} characters)")
+            }
+            SmtpError::TextLineTooLong { max } => format!("Line too long (max {max} characters)"),
+            #[allow(deprecated)]
             SmtpError::LineTooLong { max } => format!("Line too long (max {max} characters)"),
+            SmtpError::InvalidLineEnding(msg) => format!("Invalid line ending: {msg}"),
             SmtpError::PathTooLong { max } => format!("Path too long (max {max} characters)"),
             SmtpError::TooManyRecipients { max } => format!("Too many recipients (max {max})"),
             SmtpError::TooMuchData { max } => format!("Too much mail data (max {max} bytes)"),
@@ -112,6 +233,10 @@ impl SmtpError {
             SmtpError::NonUtf8Data => "Invalid character encoding".to_string(),
             SmtpError::ConnectionClosed => "Connection closed".to_string(),
             SmtpError::ProtocolViolation => "Protocol violation".to_string(),
+            SmtpError::MailboxFull => "Mailbox full".to_string(),
+            SmtpError::SenderAlreadySpecified => "Sender already specified".to_string(),
+            SmtpError::MalformedMessage(msg) => format!("Malformed message: {msg}"),
+            SmtpError::UnexpectedReply(reply) => format!("Unexpected reply: {reply}"),
         }
     }
 }
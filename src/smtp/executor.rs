@@ -0,0 +1,25 @@
+//! Executors for servicing client connections
+
+use std::thread;
+
+/// A unit of work that services one client connection
+pub type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs connection handlers, e.g. on a thread pool
+///
+/// Implement this to bound or observe the worker threads used by
+/// [`SmtpServer::serve_on`](crate::SmtpServer::serve_on).
+pub trait Spawn {
+    /// Run `f` to completion, typically on another thread
+    fn spawn(&self, f: Job);
+}
+
+/// Spawns a new thread for every connection
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadPerConnection;
+
+impl Spawn for ThreadPerConnection {
+    fn spawn(&self, f: Job) {
+        thread::spawn(f);
+    }
+}
@@ -0,0 +1,217 @@
+//! Convenience wrapper around the receiving end of the email channel
+
+use crate::smtp::email::Email;
+
+use std::cell::RefCell;
+use std::collections::{VecDeque, vec_deque};
+use std::iter::Chain;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Receives emails delivered by an [`SmtpServer`](crate::SmtpServer)
+#[derive(Debug)]
+pub struct Mailbox {
+    /// Receiving end of the server's email channel
+    receiver: mpsc::Receiver<Email>,
+    /// Emails taken from the channel by `peek` but not yet returned
+    buffer: RefCell<VecDeque<Email>>,
+}
+
+impl Mailbox {
+    /// Wrap the receiving end of an email channel
+    pub fn new(receiver: mpsc::Receiver<Email>) -> Self {
+        Self {
+            receiver,
+            buffer: RefCell::default(),
+        }
+    }
+
+    /// Look at the next email without removing it or blocking
+    ///
+    /// Repeated calls return the same email until it is taken, e.g. with
+    /// [`pop`](Self::pop).
+    pub fn peek(&mut self) -> Option<&Email> {
+        let buffer = self.buffer.get_mut();
+        if buffer.is_empty() {
+            buffer.extend(self.receiver.try_recv());
+        }
+        buffer.front()
+    }
+
+    /// Take the next email without blocking
+    pub fn pop(&self) -> Option<Email> {
+        let buffered = self.buffer.borrow_mut().pop_front();
+        buffered.or_else(|| self.receiver.try_recv().ok())
+    }
+
+    /// Take every email received so far without blocking
+    ///
+    /// Emails are returned in arrival order and the channel is left empty.
+    pub fn drain(&self) -> Vec<Email> {
+        let mut emails: Vec<Email> = self.buffer.borrow_mut().drain(..).collect();
+        emails.extend(self.receiver.try_iter());
+        emails
+    }
+
+    /// Iterate over emails as they arrive, until none arrives within `timeout`
+    ///
+    /// Iteration also ends once the channel is disconnected and empty.
+    pub fn iter_timeout(&self, timeout: Duration) -> impl Iterator<Item = Email> + '_ {
+        std::iter::from_fn(move || {
+            let buffered = self.buffer.borrow_mut().pop_front();
+            buffered.or_else(|| self.receiver.recv_timeout(timeout).ok())
+        })
+    }
+
+    /// Check that no email arrives within `timeout`
+    ///
+    /// An email already seen with [`peek`](Self::peek) fails the check
+    /// immediately. See [`assert_empty`].
+    #[allow(clippy::result_large_err)] // Returned by value for easy inspection
+    pub fn assert_empty(&self, timeout: Duration) -> Result<(), Email> {
+        match self.buffer.borrow_mut().pop_front() {
+            Some(email) => Err(email),
+            None => assert_empty(&self.receiver, timeout),
+        }
+    }
+}
+
+/// Check that no email arrives on `receiver` within `timeout`
+///
+/// Returns the unexpected email as the error, which makes for a clearer
+/// failure message than a boolean. A disconnected channel counts as empty.
+#[allow(clippy::result_large_err)] // Returned by value for easy inspection
+pub fn assert_empty(receiver: &mpsc::Receiver<Email>, timeout: Duration) -> Result<(), Email> {
+    match receiver.recv_timeout(timeout) {
+        Ok(email) => Err(email),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Blocks for each email until the server's sender is dropped
+impl IntoIterator for Mailbox {
+    type Item = Email;
+    type IntoIter = Chain<vec_deque::IntoIter<Email>, mpsc::IntoIter<Email>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.buffer.into_inner().into_iter().chain(self.receiver)
+    }
+}
+
+impl From<mpsc::Receiver<Email>> for Mailbox {
+    fn from(receiver: mpsc::Receiver<Email>) -> Self {
+        Self::new(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn email(subject: &str) -> Email {
+        Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            format!("Subject: {subject}"),
+        )
+    }
+
+    #[test]
+    fn test_drain() {
+        let (tx, rx) = mpsc::channel();
+        let mailbox = Mailbox::new(rx);
+
+        for subject in ["one", "two", "three"] {
+            tx.send(email(subject)).unwrap();
+        }
+
+        let subjects: Vec<_> = mailbox
+            .drain()
+            .iter()
+            .map(|email| email.get_subject().unwrap().to_string())
+            .collect();
+        assert_eq!(subjects, ["one", "two", "three"]);
+        assert!(mailbox.drain().is_empty());
+    }
+
+    #[test]
+    fn test_peek_and_pop() {
+        let (tx, rx) = mpsc::channel();
+        let mut mailbox = Mailbox::new(rx);
+        assert!(mailbox.peek().is_none());
+
+        tx.send(email("first")).unwrap();
+        tx.send(email("second")).unwrap();
+        assert_eq!(mailbox.peek().unwrap().get_subject(), Some("first"));
+        assert_eq!(mailbox.peek().unwrap().get_subject(), Some("first"));
+
+        let popped = mailbox.pop().unwrap();
+        assert_eq!(popped.get_subject(), Some("first"));
+        assert_eq!(mailbox.peek().unwrap().get_subject(), Some("second"));
+
+        // Other ways of taking emails see the peeked one first
+        tx.send(email("third")).unwrap();
+        let subjects: Vec<_> = mailbox
+            .drain()
+            .iter()
+            .map(|email| email.get_subject().unwrap().to_string())
+            .collect();
+        assert_eq!(subjects, ["second", "third"]);
+        assert!(mailbox.pop().is_none());
+    }
+
+    #[test]
+    fn test_assert_empty() {
+        let (tx, rx) = mpsc::channel();
+        let mailbox = Mailbox::new(rx);
+        assert!(mailbox.assert_empty(Duration::from_millis(10)).is_ok());
+
+        tx.send(email("unexpected")).unwrap();
+        let unexpected = mailbox.assert_empty(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(unexpected.get_subject(), Some("unexpected"));
+
+        drop(tx);
+        assert!(mailbox.assert_empty(Duration::from_millis(10)).is_ok());
+    }
+
+    #[test]
+    fn test_iter_timeout() {
+        let (tx, rx) = mpsc::channel();
+        let mailbox = Mailbox::new(rx);
+
+        for subject in ["one", "two", "three"] {
+            tx.send(email(subject)).unwrap();
+        }
+
+        let timeout = Duration::from_millis(50);
+        let start = Instant::now();
+        let emails: Vec<_> = mailbox.iter_timeout(timeout).collect();
+        assert_eq!(emails.len(), 3);
+        assert!(start.elapsed() >= timeout);
+
+        // Emails arriving later are picked up by the next iteration
+        tx.send(email("four")).unwrap();
+        assert_eq!(mailbox.iter_timeout(timeout).count(), 1);
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let (tx, rx) = mpsc::channel();
+        let mailbox = Mailbox::new(rx);
+
+        let sender = std::thread::spawn(move || {
+            for subject in ["one", "two"] {
+                tx.send(email(subject)).unwrap();
+            }
+        });
+
+        // Ends once the sender is dropped
+        let subjects: Vec<_> = mailbox
+            .into_iter()
+            .map(|email| email.get_subject().unwrap().to_string())
+            .collect();
+        assert_eq!(subjects, ["one", "two"]);
+        sender.join().unwrap();
+    }
+}
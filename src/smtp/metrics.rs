@@ -0,0 +1,81 @@
+//! Server activity counters
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A point-in-time snapshot of server activity
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerMetrics {
+    /// Number of client connections accepted
+    pub connections: u64,
+    /// Number of messages accepted and delivered
+    pub messages: u64,
+    /// Number of bytes read from clients
+    pub bytes_received: u64,
+    /// Number of commands answered with a 4xx or 5xx reply
+    pub rejected_commands: u64,
+}
+
+/// Thread-safe counters shared by every connection of a server
+#[derive(Debug, Default)]
+pub(crate) struct MetricsCounters {
+    connections: AtomicU64,
+    messages: AtomicU64,
+    bytes_received: AtomicU64,
+    rejected_commands: AtomicU64,
+}
+
+impl MetricsCounters {
+    /// Record an accepted connection
+    pub(crate) fn connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a delivered message
+    pub(crate) fn message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record bytes read from a client
+    pub(crate) fn bytes_received(&self, count: usize) {
+        self.bytes_received
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Record a rejected command
+    pub(crate) fn rejected_command(&self) {
+        self.rejected_commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the current counter values
+    pub(crate) fn snapshot(&self) -> ServerMetrics {
+        ServerMetrics {
+            connections: self.connections.load(Ordering::Relaxed),
+            messages: self.messages.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            rejected_commands: self.rejected_commands.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let counters = MetricsCounters::default();
+        assert_eq!(counters.snapshot(), ServerMetrics::default());
+
+        counters.connection();
+        counters.message();
+        counters.bytes_received(42);
+        counters.rejected_command();
+        counters.rejected_command();
+
+        let metrics = counters.snapshot();
+        assert_eq!(metrics.connections, 1);
+        assert_eq!(metrics.messages, 1);
+        assert_eq!(metrics.bytes_received, 42);
+        assert_eq!(metrics.rejected_commands, 2);
+    }
+}
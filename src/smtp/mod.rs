@@ -1,14 +1,34 @@
 //! SMTP server implementation
 
+#[cfg(feature = "tokio")]
+pub mod async_mailbox;
 pub mod commands;
 pub mod email;
 pub mod error;
+pub mod executor;
+pub mod mailbox;
+pub mod metrics;
 pub mod response;
 pub mod server;
 pub mod session;
+mod spool;
+pub mod store;
+#[cfg(feature = "test-utils")]
+pub mod test_server;
 
-pub use email::Email;
-pub use error::{SmtpError, SmtpLimits};
+#[cfg(feature = "tokio")]
+pub use async_mailbox::AsyncMailbox;
+pub use commands::Command;
+pub use email::{DataLine, Direction, Email, EmailBuilder, RejectedEmail};
+pub use error::{InvalidHostname, SmtpError, SmtpLimits};
+pub use executor::{Job, Spawn, ThreadPerConnection};
+pub use mailbox::{Mailbox, assert_empty};
+pub use metrics::ServerMetrics;
 pub use response::SmtpResponse;
-pub use server::SmtpServer;
-pub use session::{SmtpSession, SmtpState};
+pub use server::{
+    CommandInterceptor, CommandMatcher, GreetingDelayFn, MultiServerHandle, SmtpServer,
+};
+pub use session::{BodyType, ConnectionId, LineOverflowPolicy, SmtpSession, SmtpState};
+pub use store::{Eviction, MailStore};
+#[cfg(feature = "test-utils")]
+pub use test_server::TestServer;
@@ -1,5 +1,8 @@
 //! SMTP response handling
 
+#[cfg(feature = "ehlo")]
+use crate::smtp::error::SmtpLimits;
+
 /// Represents an SMTP response that can be sent to a client
 #[derive(Debug, Clone)]
 pub struct SmtpResponse {
@@ -45,27 +48,91 @@ impl SmtpResponse {
         Self::new("250", &format!("{hostname} Hello {client_domain}"))
     }
 
-    /// Create an EHLO response (250) with capabilities
+    /// Create an EHLO response (250) with the default capabilities
     #[cfg(feature = "ehlo")]
     pub fn ehlo(hostname: &str, client_domain: &str) -> Self {
-        let capabilities = vec!["PIPELINING".to_owned(), "SIZE 10240000".to_owned()];
+        Self::ehlo_with(hostname, client_domain, &Self::default_capabilities())
+    }
+
+    /// Create an EHLO response (250) advertising the given capabilities
+    #[cfg(feature = "ehlo")]
+    pub fn ehlo_with(hostname: &str, client_domain: &str, capabilities: &[String]) -> Self {
         Self::new_multiline(
             "250",
             &format!("{hostname} Hello {client_domain}"),
-            capabilities,
+            capabilities.to_vec(),
         )
     }
 
+    /// The capabilities advertised by default, including those implied by
+    /// enabled features
+    #[cfg(feature = "ehlo")]
+    pub fn default_capabilities() -> Vec<String> {
+        Self::capabilities_with_size(SmtpLimits::MAX_DATA_SIZE)
+    }
+
+    /// The default capabilities, advertising `max_size` as the `SIZE` limit
+    #[cfg(feature = "ehlo")]
+    pub fn capabilities_with_size(max_size: usize) -> Vec<String> {
+        let mut capabilities = vec![
+            "PIPELINING".to_owned(),
+            format!("SIZE {max_size}"),
+            "8BITMIME".to_owned(),
+        ];
+        if cfg!(feature = "chunking") {
+            capabilities.push("CHUNKING".to_owned());
+        }
+        if cfg!(feature = "binarymime") {
+            capabilities.push("BINARYMIME".to_owned());
+        }
+        if cfg!(feature = "smtputf8") {
+            capabilities.push("SMTPUTF8".to_owned());
+        }
+        capabilities
+    }
+
     /// Create a DATA intermediate response (354)
     pub fn data_start() -> Self {
         Self::new("354", "End data with <CR><LF>.<CR><LF>")
     }
 
+    /// Create a BDAT chunk acknowledgement (250)
+    #[cfg(feature = "chunking")]
+    pub fn chunk_received(size: usize) -> Self {
+        Self::new("250", &format!("{size} octets received"))
+    }
+
     /// Create a QUIT response (221)
     pub fn quit() -> Self {
         Self::new("221", "Bye")
     }
 
+    /// Create a service unavailable response (421)
+    pub fn service_unavailable() -> Self {
+        Self::new("421", "Service not available, closing transmission channel")
+    }
+
+    /// Create a mailbox busy response (450)
+    pub fn mailbox_busy() -> Self {
+        Self::new(
+            "450",
+            "Requested mail action not taken: mailbox unavailable",
+        )
+    }
+
+    /// Create a local processing error response (451)
+    pub fn local_error() -> Self {
+        Self::new("451", "Requested action aborted: local error in processing")
+    }
+
+    /// Create an insufficient storage response (452)
+    pub fn insufficient_storage() -> Self {
+        Self::new(
+            "452",
+            "Requested action not taken: insufficient system storage",
+        )
+    }
+
     /// Create an error response from an error
     pub fn error(code: &str, message: &str) -> Self {
         Self::new(code, message)
@@ -89,6 +156,38 @@ impl SmtpResponse {
         }
     }
 
+    /// Split a single-line message over continuation lines
+    ///
+    /// Each formatted line, including the code, separator and CRLF, fits in
+    /// `max_line_length` bytes. Concatenating the lines gives the original
+    /// message. Multiline responses are returned unchanged.
+    pub(crate) fn split_message(&self, max_line_length: usize) -> Self {
+        if self.multiline.is_some() {
+            return self.clone();
+        }
+
+        let max_text = max_line_length.saturating_sub(self.code.len() + 3).max(1);
+        let mut chunks = Vec::new();
+        let mut rest = self.message.as_str();
+        while rest.len() > max_text {
+            // Split on a character boundary
+            let mut end = max_text;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (chunk, tail) = rest.split_at(end);
+            chunks.push(chunk.to_string());
+            rest = tail;
+        }
+
+        if chunks.is_empty() {
+            return self.clone();
+        }
+        chunks.push(rest.to_string());
+        let first = chunks.remove(0);
+        Self::new_multiline(&self.code, &first, chunks)
+    }
+
     /// Check if this is a success response (2xx)
     pub fn is_success(&self) -> bool {
         self.code.starts_with('2')
@@ -143,7 +242,41 @@ mod tests {
         let formatted = response.format();
         assert!(formatted.contains("250-server.local Hello client.local\r\n"));
         assert!(formatted.contains("250-PIPELINING\r\n"));
-        assert!(formatted.contains("250 SIZE 10240000\r\n"));
+        assert!(formatted.contains(&format!("SIZE {}\r\n", SmtpLimits::MAX_DATA_SIZE)));
+        assert!(formatted.contains("8BITMIME\r\n"));
+    }
+
+    #[cfg(feature = "ehlo")]
+    #[test]
+    fn test_ehlo_with_capabilities() {
+        let capabilities = ["DSN".to_owned()];
+        let response = SmtpResponse::ehlo_with("server.local", "client.local", &capabilities);
+        assert_eq!(response.multiline, Some(vec!["DSN".to_owned()]));
+        assert_eq!(
+            SmtpResponse::ehlo("server.local", "client.local").multiline,
+            Some(SmtpResponse::default_capabilities())
+        );
+    }
+
+    #[cfg(feature = "chunking")]
+    #[test]
+    fn test_ehlo_advertises_chunking() {
+        let response = SmtpResponse::ehlo("server.local", "client.local");
+        assert!(response.format().contains("CHUNKING\r\n"));
+    }
+
+    #[cfg(feature = "binarymime")]
+    #[test]
+    fn test_ehlo_advertises_binarymime() {
+        let response = SmtpResponse::ehlo("server.local", "client.local");
+        assert!(response.format().contains("BINARYMIME\r\n"));
+    }
+
+    #[cfg(feature = "smtputf8")]
+    #[test]
+    fn test_ehlo_advertises_smtputf8() {
+        let response = SmtpResponse::ehlo("server.local", "client.local");
+        assert!(response.format().contains("250 SMTPUTF8\r\n"));
     }
 
     #[test]
@@ -153,6 +286,21 @@ mod tests {
         assert_eq!(response.message, "End data with <CR><LF>.<CR><LF>");
     }
 
+    #[test]
+    fn test_transient_error_responses() {
+        let responses = [
+            (SmtpResponse::service_unavailable(), "421"),
+            (SmtpResponse::mailbox_busy(), "450"),
+            (SmtpResponse::local_error(), "451"),
+            (SmtpResponse::insufficient_storage(), "452"),
+        ];
+        for (response, code) in responses {
+            assert_eq!(response.code, code);
+            assert!(response.is_error(), "{code} should be an error");
+            assert!(!response.is_success());
+        }
+    }
+
     #[test]
     fn test_quit_response() {
         let response = SmtpResponse::quit();
@@ -207,4 +355,33 @@ mod tests {
         let success_response = SmtpResponse::new("250", "OK");
         assert!(!success_response.is_error());
     }
+
+    #[test]
+    fn test_split_message() {
+        let message = "x".repeat(600);
+        let response = SmtpResponse::new("250", &message).split_message(512);
+        let formatted = response.format();
+
+        let lines: Vec<&str> = formatted.split_terminator("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.len() + 2 <= 512));
+        assert!(lines[0].starts_with("250-"));
+        assert!(lines[1].starts_with("250 "));
+        let text: String = lines.iter().map(|line| &line[4..]).collect();
+        assert_eq!(text, message);
+
+        // Short messages stay on one line
+        let response = SmtpResponse::ok().split_message(512);
+        assert_eq!(response.format(), "250 OK\r\n");
+    }
+
+    #[test]
+    fn test_split_message_char_boundary() {
+        let message = "é".repeat(300);
+        let response = SmtpResponse::new("550", &message).split_message(512);
+
+        let mut text = response.message.clone();
+        text.extend(response.multiline.unwrap());
+        assert_eq!(text, message);
+    }
 }
@@ -1,20 +1,355 @@
 //! SMTP server implementation
 
-use crate::smtp::commands::SmtpCommandHandler;
-use crate::smtp::email::Email;
-use crate::smtp::error::{SmtpError, SmtpLimits};
+use crate::smtp::commands::{RcptValidator, SmtpCommandHandler};
+use crate::smtp::email::{DataLine, Direction, Email, RejectedEmail};
+use crate::smtp::error::{InvalidHostname, SmtpError, SmtpLimits};
+use crate::smtp::executor::{Spawn, ThreadPerConnection};
+use crate::smtp::metrics::{MetricsCounters, ServerMetrics};
 use crate::smtp::response::SmtpResponse;
-use crate::smtp::session::SmtpSession;
+#[cfg(feature = "chunking")]
+use crate::smtp::session::PendingChunk;
+use crate::smtp::session::{ConnectionId, LineOverflowPolicy, SmtpSession};
+use crate::smtp::store::MailStore;
 
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+#[cfg(feature = "chunking")]
+use std::io::Read;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// How often `serve_until` checks for a stop signal while idle
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Computes the delay before the greeting is sent, given the peer address
+pub type GreetingDelayFn = Arc<dyn Fn(SocketAddr) -> Duration + Send + Sync>;
+
+/// Inspects a command before the built-in handler, optionally replying instead
+pub type CommandInterceptor = Arc<dyn Fn(&str, &SmtpSession) -> Option<SmtpResponse> + Send + Sync>;
+
+/// Selects the command that a scripted response answers
+///
+/// A matcher names a command verb, such as `MAIL`, and optionally which
+/// occurrence of that verb it matches. Occurrences are counted from 0 across
+/// all connections to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandMatcher {
+    verb: String,
+    occurrence: Option<usize>,
+}
+
+impl CommandMatcher {
+    /// Match any occurrence of `verb`
+    pub fn verb(verb: &str) -> Self {
+        Self {
+            verb: verb.to_ascii_uppercase(),
+            occurrence: None,
+        }
+    }
+
+    /// Match only the occurrence of `verb` with the given index
+    pub fn nth(verb: &str, occurrence: usize) -> Self {
+        Self {
+            verb: verb.to_ascii_uppercase(),
+            occurrence: Some(occurrence),
+        }
+    }
+
+    /// Whether this matcher selects the `occurrence`-th command `verb`
+    pub fn matches(&self, verb: &str, occurrence: usize) -> bool {
+        self.verb.eq_ignore_ascii_case(verb) && self.occurrence.is_none_or(|n| n == occurrence)
+    }
+}
+
+/// Scripted responses not yet sent, shared by clones of a server
+#[derive(Debug, Default)]
+struct ResponseScript {
+    responses: VecDeque<(CommandMatcher, SmtpResponse)>,
+    /// Number of times each verb has been received
+    seen: HashMap<String, usize>,
+}
+
+/// Replaces each response just before it is sent
+type ResponseInterceptor = Arc<dyn Fn(&SmtpResponse) -> SmtpResponse + Send + Sync>;
+
+/// Decides whether a peer may use the server
+type ConnectionFilter = Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>;
+
+/// Called with the session after each command is processed
+type SessionObserver = Arc<dyn Fn(&SmtpSession) + Send + Sync>;
+
+/// Called with each accepted email before it is sent to the channel
+type EmailCallback = Arc<dyn Fn(&Email) + Send + Sync>;
+
+/// Checks a received email, returning the reply code and message to reject it
+type DataValidator = Arc<dyn Fn(&Email) -> Result<(), (String, String)> + Send + Sync>;
+
+/// A client connection the server can speak SMTP over
+trait Connection: std::io::Read + Write + Sized {
+    /// Create a second handle to the connection for buffered reading
+    fn try_clone(&self) -> std::io::Result<Self>;
+
+    /// Get the peer address, if the transport has one
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// A listener the server can accept connections from
+trait Listener {
+    /// Connections accepted by this listener
+    type Stream: Connection;
+
+    /// Wait for the next connection
+    fn accept_connection(&self) -> std::io::Result<Self::Stream>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_connection(&self) -> std::io::Result<TcpStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_connection(&self) -> std::io::Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+/// Where the server hands over delivered emails
+trait EmailSink {
+    /// Send an email, ignoring a disconnected receiver
+    fn send_email(&self, email: Email);
+}
+
+impl EmailSink for mpsc::Sender<Email> {
+    fn send_email(&self, email: Email) {
+        let _ = self.send(email);
+    }
+}
+
+impl EmailSink for mpsc::SyncSender<Email> {
+    fn send_email(&self, email: Email) {
+        // Blocks while the channel is full
+        let _ = self.send(email);
+    }
+}
+
+/// Removes a Unix socket file when dropped
+#[cfg(unix)]
+struct SocketFileGuard(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFileGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Handle to a server listening on several addresses, from
+/// [`SmtpServer::start_multi`]
+///
+/// The server is stopped when this value is dropped. Connections still in
+/// progress are allowed to finish.
+#[derive(Debug)]
+pub struct MultiServerHandle {
+    /// Addresses the listeners are bound to, in the order given
+    addrs: Vec<SocketAddr>,
+    /// Set to stop the accept loops
+    stop: Arc<AtomicBool>,
+    /// Threads running the accept loops
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl MultiServerHandle {
+    /// Get the addresses the server is listening on, in the order given
+    ///
+    /// Useful to find the ports chosen for addresses with port 0.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Stop every listener and wait for the accept loops to finish
+    pub fn shutdown(self) {
+        drop(self);
+    }
+}
+
+impl Drop for MultiServerHandle {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        // Wake each accept loop so it notices the stop flag
+        for &addr in &self.addrs {
+            wake_accept_loop(addr);
+        }
+
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// State of one serving loop, shared with the connections it accepts
+#[derive(Debug, Default)]
+struct ServeLoop {
+    /// Number of messages accepted through this loop
+    messages: AtomicU64,
+    /// Addresses of the blocking accept loops, woken once the maximum
+    /// number of messages is reached
+    wake_addrs: Vec<SocketAddr>,
+}
 
 /// Main SMTP server that handles connections and sends emails to a channel
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct SmtpServer {
     /// Server hostname
     hostname: String,
+    /// Optional per-peer delay applied before sending the greeting
+    greeting_delay_fn: Option<GreetingDelayFn>,
+    /// Response sent instead of the default greeting
+    greeting_override: Option<SmtpResponse>,
+    /// Optional filter deciding which peers are greeted
+    connection_filter: Option<ConnectionFilter>,
+    /// Optional hook called before the built-in command handler
+    command_interceptor: Option<CommandInterceptor>,
+    /// Optional hook applied to every response before it is sent
+    response_interceptor: Option<ResponseInterceptor>,
+    /// Optional replies scripted for specific commands
+    response_script: Option<Arc<Mutex<ResponseScript>>>,
+    /// Optional callback invoked with the session after each command
+    session_observer: Option<SessionObserver>,
+    /// Optional callback invoked for each accepted email
+    on_email: Option<EmailCallback>,
+    /// Optional check that may reject an email at the end of data
+    data_validator: Option<DataValidator>,
+    /// Optional channel that rejected messages are sent to
+    rejection_sender: Option<mpsc::Sender<RejectedEmail>>,
+    /// Optional check that may reject each recipient
+    rcpt_validator: Option<Arc<RcptValidator>>,
+    /// Capabilities advertised in EHLO replies, replacing the defaults
+    #[cfg(feature = "ehlo")]
+    capabilities: Option<Vec<String>>,
+    /// Optional store that accepted emails are also added to
+    store: Option<MailStore>,
+    /// Whether to add a `Message-ID` header to messages that lack one
+    message_id: bool,
+    /// Whether to reject DATA terminated before the end of the header block
+    strict_header_block: bool,
+    /// Whether DATA lines must use CRLF line endings only
+    strict_crlf: bool,
+    /// How DATA lines over the text line limit are handled
+    line_overflow_policy: LineOverflowPolicy,
+    /// Whether emails carry the transcript of the conversation
+    capture_transcript: bool,
+    /// Whether HELO and EHLO are accepted without a domain
+    lenient_helo: bool,
+    /// Whether MAIL and RCPT accept an address without angle brackets
+    optional_angle_brackets: bool,
+    /// Text of the NOOP reply, replacing `OK`
+    noop_message: Option<String>,
+    /// Whether the end-of-data reply reports the received size
+    size_in_reply: bool,
+    /// Whether the end-of-data reply reports a checksum of the content
+    echo_data_checksum: bool,
+    /// Directory that large DATA content is written to
+    spool_dir: Option<PathBuf>,
+    /// Maximum size of spooled message content
+    max_spool_size: Option<usize>,
+    /// Size and recipient limits applied to each transaction
+    limits: SmtpLimits,
+    /// Label given to connections accepted by this server's listener
+    listener_label: Option<String>,
+    /// Number of accepted messages after which the server stops
+    max_messages: Option<u64>,
+    /// Whether the domain of each address is lowercased
+    normalize_addresses: bool,
+    /// Whether raw content keeps line endings exactly as received
+    verbatim_data: bool,
+    /// Activity counters, shared by clones of this server
+    metrics: Arc<MetricsCounters>,
+    /// Number of connection IDs handed out, shared by clones of this server
+    connection_ids: Arc<AtomicU64>,
+    /// State of the loop currently serving connections
+    serve_loop: Arc<ServeLoop>,
+}
+
+impl fmt::Debug for SmtpServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("SmtpServer");
+        debug
+            .field("hostname", &self.hostname)
+            .field("greeting_delay_fn", &self.greeting_delay_fn.is_some())
+            .field("greeting_override", &self.greeting_override)
+            .field("connection_filter", &self.connection_filter.is_some())
+            .field("command_interceptor", &self.command_interceptor.is_some())
+            .field("response_interceptor", &self.response_interceptor.is_some())
+            .field("response_script", &self.response_script.is_some())
+            .field("session_observer", &self.session_observer.is_some())
+            .field("on_email", &self.on_email.is_some())
+            .field("data_validator", &self.data_validator.is_some())
+            .field("rejection_sender", &self.rejection_sender.is_some())
+            .field("rcpt_validator", &self.rcpt_validator.is_some())
+            .field("store", &self.store);
+        #[cfg(feature = "ehlo")]
+        debug.field("capabilities", &self.capabilities);
+        debug
+            .field("message_id", &self.message_id)
+            .field("strict_header_block", &self.strict_header_block)
+            .field("strict_crlf", &self.strict_crlf)
+            .field("line_overflow_policy", &self.line_overflow_policy)
+            .field("capture_transcript", &self.capture_transcript)
+            .field("lenient_helo", &self.lenient_helo)
+            .field("optional_angle_brackets", &self.optional_angle_brackets)
+            .field("noop_message", &self.noop_message)
+            .field("size_in_reply", &self.size_in_reply)
+            .field("echo_data_checksum", &self.echo_data_checksum)
+            .field("spool_dir", &self.spool_dir)
+            .field("max_spool_size", &self.max_spool_size)
+            .field("limits", &self.limits)
+            .field("listener_label", &self.listener_label)
+            .field("max_messages", &self.max_messages)
+            .field("normalize_addresses", &self.normalize_addresses)
+            .field("verbatim_data", &self.verbatim_data)
+            .field("metrics", &self.metrics)
+            .field("connection_ids", &self.connection_ids)
+            .field("serve_loop", &self.serve_loop)
+            .finish()
+    }
 }
 
 impl SmtpServer {
@@ -22,7 +357,410 @@ impl SmtpServer {
     pub fn new(hostname: &str) -> Self {
         Self {
             hostname: hostname.to_owned(),
+            greeting_delay_fn: None,
+            greeting_override: None,
+            connection_filter: None,
+            command_interceptor: None,
+            response_interceptor: None,
+            response_script: None,
+            session_observer: None,
+            on_email: None,
+            data_validator: None,
+            rejection_sender: None,
+            rcpt_validator: None,
+            #[cfg(feature = "ehlo")]
+            capabilities: None,
+            store: None,
+            message_id: false,
+            strict_header_block: false,
+            strict_crlf: false,
+            line_overflow_policy: LineOverflowPolicy::default(),
+            capture_transcript: false,
+            lenient_helo: false,
+            optional_angle_brackets: false,
+            noop_message: None,
+            size_in_reply: false,
+            echo_data_checksum: false,
+            spool_dir: None,
+            max_spool_size: None,
+            limits: SmtpLimits::default(),
+            listener_label: None,
+            max_messages: None,
+            normalize_addresses: false,
+            verbatim_data: false,
+            metrics: Arc::default(),
+            connection_ids: Arc::default(),
+            serve_loop: Arc::default(),
+        }
+    }
+
+    /// Create a new SMTP server, checking that `hostname` is usable in replies
+    ///
+    /// The hostname must be a domain name such as `mail.example.com` or an
+    /// address literal such as `[127.0.0.1]` or `[IPv6:::1]` (RFC 5321
+    /// section 4.1.3). [`new`](Self::new) accepts any string, which can
+    /// produce malformed greeting and HELO replies.
+    pub fn try_new(hostname: &str) -> Result<Self, InvalidHostname> {
+        if !is_valid_hostname(hostname) {
+            return Err(InvalidHostname(hostname.to_owned()));
         }
+        Ok(Self::new(hostname))
+    }
+
+    /// Delay the greeting of every TCP connection by `delay`
+    ///
+    /// Useful for testing client connect and banner read timeouts. The delay
+    /// only holds up the connection being greeted when connections are served
+    /// concurrently, e.g. with [`serve_on`](Self::serve_on).
+    pub fn with_greeting_delay(self, delay: Duration) -> Self {
+        self.with_greeting_delay_fn(Arc::new(move |_| delay))
+    }
+
+    /// Delay the greeting by a duration computed from the peer address
+    ///
+    /// Useful for emulating tarpitting of slow or suspicious clients.
+    /// Connections over a Unix socket have no peer address, so they are
+    /// greeted without delay and `delay_fn` is not called.
+    pub fn with_greeting_delay_fn(mut self, delay_fn: GreetingDelayFn) -> Self {
+        self.greeting_delay_fn = Some(delay_fn);
+        self
+    }
+
+    /// Greet clients with `greeting` instead of the default `220` response
+    ///
+    /// A greeting other than 2xx, such as `421` or `554`, models a server
+    /// refusing service: the connection is closed right after sending it.
+    pub fn with_greeting_override(mut self, greeting: SmtpResponse) -> Self {
+        self.greeting_override = Some(greeting);
+        self
+    }
+
+    /// Only serve peers for which `filter` returns true
+    ///
+    /// Rejected peers receive `554 Access denied` instead of the greeting and
+    /// are disconnected. Unix socket peers have no address and are not filtered.
+    pub fn with_connection_filter(
+        mut self,
+        filter: Box<dyn Fn(SocketAddr) -> bool + Send + Sync>,
+    ) -> Self {
+        self.connection_filter = Some(Arc::from(filter));
+        self
+    }
+
+    /// Intercept commands before they reach the built-in handler
+    ///
+    /// The interceptor sees every command line outside of DATA. Returning
+    /// `Some(response)` sends that reply without changing the session state;
+    /// returning `None` lets the command be handled normally.
+    pub fn with_command_interceptor(mut self, interceptor: CommandInterceptor) -> Self {
+        self.command_interceptor = Some(interceptor);
+        self
+    }
+
+    /// Script the replies to specific commands
+    ///
+    /// Each command outside of DATA is checked against the first remaining
+    /// entry; when it matches, the entry is consumed and its response is
+    /// sent instead of the default reply. Once the script is exhausted, or
+    /// while the next entry does not match, commands are handled normally.
+    /// The script is shared by all connections, so it can span several.
+    ///
+    /// A command answered with a scripted 4xx or 5xx reply is not executed.
+    /// Any other scripted reply is sent after the command executes normally;
+    /// if executing it fails, the error is sent and the entry is kept.
+    /// Commands answered by the command interceptor are not checked against
+    /// the script.
+    pub fn with_scripted_responses(mut self, script: Vec<(CommandMatcher, SmtpResponse)>) -> Self {
+        self.response_script = Some(Arc::new(Mutex::new(ResponseScript {
+            responses: script.into(),
+            seen: HashMap::new(),
+        })));
+        self
+    }
+
+    /// Observe or replace every response before it is sent
+    ///
+    /// The interceptor receives each response, including the greeting, and
+    /// returns the response to send instead. The session state is unaffected,
+    /// so e.g. a rewritten RCPT reply does not un-accept the recipient.
+    pub fn with_response_interceptor(
+        mut self,
+        interceptor: Box<dyn Fn(&SmtpResponse) -> SmtpResponse + Send + Sync>,
+    ) -> Self {
+        self.response_interceptor = Some(Arc::from(interceptor));
+        self
+    }
+
+    /// Advertise exactly these capabilities in EHLO replies
+    ///
+    /// The list replaces the defaults entirely, including capabilities
+    /// implied by enabled features such as `CHUNKING`.
+    #[cfg(feature = "ehlo")]
+    pub fn with_capabilities(mut self, capabilities: Vec<String>) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// Call `observer` with the session after each command is processed
+    ///
+    /// The observer runs before the reply is sent, so it sees the state the
+    /// reply reflects, e.g. the recipients accepted so far. It is not called
+    /// for lines of message content.
+    pub fn with_session_observer(
+        mut self,
+        observer: Box<dyn Fn(&SmtpSession) + Send + Sync>,
+    ) -> Self {
+        self.session_observer = Some(Arc::from(observer));
+        self
+    }
+
+    /// Call `callback` with each accepted email, before it is sent to the channel
+    ///
+    /// A panic in the callback is logged and does not affect the connection.
+    pub fn with_on_email(mut self, callback: Box<dyn Fn(&Email) + Send + Sync>) -> Self {
+        self.on_email = Some(Arc::from(callback));
+        self
+    }
+
+    /// Check each email at the end of data, before it is accepted
+    ///
+    /// Returning `Err((code, message))` sends that reply instead of `250` and
+    /// discards the email: it is not stored, passed to the `on_email`
+    /// callback or sent to the channel.
+    #[allow(clippy::type_complexity)]
+    pub fn with_data_validator(
+        mut self,
+        validator: Box<dyn Fn(&Email) -> Result<(), (String, String)> + Send + Sync>,
+    ) -> Self {
+        self.data_validator = Some(Arc::from(validator));
+        self
+    }
+
+    /// Send messages rejected during or at the end of data to `sender`
+    ///
+    /// Each [`RejectedEmail`] carries the envelope, the reply sent to the
+    /// client and the content received, so tests of error flows can check
+    /// what was refused. Rejected commands outside of data are not reported.
+    pub fn with_rejection_sender(mut self, sender: mpsc::Sender<RejectedEmail>) -> Self {
+        self.rejection_sender = Some(sender);
+        self
+    }
+
+    /// Decide whether to accept each recipient
+    ///
+    /// The validator runs for every `RCPT` that passes the built-in checks
+    /// and sees the current session, e.g. to decide based on the sender.
+    /// Returning `Err((code, message))` sends that reply and the recipient is
+    /// not added.
+    #[allow(clippy::type_complexity)]
+    pub fn with_rcpt_validator(
+        mut self,
+        validator: Box<dyn Fn(&str, &SmtpSession) -> Result<(), (String, String)> + Send + Sync>,
+    ) -> Self {
+        self.rcpt_validator = Some(Arc::from(validator));
+        self
+    }
+
+    /// Also add accepted emails to the given store
+    ///
+    /// If the store is full and rejects new emails, the end of data is
+    /// answered with `452 Mailbox full` and the email is not delivered.
+    pub fn with_store(mut self, store: MailStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Add a `Message-ID` header to received messages that lack one
+    ///
+    /// The generated ID has the form `<timestamp.connection@hostname>`.
+    /// A `Message-ID` supplied by the client is never replaced.
+    pub fn with_message_id(mut self, enabled: bool) -> Self {
+        self.message_id = enabled;
+        self
+    }
+
+    /// Reject messages whose DATA ends before the blank line ending the headers
+    ///
+    /// By default such messages are accepted with the warning
+    /// "DATA terminated within header block" in [`Email::warnings`]. In
+    /// strict mode they are rejected with `554 Malformed message: no body`.
+    pub fn with_strict_header_block(mut self, strict: bool) -> Self {
+        self.strict_header_block = strict;
+        self
+    }
+
+    /// Require DATA lines to end with CRLF and contain no other CR or LF
+    ///
+    /// In strict mode a line with a bare CR or ending with a bare LF aborts
+    /// the transaction with `500 Invalid line ending`. By default bare LF
+    /// endings are accepted, including on the final `.` as typed in a manual
+    /// telnet session, and bare CRs within a line are removed, since they
+    /// can desynchronize downstream parsers.
+    pub fn with_strict_crlf(mut self, strict: bool) -> Self {
+        self.strict_crlf = strict;
+        self
+    }
+
+    /// Choose how DATA lines longer than the text line limit are handled
+    ///
+    /// By default such lines reject the message with `552 Line too long`.
+    pub fn with_line_overflow_policy(mut self, policy: LineOverflowPolicy) -> Self {
+        self.line_overflow_policy = policy;
+        self
+    }
+
+    /// Attach the command/response transcript to each email
+    ///
+    /// [`Email::transcript`] then holds every client line and server reply
+    /// since the previous message or `RSET`, ending with the final reply.
+    pub fn with_capture_transcript(mut self, enabled: bool) -> Self {
+        self.capture_transcript = enabled;
+        self
+    }
+
+    /// Accept a bare `HELO` or `EHLO` without a domain argument
+    ///
+    /// The client domain is then recorded as the peer IP address in
+    /// brackets, e.g. `[127.0.0.1]`. By default a missing domain is
+    /// answered with `501`.
+    pub fn with_lenient_helo(mut self, lenient: bool) -> Self {
+        self.lenient_helo = lenient;
+        self
+    }
+
+    /// Accept `MAIL FROM:` and `RCPT TO:` addresses without angle brackets
+    ///
+    /// A bare address such as `MAIL FROM:user@example.com` is then accepted,
+    /// still subject to the usual validation, and noted in
+    /// [`Email::warnings`]. By default it is answered with `501`.
+    pub fn with_optional_angle_brackets(mut self, optional: bool) -> Self {
+        self.optional_angle_brackets = optional;
+        self
+    }
+
+    /// Reply to NOOP with `250 <message>` instead of `250 OK`
+    ///
+    /// The text is sent as given, so an enhanced status code such as
+    /// `2.0.0` can be included at its start.
+    pub fn with_noop_message(mut self, message: String) -> Self {
+        self.noop_message = Some(message);
+        self
+    }
+
+    /// Report the message size in the end-of-data reply
+    ///
+    /// The reply becomes `250 OK <n> bytes received`, where `n` counts the
+    /// message content as received, each line with its own ending. Stuffed
+    /// leading dots and the terminating `.` line are not counted.
+    pub fn with_size_in_reply(mut self, enabled: bool) -> Self {
+        self.size_in_reply = enabled;
+        self
+    }
+
+    /// Report a checksum of the received content in the end-of-data reply
+    ///
+    /// `checksum=<hex>` is appended to the reply, e.g.
+    /// `250 OK checksum=cbf43926`, holding the CRC-32 described in
+    /// [`Email::content_crc32`]. Clients can compare it with their own to
+    /// detect corruption on the wire.
+    pub fn with_echo_data_checksum(mut self, enabled: bool) -> Self {
+        self.echo_data_checksum = enabled;
+        self
+    }
+
+    /// Write large DATA content to files in `dir` instead of memory
+    ///
+    /// Once a message grows beyond 1 MiB its content is streamed to a file,
+    /// and the data size limit no longer applies; see
+    /// [`with_max_spool_size`](Self::with_max_spool_size). The email then
+    /// keeps only its headers in memory; read the body with
+    /// [`Email::body_reader`]. Content sent with BDAT is not spooled.
+    pub fn with_spool_dir(mut self, dir: PathBuf) -> Self {
+        self.spool_dir = Some(dir);
+        self
+    }
+
+    /// Limit spooled messages to `max` bytes
+    ///
+    /// Applies in place of the data size limit once a message is spooled,
+    /// and to the `SIZE` declared with `MAIL` when a spool directory is set.
+    /// Larger messages are rejected with `552`. Spooled messages are not
+    /// limited by default.
+    pub fn with_max_spool_size(mut self, max: usize) -> Self {
+        self.max_spool_size = Some(max);
+        self
+    }
+
+    /// Accept at most `max` recipients per transaction
+    ///
+    /// Further `RCPT` commands are answered with `552 Too many recipients`.
+    /// Defaults to [`SmtpLimits::MAX_RECIPIENTS`]; a limit of 0 rejects every
+    /// recipient.
+    pub fn with_max_recipients(mut self, max: usize) -> Self {
+        self.limits.max_recipients = max;
+        self
+    }
+
+    /// Accept at most `max` bytes of content per message
+    ///
+    /// Larger messages are answered with `552 Too much mail data`, and the
+    /// EHLO `SIZE` capability advertises the limit. Defaults to
+    /// [`SmtpLimits::MAX_DATA_SIZE`].
+    ///
+    /// With [`with_spool_dir`](Self::with_spool_dir), messages written to
+    /// the spool are not subject to this limit, but to the one set with
+    /// [`with_max_spool_size`](Self::with_max_spool_size), if any.
+    pub fn with_max_data_size(mut self, max: usize) -> Self {
+        self.limits.max_data_size = max;
+        self
+    }
+
+    /// Get the limits the server applies to each transaction
+    pub fn limits(&self) -> &SmtpLimits {
+        &self.limits
+    }
+
+    /// Stop serving once `max` messages have been accepted
+    ///
+    /// No new connections are accepted after the `max`th email, and the
+    /// blocking `start`, `serve_on` and `serve_until` methods return.
+    /// Connections already in progress finish normally, so a client sending
+    /// several messages on one connection may exceed the limit. Messages are
+    /// counted separately for each call to a serving method, so neither
+    /// earlier runs nor clones of this server count toward the limit.
+    pub fn with_max_messages(mut self, max: usize) -> Self {
+        self.max_messages = Some(max as u64);
+        self
+    }
+
+    /// Lowercase the domain of the sender and recipient addresses
+    ///
+    /// Affects [`Email::from`] and [`Email::to`]. Only the domain is
+    /// case-insensitive per RFC 5321, so the local part is kept as sent.
+    /// By default addresses are stored exactly as received.
+    pub fn with_normalize_addresses(mut self, enabled: bool) -> Self {
+        self.normalize_addresses = enabled;
+        self
+    }
+
+    /// Keep the raw content of each email exactly as received
+    ///
+    /// [`Email::raw`] is then the DATA payload byte for byte, after removing
+    /// dot-stuffing: like every line ending, bare CRs within lines are kept
+    /// rather than removed. This suits DKIM verification, which is sensitive
+    /// to the exact bytes. A `Message-ID` added by
+    /// [`with_message_id`](Self::with_message_id) is the only change.
+    pub fn with_verbatim_data(mut self, enabled: bool) -> Self {
+        self.verbatim_data = enabled;
+        self
+    }
+
+    /// Get a snapshot of the server's activity counters
+    ///
+    /// Counters are shared with clones of this server, so a clone kept
+    /// before starting the server observes its connections.
+    pub fn metrics(&self) -> ServerMetrics {
+        self.metrics.snapshot()
     }
 
     /// Start the server on the specified address (blocking)
@@ -30,13 +768,76 @@ impl SmtpServer {
     pub fn start(&self, addr: &str, email_sender: mpsc::Sender<Email>) -> Result<(), SmtpError> {
         let listener = TcpListener::bind(addr)?;
         println!("SMTP server listening on {addr}");
+        self.serve_sequentially(listener, &email_sender);
+        Ok(())
+    }
 
-        let command_handler = SmtpCommandHandler::new(&self.hostname);
+    /// Start the server on the specified address, sending to a bounded channel (blocking)
+    ///
+    /// Delivery blocks while the channel is full, and the client only gets
+    /// its reply to the end of data once the email is in the channel. A
+    /// consumer that falls behind thus slows down clients, and emails reach
+    /// it strictly in the order they were accepted.
+    pub fn start_with_sync_sender(
+        &self,
+        addr: &str,
+        email_sender: mpsc::SyncSender<Email>,
+    ) -> Result<(), SmtpError> {
+        let listener = TcpListener::bind(addr)?;
+        println!("SMTP server listening on {addr}");
+        self.serve_sequentially(listener, &email_sender);
+        Ok(())
+    }
 
-        for stream in listener.incoming() {
-            match stream {
+    /// Like [`start_with_sync_sender`](Self::start_with_sync_sender), with an
+    /// existing listener (blocking)
+    pub fn start_with_listener_sync_sender(
+        &self,
+        listener: TcpListener,
+        email_sender: mpsc::SyncSender<Email>,
+    ) -> Result<(), SmtpError> {
+        println!(
+            "SMTP server listening on {}",
+            listener.local_addr().map_err(SmtpError::Io)?
+        );
+        self.serve_sequentially(listener, &email_sender);
+        Ok(())
+    }
+
+    /// Start the server with an existing listener (blocking)
+    /// Emails will be sent to the provided channel as they are received
+    pub fn start_with_listener(
+        &self,
+        listener: TcpListener,
+        email_sender: mpsc::Sender<Email>,
+    ) -> Result<(), SmtpError> {
+        println!(
+            "SMTP server listening on {}",
+            listener.local_addr().map_err(SmtpError::Io)?
+        );
+        self.serve_sequentially(listener, &email_sender);
+        Ok(())
+    }
+
+    /// Handle connections from `listener` one at a time
+    fn serve_sequentially(&self, listener: impl Listener, email_sender: &impl EmailSink) {
+        let server = self.for_serve_loop(Vec::new());
+        server.accept_sequentially(listener, email_sender);
+    }
+
+    /// Accept connections from `listener` and handle each before the next
+    fn accept_sequentially(&self, listener: impl Listener, email_sender: &impl EmailSink) {
+        let command_handler = self.command_handler();
+
+        loop {
+            match listener.accept_connection() {
                 Ok(stream) => {
-                    if let Err(e) = self.handle_client(stream, &command_handler, &email_sender) {
+                    if let Err(e) = self.handle_client(
+                        stream,
+                        self.next_connection_id(),
+                        &command_handler,
+                        email_sender,
+                    ) {
                         eprintln!("Error handling client: {e}");
                     }
                 }
@@ -44,32 +845,97 @@ impl SmtpServer {
                     eprintln!("Error accepting connection: {e}");
                 }
             }
+
+            if self.reached_max_messages() {
+                break;
+            }
         }
+    }
 
-        Ok(())
+    /// Build the job that handles `stream` on another thread
+    fn client_job(
+        &self,
+        stream: TcpStream,
+        email_sender: &mpsc::Sender<Email>,
+    ) -> impl FnOnce() + Send + 'static {
+        let server = self.clone();
+        let email_sender = email_sender.clone();
+        let connection_id = self.next_connection_id();
+        move || {
+            let command_handler = server.command_handler();
+            if let Err(e) =
+                server.handle_client(stream, connection_id, &command_handler, &email_sender)
+            {
+                eprintln!("Error handling client: {e}");
+            }
+        }
     }
 
-    /// Start the server with an existing listener (blocking)
+    /// Start the server on a Unix domain socket at `path` (blocking)
     /// Emails will be sent to the provided channel as they are received
-    pub fn start_with_listener(
+    ///
+    /// The socket file is removed when the server stops. The greeting delay
+    /// function is not applied, since Unix socket peers have no address.
+    #[cfg(unix)]
+    pub fn start_unix(
+        &self,
+        path: &Path,
+        email_sender: mpsc::Sender<Email>,
+    ) -> Result<(), SmtpError> {
+        let listener = UnixListener::bind(path)?;
+        let _guard = SocketFileGuard(path.to_path_buf());
+        println!("SMTP server listening on {}", path.display());
+        self.serve_sequentially(listener, &email_sender);
+        Ok(())
+    }
+
+    /// Serve connections from an existing listener on the given executor (blocking)
+    ///
+    /// Each accepted connection is handed to `pool`, so connections can be
+    /// serviced concurrently. Use [`ThreadPerConnection`] for a thread per client.
+    /// Emails from one connection still reach the channel in the order sent.
+    ///
+    /// [`ThreadPerConnection`]: crate::ThreadPerConnection
+    pub fn serve_on(
+        &self,
+        listener: TcpListener,
+        email_sender: mpsc::Sender<Email>,
+        pool: impl Spawn,
+    ) -> Result<(), SmtpError> {
+        let addr = listener.local_addr().map_err(SmtpError::Io)?;
+        self.for_serve_loop(vec![addr]).serve_on_until(
+            listener,
+            email_sender,
+            pool,
+            &AtomicBool::new(false),
+        )
+    }
+
+    /// Like [`serve_on`](Self::serve_on), but return once `stop` is set
+    ///
+    /// The flag is checked after each accepted connection, so the caller must
+    /// connect once after setting it to wake the accept loop. The message
+    /// limit applies to the serving loop of this server, which the caller
+    /// sets up with [`for_serve_loop`](Self::for_serve_loop).
+    pub(crate) fn serve_on_until(
         &self,
         listener: TcpListener,
         email_sender: mpsc::Sender<Email>,
+        pool: impl Spawn,
+        stop: &AtomicBool,
     ) -> Result<(), SmtpError> {
         println!(
             "SMTP server listening on {}",
             listener.local_addr().map_err(SmtpError::Io)?
         );
 
-        let command_handler = SmtpCommandHandler::new(&self.hostname);
-
         for stream in listener.incoming() {
+            if stop.load(Ordering::SeqCst) || self.reached_max_messages() {
+                break;
+            }
+
             match stream {
-                Ok(stream) => {
-                    if let Err(e) = self.handle_client(stream, &command_handler, &email_sender) {
-                        eprintln!("Error handling client: {e}");
-                    }
-                }
+                Ok(stream) => pool.spawn(Box::new(self.client_job(stream, &email_sender))),
                 Err(e) => {
                     eprintln!("Error accepting connection: {e}");
                 }
@@ -79,76 +945,341 @@ impl SmtpServer {
         Ok(())
     }
 
-    /// Handle a client connection
-    fn handle_client(
+    /// Serve connections from an existing listener until a stop signal (blocking)
+    ///
+    /// Each connection is handled on its own thread. The listener is polled
+    /// for new connections, and the loop ends once `stop` receives a value or
+    /// its sender is dropped. Connections still in progress are then allowed
+    /// to finish before this returns.
+    pub fn serve_until(
         &self,
-        mut stream: TcpStream,
-        command_handler: &SmtpCommandHandler,
-        email_sender: &mpsc::Sender<Email>,
+        listener: TcpListener,
+        email_sender: mpsc::Sender<Email>,
+        stop: mpsc::Receiver<()>,
     ) -> Result<(), SmtpError> {
-        let mut session = SmtpSession::new();
-        let mut reader = BufReader::new(stream.try_clone()?);
-
-        // Send greeting
-        self.send_response(&mut stream, &SmtpResponse::greeting())?;
-
-        let mut line_buffer = Vec::new();
-        loop {
-            line_buffer.clear();
-
-            // Read line with UTF-8 safety
-            match reader.read_until(b'\n', &mut line_buffer) {
-                Ok(0) => break, // Connection closed
-                Ok(_) => {
-                    // Handle potential UTF-8 issues gracefully
-                    let line = match String::from_utf8(line_buffer.clone()) {
-                        Ok(s) => s,
-                        Err(_) => {
-                            // Replace invalid UTF-8 sequences with replacement character
-                            String::from_utf8_lossy(&line_buffer).into_owned()
-                        }
-                    };
+        println!(
+            "SMTP server listening on {}",
+            listener.local_addr().map_err(SmtpError::Io)?
+        );
+        listener.set_nonblocking(true)?;
 
-                    let command = line.trim();
-                    if command.is_empty() {
+        // The listener is polled, so there is no accept loop to wake
+        let server = self.for_serve_loop(Vec::new());
+        let mut connections: Vec<thread::JoinHandle<()>> = Vec::new();
+        while !server.reached_max_messages()
+            && let Err(mpsc::TryRecvError::Empty) = stop.try_recv()
+        {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    // Accepted sockets may inherit non-blocking mode
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        eprintln!("Error accepting connection: {e}");
                         continue;
                     }
 
-                    // Handle data mode specially
-                    if session.in_data_mode {
-                        match self.handle_data_line(command, &mut session) {
-                            Ok(Some(response)) => {
-                                self.send_response(&mut stream, &response)?;
-                                if response.code == "250" {
-                                    // Email stored successfully
-                                    if let Ok(email) = session.finish_data_collection() {
-                                        // Errors when there are no listeners.
-                                        // We ignore these errors for now.
-                                        let _ = email_sender.send(email);
-                                    }
-                                    session.reset();
-                                } else {
-                                    // Reset on error
-                                    session.reset();
-                                }
-                            }
-                            Ok(None) => {
-                                // Continue collecting data
-                            }
-                            Err(e) => {
+                    connections.retain(|connection| !connection.is_finished());
+                    connections.push(thread::spawn(server.client_job(stream, &email_sender)));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(STOP_POLL_INTERVAL);
+                }
+                Err(e) => {
+                    eprintln!("Error accepting connection: {e}");
+                }
+            }
+        }
+
+        for connection in connections {
+            let _ = connection.join();
+        }
+        Ok(())
+    }
+
+    /// Start the server on several addresses at once (non-blocking)
+    ///
+    /// Every address is bound before any is served, so a failure to bind
+    /// one leaves none running. Each listener runs its own accept loop with
+    /// a thread per connection, and emails from all of them are sent to
+    /// `email_sender`. The returned handle stops the server when dropped.
+    pub fn start_multi(
+        &self,
+        addrs: &[&str],
+        email_sender: mpsc::Sender<Email>,
+    ) -> Result<MultiServerHandle, SmtpError> {
+        let addrs: Vec<_> = addrs.iter().map(|addr| (None, *addr)).collect();
+        self.bind_multi(&addrs, email_sender)
+    }
+
+    /// Like [`start_multi`](Self::start_multi), with a label for each listener
+    ///
+    /// `listeners` holds `(label, addr)` pairs. Emails carry the label of
+    /// the listener that accepted them in [`Email::listener_label`], e.g. to
+    /// tell the `submission` port from the `smtp` port.
+    pub fn start_multi_labeled(
+        &self,
+        listeners: &[(&str, &str)],
+        email_sender: mpsc::Sender<Email>,
+    ) -> Result<MultiServerHandle, SmtpError> {
+        let addrs: Vec<_> = listeners
+            .iter()
+            .map(|(label, addr)| (Some(*label), *addr))
+            .collect();
+        self.bind_multi(&addrs, email_sender)
+    }
+
+    /// Bind every address, then serve each on its own accept loop thread
+    fn bind_multi(
+        &self,
+        addrs: &[(Option<&str>, &str)],
+        email_sender: mpsc::Sender<Email>,
+    ) -> Result<MultiServerHandle, SmtpError> {
+        let listeners = addrs
+            .iter()
+            .map(|(label, addr)| Ok((*label, TcpListener::bind(addr)?)))
+            .collect::<Result<Vec<_>, SmtpError>>()?;
+        let addrs = listeners
+            .iter()
+            .map(|(_, listener)| listener.local_addr())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // The listeners share one message count, and all are woken at the limit
+        let serving = self.for_serve_loop(addrs.clone());
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads = listeners
+            .into_iter()
+            .map(|(label, listener)| {
+                let mut server = serving.clone();
+                server.listener_label = label.map(str::to_owned);
+                let email_sender = email_sender.clone();
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    if let Err(e) =
+                        server.serve_on_until(listener, email_sender, ThreadPerConnection, &stop)
+                    {
+                        eprintln!("Error running server: {e}");
+                    }
+                })
+            })
+            .collect();
+
+        Ok(MultiServerHandle {
+            addrs,
+            stop,
+            threads,
+        })
+    }
+
+    /// Create a command handler configured for this server
+    fn command_handler(&self) -> SmtpCommandHandler<'_> {
+        let handler = SmtpCommandHandler::new(&self.hostname)
+            .with_lenient_helo(self.lenient_helo)
+            .with_optional_angle_brackets(self.optional_angle_brackets);
+        #[cfg(feature = "ehlo")]
+        let handler = match &self.capabilities {
+            Some(capabilities) => handler.with_capabilities(capabilities),
+            None => handler,
+        };
+        let handler = match &self.rcpt_validator {
+            Some(validator) => handler.with_rcpt_validator(validator.as_ref()),
+            None => handler,
+        };
+        match &self.noop_message {
+            Some(message) => handler.with_noop_message(message),
+            None => handler,
+        }
+    }
+
+    /// Clone this server for a new serving loop, starting its message count
+    /// at zero
+    fn for_serve_loop(&self, wake_addrs: Vec<SocketAddr>) -> Self {
+        let mut server = self.clone();
+        server.serve_loop = Arc::new(ServeLoop {
+            messages: AtomicU64::new(0),
+            wake_addrs,
+        });
+        server
+    }
+
+    /// Check if the configured number of messages has been accepted
+    fn reached_max_messages(&self) -> bool {
+        self.max_messages
+            .is_some_and(|max| self.serve_loop.messages.load(Ordering::SeqCst) >= max)
+    }
+
+    /// Hand out the next connection ID, in accept order starting at 1
+    fn next_connection_id(&self) -> ConnectionId {
+        ConnectionId(self.connection_ids.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// Handle a client connection
+    fn handle_client(
+        &self,
+        mut stream: impl Connection,
+        connection_id: ConnectionId,
+        command_handler: &SmtpCommandHandler,
+        email_sender: &impl EmailSink,
+    ) -> Result<(), SmtpError> {
+        // Correlate everything logged for this connection
+        #[cfg(feature = "tracing")]
+        let _span = {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|| "local".to_string(), |addr| addr.to_string());
+            tracing::info_span!("smtp_conn", peer = %peer, id = connection_id.0).entered()
+        };
+
+        let mut session = SmtpSession::new();
+        session.connection_id = Some(connection_id);
+        session.listener_label = self.listener_label.clone();
+        session.peer_addr = stream.peer_addr();
+        session.line_overflow_policy = self.line_overflow_policy;
+        session.capture_transcript = self.capture_transcript;
+        session.spool_dir = self.spool_dir.clone();
+        session.max_spool_size = self.max_spool_size;
+        session.max_recipients = self.limits.max_recipients;
+        session.max_data_size = self.limits.max_data_size;
+        session.normalize_addresses = self.normalize_addresses;
+        session.verbatim_data = self.verbatim_data;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        self.metrics.connection();
+
+        // Turn away peers rejected by the connection filter
+        if let (Some(filter), Some(peer_addr)) = (&self.connection_filter, stream.peer_addr())
+            && !filter(peer_addr)
+        {
+            let response = SmtpResponse::new("554", "Access denied");
+            return self.send_response(&mut stream, &response, &mut session);
+        }
+
+        // Delay the greeting if configured for this peer
+        if let (Some(delay_fn), Some(peer_addr)) = (&self.greeting_delay_fn, stream.peer_addr()) {
+            let delay = delay_fn(peer_addr);
+            if !delay.is_zero() {
+                thread::sleep(delay);
+            }
+        }
+
+        // Send greeting, closing the connection if it refuses service
+        let greeting = self
+            .greeting_override
+            .clone()
+            .unwrap_or_else(SmtpResponse::greeting);
+        self.send_response(&mut stream, &greeting, &mut session)?;
+        if !greeting.code.starts_with('2') {
+            return Ok(());
+        }
+
+        let mut line_buffer = Vec::new();
+        // Reply to a message rejected mid-DATA, sent once its terminator arrives
+        let mut rejected_data: Option<SmtpResponse> = None;
+        loop {
+            line_buffer.clear();
+
+            // Read one line at a time, as bytes so invalid UTF-8 is handled
+            // below. Pipelined commands that arrive in the same segment stay
+            // buffered in `reader` and are answered in order.
+            match reader.read_until(b'\n', &mut line_buffer) {
+                Ok(0) => {
+                    // Connection closed; a partial message is never delivered
+                    if session.in_data_mode {
+                        eprintln!("Connection closed during DATA, transaction discarded");
+                    }
+                    break;
+                }
+                Ok(count) => {
+                    self.metrics.bytes_received(count);
+
+                    // Handle potential UTF-8 issues gracefully
+                    let line = match String::from_utf8(line_buffer.clone()) {
+                        Ok(s) => s,
+                        Err(_) => {
+                            // Replace invalid UTF-8 sequences with replacement character
+                            String::from_utf8_lossy(&line_buffer).into_owned()
+                        }
+                    };
+
+                    session
+                        .record_transcript(Direction::Client, line.trim_end_matches(['\r', '\n']));
+
+                    let command = line.trim();
+                    if command.is_empty() && !session.in_data_mode {
+                        continue;
+                    }
+
+                    // Skip the rest of a rejected message, so that none of
+                    // its lines are taken as commands
+                    if let Some(response) = &rejected_data {
+                        if strip_line_ending(&line_buffer) == b"." {
+                            self.send_response(&mut stream, response, &mut session)?;
+                            session.reset();
+                            rejected_data = None;
+                        }
+                        continue;
+                    }
+
+                    // Handle data mode specially
+                    if session.in_data_mode {
+                        match self.handle_data_line(&line_buffer, &mut session) {
+                            Ok(Some(response)) => {
+                                let response = if response.code == "250" {
+                                    self.deliver(&mut session, email_sender)
+                                } else {
+                                    response
+                                };
+                                self.send_response(&mut stream, &response, &mut session)?;
+                                session.reset();
+                            }
+                            Ok(None) => {
+                                // Continue collecting data
+                            }
+                            Err(e) => {
                                 let response = SmtpResponse::error(
                                     e.to_response_code(),
                                     &e.to_response_message(),
                                 );
-                                self.send_response(&mut stream, &response)?;
-                                session.reset();
+                                self.report_rejection(&session, &response);
+                                if strip_line_ending(&line_buffer) == b"." {
+                                    self.send_response(&mut stream, &response, &mut session)?;
+                                    session.reset();
+                                } else {
+                                    rejected_data = Some(response);
+                                }
                             }
                         }
                     } else {
-                        // Normal command processing
-                        match command_handler.process_command(command, &mut session) {
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(command, "command received");
+
+                        // Normal command processing, unless intercepted
+                        let intercepted = self
+                            .command_interceptor
+                            .as_ref()
+                            .and_then(|interceptor| interceptor(command, &session));
+                        let result = match intercepted {
+                            Some(response) => Ok(response),
+                            None => self.execute_command(command, command_handler, &mut session),
+                        };
+
+                        #[cfg(feature = "chunking")]
+                        let result = match session.pending_chunk.take() {
+                            Some(chunk) => Ok(self.receive_chunk(
+                                &mut reader,
+                                chunk,
+                                result,
+                                &mut session,
+                                email_sender,
+                            )?),
+                            None => result,
+                        };
+
+                        match result {
                             Ok(response) => {
-                                self.send_response(&mut stream, &response)?;
+                                if response.code.starts_with(['4', '5']) {
+                                    self.metrics.rejected_command();
+                                }
+
+                                self.observe_session(&session);
+                                self.send_response(&mut stream, &response, &mut session)?;
                                 if response.code == "221" {
                                     break; // QUIT command
                                 }
@@ -158,7 +1289,9 @@ impl SmtpServer {
                                     e.to_response_code(),
                                     &e.to_response_message(),
                                 );
-                                self.send_response(&mut stream, &response)?;
+                                self.metrics.rejected_command();
+                                self.observe_session(&session);
+                                self.send_response(&mut stream, &response, &mut session)?;
 
                                 // Don't automatically reset on all 5xx errors
                                 // Let the command handler manage session state
@@ -176,309 +1309,2799 @@ impl SmtpServer {
         Ok(())
     }
 
+    /// Report the message in progress in `session` as rejected with `response`
+    fn report_rejection(&self, session: &SmtpSession, response: &SmtpResponse) {
+        let Some(sender) = &self.rejection_sender else {
+            return;
+        };
+
+        let raw = &session.raw_data;
+        #[cfg(feature = "chunking")]
+        let raw = if raw.is_empty() {
+            &session.chunk_data
+        } else {
+            raw
+        };
+        let _ = sender.send(RejectedEmail {
+            from: session.from.clone(),
+            to: session.to.clone(),
+            code: response.code.clone(),
+            message: response.message.clone(),
+            raw: (!raw.is_empty()).then(|| raw.clone()),
+            connection_id: session.connection_id,
+        });
+    }
+
+    /// Report a complete email as rejected with `response`
+    fn report_rejected_email(&self, email: Email, response: &SmtpResponse) {
+        if let Some(sender) = &self.rejection_sender {
+            let _ = sender.send(RejectedEmail {
+                from: Some(email.from),
+                to: email.to,
+                code: response.code.clone(),
+                message: response.message.clone(),
+                raw: Some(email.raw),
+                connection_id: email.connection_id,
+            });
+        }
+    }
+
+    /// Pass the session to the observer, if one is configured
+    fn observe_session(&self, session: &SmtpSession) {
+        if let Some(observer) = &self.session_observer {
+            observer(session);
+        }
+    }
+
+    /// Execute `command`, unless the script has a reply for it next
+    ///
+    /// A scripted 4xx or 5xx reply is sent instead of executing the command.
+    /// Any other scripted reply is only taken from the script, and sent,
+    /// once the command executes successfully.
+    fn execute_command(
+        &self,
+        command: &str,
+        command_handler: &SmtpCommandHandler,
+        session: &mut SmtpSession,
+    ) -> Result<SmtpResponse, SmtpError> {
+        let Some(script) = &self.response_script else {
+            return command_handler.process_command(command, session);
+        };
+        let verb = command
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        // The lock is released before executing the command, which may run
+        // user callbacks such as the recipient validator
+        let entry = {
+            let mut script = script.lock().unwrap_or_else(|e| e.into_inner());
+            let seen = script.seen.entry(verb.clone()).or_default();
+            let occurrence = *seen;
+            *seen += 1;
+            match script.responses.front() {
+                Some((matcher, _)) if matcher.matches(&verb, occurrence) => {
+                    script.responses.pop_front()
+                }
+                _ => None,
+            }
+        };
+
+        let Some((matcher, scripted)) = entry else {
+            return command_handler.process_command(command, session);
+        };
+        if scripted.is_error() {
+            return Ok(scripted);
+        }
+        if let Err(e) = command_handler.process_command(command, session) {
+            // Keep the reply for the next occurrence that succeeds
+            let mut script = script.lock().unwrap_or_else(|e| e.into_inner());
+            script.responses.push_front((matcher, scripted));
+            return Err(e);
+        }
+        Ok(scripted)
+    }
+
     /// Handle a line of data during DATA mode
+    ///
+    /// `line` is the raw line as read, including its terminator. Since lines
+    /// are accumulated until `\n`, a terminator split across TCP reads still
+    /// arrives here as a single `.<CR><LF>` line following a line boundary.
     fn handle_data_line(
         &self,
-        line: &str,
+        line: &[u8],
         session: &mut SmtpSession,
     ) -> Result<Option<SmtpResponse>, SmtpError> {
-        if line == "." {
-            // End of data
+        if self.strict_crlf {
+            if strip_line_ending(line).contains(&b'\r') {
+                return Err(SmtpError::InvalidLineEnding("bare CR".to_string()));
+            }
+            if line.ends_with(b"\n") && !line.ends_with(b"\r\n") {
+                return Err(SmtpError::InvalidLineEnding("bare LF".to_string()));
+            }
+        }
+
+        if strip_line_ending(line) == b"." {
+            // End of data, possibly before the blank line ending the headers.
+            // An empty message has no header block to end.
+            if !session.is_spooling()
+                && !session.data.is_empty()
+                && !session.data.iter().any(String::is_empty)
+            {
+                if self.strict_header_block {
+                    return Err(SmtpError::MalformedMessage("no body".to_string()));
+                }
+                session
+                    .warnings
+                    .push("DATA terminated within header block".to_string());
+            }
             Ok(Some(SmtpResponse::ok()))
         } else {
-            // Add data line
-            session.add_data_line(line.to_string())?;
+            let content = strip_line_ending(line);
+            let ending = &line[content.len()..];
+
+            // Remove bare CRs, unless keeping the content verbatim
+            let without_cr: Vec<u8>;
+            let content = if content.contains(&b'\r') && !self.verbatim_data {
+                without_cr = content.iter().copied().filter(|&b| b != b'\r').collect();
+                &without_cr[..]
+            } else {
+                content
+            };
+
+            // Remove a stuffed leading dot (RFC 5321 section 4.5.2)
+            let (content, was_dot_stuffed) = match content.strip_prefix(b".") {
+                Some(rest) => (rest, true),
+                None => (content, false),
+            };
+
+            // Add data line exactly as received, keeping the raw bytes for
+            // 8-bit content and any leading or trailing whitespace
+            session.add_data_bytes_with_ending(content, ending)?;
+            if !session.is_spooling() {
+                session.data_lines.push(DataLine {
+                    content: session.data.last().cloned().unwrap_or_default(),
+                    had_crlf: line.ends_with(b"\r\n"),
+                    was_dot_stuffed,
+                });
+            }
             Ok(None)
         }
     }
 
+    /// Read the payload of a BDAT chunk and return the reply to send
+    ///
+    /// `result` is the outcome of the BDAT command itself. When it failed,
+    /// the payload is read and discarded. The final chunk completes the
+    /// transaction just like the end of DATA.
+    #[cfg(feature = "chunking")]
+    fn receive_chunk(
+        &self,
+        reader: &mut impl Read,
+        chunk: PendingChunk,
+        result: Result<SmtpResponse, SmtpError>,
+        session: &mut SmtpSession,
+        email_sender: &impl EmailSink,
+    ) -> Result<SmtpResponse, SmtpError> {
+        let mut payload = reader.take(chunk.size as u64);
+        let result = match result {
+            Ok(response) => {
+                // The buffer grows with the bytes that actually arrive, rather
+                // than trusting the size the client announced
+                let mut data = Vec::new();
+                let count = payload.read_to_end(&mut data)?;
+                self.metrics.bytes_received(count);
+                if count < chunk.size {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                session.add_chunk(&data).map(|()| response)
+            }
+            Err(e) => {
+                // Discard the payload so the next command is read correctly
+                let count = io::copy(&mut payload, &mut io::sink())?;
+                self.metrics.bytes_received(count as usize);
+                Err(e)
+            }
+        };
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                let response = SmtpResponse::error(e.to_response_code(), &e.to_response_message());
+                self.report_rejection(session, &response);
+                session.reset();
+                return Ok(response);
+            }
+        };
+
+        if chunk.last {
+            let response = self.deliver(session, email_sender);
+            session.reset();
+            return Ok(response);
+        }
+
+        Ok(response)
+    }
+
+    /// Finish the transaction and hand the email to the store and channel
+    ///
+    /// Returns the reply for the end of data. The email is sent before the
+    /// reply, which keeps emails from one connection in order.
+    fn deliver(&self, session: &mut SmtpSession, email_sender: &impl EmailSink) -> SmtpResponse {
+        let size = session.data_size;
+        let result = session.finish_data_collection().map(|mut email| {
+            if self.message_id && email.get_header("Message-ID").is_none() {
+                let id = self.generate_message_id(&email);
+                email.prepend_header("Message-ID", &id);
+            }
+            email
+        });
+        let mut email = match result {
+            Ok(email) => email,
+            Err(e) => {
+                let response = SmtpResponse::error(e.to_response_code(), &e.to_response_message());
+                self.report_rejection(session, &response);
+                return response;
+            }
+        };
+
+        // Content rejected by the validator is dropped
+        if let Some(validator) = &self.data_validator
+            && let Err((code, message)) = validator(&email)
+        {
+            let response = SmtpResponse::error(&code, &message);
+            self.report_rejected_email(email, &response);
+            return response;
+        }
+
+        if let Some(store) = &self.store
+            && let Err(e) = store.push(email.clone())
+        {
+            let response = SmtpResponse::error(e.to_response_code(), &e.to_response_message());
+            self.report_rejected_email(email, &response);
+            return response;
+        }
+
+        if let Some(callback) = &self.on_email
+            && panic::catch_unwind(AssertUnwindSafe(|| callback(&email))).is_err()
+        {
+            eprintln!("Email callback panicked");
+        }
+
+        #[cfg(feature = "tracing")]
+        tracing::info!(size, recipients = email.to.len(), "message accepted");
+
+        // The reply is sent after the email, so render it here once
+        let mut message = "OK".to_string();
+        if self.size_in_reply {
+            message.push_str(&format!(" {size} bytes received"));
+        }
+        if self.echo_data_checksum {
+            match email.content_crc32() {
+                Ok(crc) => message.push_str(&format!(" checksum={crc:08x}")),
+                Err(e) => eprintln!("Error computing checksum: {e}"),
+            }
+        }
+        let response = SmtpResponse::new("250", &message);
+        if self.capture_transcript {
+            let reply = self.render_response(&response);
+            email
+                .transcript
+                .push((Direction::Server, reply.trim_end().to_string()));
+            session.rendered_reply = Some(reply);
+        }
+
+        email_sender.send_email(email);
+        self.metrics.message();
+        let messages = self.serve_loop.messages.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.max_messages == Some(messages) {
+            for &addr in &self.serve_loop.wake_addrs {
+                wake_accept_loop(addr);
+            }
+        }
+        response
+    }
+
+    /// Generate a `Message-ID` value for an email received by this server
+    fn generate_message_id(&self, email: &Email) -> String {
+        let nanos = email
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let connection = email.connection_id.map_or(0, |id| id.0);
+        format!("<{nanos}.{connection}@{}>", self.hostname)
+    }
+
     /// Send a response to the client
     fn send_response(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut impl Write,
         response: &SmtpResponse,
+        session: &mut SmtpSession,
     ) -> Result<(), SmtpError> {
+        let formatted = match session.rendered_reply.take() {
+            Some(formatted) => formatted,
+            None => self.render_response(response),
+        };
+        stream.write_all(formatted.as_bytes())?;
+        stream.flush()?;
+
+        #[cfg(feature = "tracing")]
+        tracing::trace!(response = formatted.trim_end(), "response sent");
+
+        session.record_transcript(Direction::Server, formatted.trim_end());
+        Ok(())
+    }
+
+    /// Format a response as it goes on the wire
+    fn render_response(&self, response: &SmtpResponse) -> String {
+        let intercepted;
+        let response = match &self.response_interceptor {
+            Some(interceptor) => {
+                intercepted = interceptor(response);
+                &intercepted
+            }
+            None => response,
+        };
+
         // Ensure response doesn't exceed maximum line length
         let formatted = response.format();
-        if formatted.len() > SmtpLimits::REPLY_LINE_MAX_LENGTH {
-            // Truncate message if too long
-            let truncated_response =
-                SmtpResponse::new(&response.code, "Response too long (truncated)");
-            stream.write_all(truncated_response.format().as_bytes())?;
+        if formatted.len() <= SmtpLimits::REPLY_LINE_MAX_LENGTH {
+            formatted
+        } else if response.multiline.is_none() {
+            // Continue a long message over several lines (RFC 5321 section 4.2.1)
+            response
+                .split_message(SmtpLimits::REPLY_LINE_MAX_LENGTH)
+                .format()
         } else {
-            stream.write_all(formatted.as_bytes())?;
+            // Truncate message if too long
+            SmtpResponse::new(&response.code, "Response too long (truncated)").format()
+        }
+    }
+}
+
+/// Check that `hostname` is a domain or an IPv4 or IPv6 address literal
+fn is_valid_hostname(hostname: &str) -> bool {
+    if let Some(literal) = hostname
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+    {
+        return match literal.strip_prefix("IPv6:") {
+            Some(ipv6) => ipv6.parse::<Ipv6Addr>().is_ok(),
+            None => literal.parse::<Ipv4Addr>().is_ok(),
+        };
+    }
+
+    // sub-domain = Let-dig [Ldh-str], at most 63 octets per label
+    hostname.len() <= 255
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// Connect to a listener bound to `addr` so its blocking accept returns
+///
+/// An unspecified address such as `0.0.0.0` cannot be connected to on every
+/// platform, so the matching loopback address is used instead.
+pub(crate) fn wake_accept_loop(mut addr: SocketAddr) {
+    if addr.ip().is_unspecified() {
+        match addr {
+            SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
         }
-        stream.flush()?;
-        Ok(())
     }
+    let _ = TcpStream::connect(addr);
+}
+
+/// Strip a trailing `<CR><LF>` or bare `<LF>` from a raw line
+fn strip_line_ending(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r\n")
+        .or_else(|| line.strip_suffix(b"\n"))
+        .unwrap_or(line)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::{BufRead, BufReader, Write};
-    use std::net::TcpListener;
-    use std::sync::mpsc;
-    use std::thread;
-    use std::time::Duration;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::executor::{Job, ThreadPerConnection};
+    use crate::smtp::store::Eviction;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Mutex, mpsc};
+    use std::time::Instant;
+
+    fn start_test_server() -> (String, mpsc::Receiver<Email>) {
+        start_test_server_with(SmtpServer::new("test.local"))
+    }
+
+    fn start_test_server_with(server: SmtpServer) -> (String, mpsc::Receiver<Email>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+
+        // Start server in background thread
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let command_handler = server.command_handler();
+                        let connection_id = server.next_connection_id();
+                        if let Err(e) =
+                            server.handle_client(stream, connection_id, &command_handler, &tx)
+                        {
+                            eprintln!("Error handling client: {e}");
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        (addr, rx)
+    }
+
+    fn send_command(stream: &mut TcpStream, command: &str) -> Result<String, std::io::Error> {
+        writeln!(stream, "{command}")?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let mut response = String::new();
+        reader.read_line(&mut response)?;
+        Ok(response.trim().to_string())
+    }
+
+    /// Connect and read the greeting
+    fn read_greeting(addr: &str) -> (String, BufReader<TcpStream>) {
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        (greeting, reader)
+    }
+
+    /// Send EHLO and return each line of the multiline reply
+    #[cfg(feature = "ehlo")]
+    fn send_ehlo(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+        reader
+            .get_mut()
+            .write_all(b"EHLO client.local\r\n")
+            .unwrap();
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                return lines;
+            }
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            lines.push(line.trim_end().to_string());
+            if last {
+                return lines;
+            }
+        }
+    }
+
+    /// Connect and start a transaction for one recipient, up to the DATA
+    /// command
+    fn begin_data(addr: &str) -> BufReader<TcpStream> {
+        let (_, mut reader) = read_greeting(addr);
+        let stream = reader.get_mut();
+        send_command(stream, "HELO client.local").unwrap();
+        send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+        send_command(stream, "RCPT TO:<recipient@example.com>").unwrap();
+        send_command(stream, "DATA").unwrap();
+        reader
+    }
+
+    /// Send `data` as the message content and return the end-of-data reply
+    fn send_data(addr: &str, data: &str) -> (String, TcpStream) {
+        let mut stream = begin_data(addr).into_inner();
+        (send_command(&mut stream, data).unwrap(), stream)
+    }
+
+    /// Send a message with only a subject and return the end-of-data reply
+    fn send_message(addr: &str, subject: &str) -> String {
+        let (response, mut stream) = send_data(addr, &format!("Subject: {subject}\r\n."));
+        send_command(&mut stream, "QUIT").unwrap();
+        response
+    }
+
+    /// Send a message ended by a bare LF terminator, followed by NOOP
+    fn send_lf_terminated(server: SmtpServer) -> (String, Option<Email>) {
+        let (addr, rx) = start_test_server_with(server);
+        let mut reader = begin_data(&addr);
+        reader
+            .get_mut()
+            .write_all(b"Subject: LF\r\n\r\nBody\r\n.\nNOOP\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        let email = rx.recv_timeout(Duration::from_millis(200)).ok();
+
+        // Exactly one reply for the message, then the NOOP reply
+        let mut noop = String::new();
+        reader.read_line(&mut noop).unwrap();
+        assert_eq!(noop, "250 OK\r\n");
+        (response.trim_end().to_string(), email)
+    }
+
+    /// Send a message with a 1500 byte line under `policy`
+    fn send_long_line(policy: LineOverflowPolicy) -> (String, Option<Email>) {
+        let server = SmtpServer::new("test.local").with_line_overflow_policy(policy);
+        let (addr, rx) = start_test_server_with(server);
+        let data = format!("Subject: Long\r\n\r\n{}\r\n.", "a".repeat(1500));
+        let (response, mut stream) = send_data(&addr, &data);
+
+        // Exactly one reply for the message, even when rejected mid-way
+        assert_eq!(send_command(&mut stream, "NOOP").unwrap(), "250 OK");
+        (response, rx.recv_timeout(Duration::from_millis(200)).ok())
+    }
+
+    /// Send `count` RCPT commands to a server allowing `max_recipients`
+    fn rcpt_replies(max_recipients: usize, count: usize) -> Vec<String> {
+        let server = SmtpServer::new("test.local").with_max_recipients(max_recipients);
+        let (addr, _rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+
+        send_command(stream, "HELO client.local").unwrap();
+        send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+        (0..count)
+            .map(|i| send_command(stream, &format!("RCPT TO:<user{i}@example.com>")).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_server_creation() {
+        let server = SmtpServer::new("test.local");
+        assert_eq!(server.hostname, "test.local");
+    }
+
+    #[test]
+    fn test_complete_smtp_session() {
+        let (addr, rx) = start_test_server();
+
+        // Connect to server
+        let mut stream = TcpStream::connect(&addr).unwrap();
+
+        // Read greeting
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        assert!(greeting.starts_with("220"));
+
+        // Send HELO
+        let response = send_command(&mut stream, "HELO client.local").unwrap();
+        assert!(response.starts_with("250"));
+
+        // Send MAIL FROM
+        let response = send_command(&mut stream, "MAIL FROM:<test@example.com>").unwrap();
+        assert!(response.starts_with("250"));
+
+        // Send RCPT TO
+        let response = send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
+        assert!(response.starts_with("250"));
+
+        // Send DATA
+        let response = send_command(&mut stream, "DATA").unwrap();
+        assert!(response.starts_with("354"));
+
+        // Send email content
+        writeln!(stream, "Subject: Test Email").unwrap();
+        writeln!(stream).unwrap();
+        writeln!(stream, "This is a test email.").unwrap();
+        writeln!(stream, ".").unwrap();
+        stream.flush().unwrap();
+
+        // Read final response
+        let mut final_response = String::new();
+        reader.read_line(&mut final_response).unwrap();
+        assert!(final_response.starts_with("250"));
+
+        // Send QUIT
+        let response = send_command(&mut stream, "QUIT").unwrap();
+        assert!(response.starts_with("221"));
+
+        // Wait for email to be processed
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.from, "test@example.com");
+        assert_eq!(email.to, vec!["recipient@example.com"]);
+        assert!(email.data.contains("Subject: Test Email"));
+        assert!(email.data.contains("This is a test email."));
+    }
+
+    #[test]
+    fn test_error_handling() {
+        let (addr, _rx) = start_test_server();
+
+        // Connect to server
+        let mut stream = TcpStream::connect(&addr).unwrap();
+
+        // Read greeting
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        assert!(greeting.starts_with("220"));
+
+        // Send invalid command
+        let response = send_command(&mut stream, "INVALID").unwrap();
+        assert!(response.starts_with("500"));
+
+        // Try MAIL without HELO
+        let response = send_command(&mut stream, "MAIL FROM:<test@example.com>").unwrap();
+        assert!(
+            response.starts_with("503 "),
+            "Expected 503, got: {response}"
+        );
+
+        // Send QUIT
+        let response = send_command(&mut stream, "QUIT").unwrap();
+        assert!(response.starts_with("221"));
+    }
+
+    #[test]
+    fn test_multiple_recipients() {
+        let (addr, rx) = start_test_server();
+
+        // Connect to server
+        let mut stream = TcpStream::connect(&addr).unwrap();
+
+        // Read greeting
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+
+        // Complete SMTP session with multiple recipients
+        send_command(&mut stream, "HELO client.local").unwrap();
+        send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
+        send_command(&mut stream, "RCPT TO:<recipient1@example.com>").unwrap();
+        send_command(&mut stream, "RCPT TO:<recipient2@example.com>").unwrap();
+        send_command(&mut stream, "DATA").unwrap();
+
+        writeln!(stream, "Subject: Multiple Recipients").unwrap();
+        writeln!(stream).unwrap();
+        writeln!(stream, "Test message for multiple recipients").unwrap();
+        writeln!(stream, ".").unwrap();
+        stream.flush().unwrap();
+
+        // Read response
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("250"));
+
+        send_command(&mut stream, "QUIT").unwrap();
+
+        // Wait for email to be processed
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.to.len(), 2);
+        assert!(email.to.contains(&"recipient1@example.com".to_string()));
+        assert!(email.to.contains(&"recipient2@example.com".to_string()));
+    }
+
+    #[test]
+    fn test_rset_command() {
+        let (addr, rx) = start_test_server();
+
+        // Connect to server
+        let mut stream = TcpStream::connect(&addr).unwrap();
+
+        // Read greeting
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+
+        // Start transaction
+        send_command(&mut stream, "HELO client.local").unwrap();
+        send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
+        send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
+
+        // Reset transaction
+        let response = send_command(&mut stream, "RSET").unwrap();
+        assert!(response.starts_with("250"));
+
+        // Should be able to start new transaction
+        send_command(&mut stream, "MAIL FROM:<newsender@example.com>").unwrap();
+        send_command(&mut stream, "RCPT TO:<newrecipient@example.com>").unwrap();
+        send_command(&mut stream, "DATA").unwrap();
+
+        writeln!(stream, "Subject: After Reset").unwrap();
+        writeln!(stream).unwrap();
+        writeln!(stream, "This message came after RSET").unwrap();
+        writeln!(stream, ".").unwrap();
+        stream.flush().unwrap();
+
+        // Read response
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("250"));
+
+        send_command(&mut stream, "QUIT").unwrap();
+
+        // Should only receive the email after RSET
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.from, "newsender@example.com");
+        assert_eq!(email.to, vec!["newrecipient@example.com"]);
+
+        // Should not receive any more emails
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    /// Fixed-size pool that records which worker ran each job
+    struct FixedPool {
+        jobs: Mutex<mpsc::Sender<Job>>,
+        served_by: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FixedPool {
+        fn new(size: usize) -> Self {
+            let (tx, rx) = mpsc::channel::<Job>();
+            let rx = Arc::new(Mutex::new(rx));
+            for i in 0..size {
+                let rx = Arc::clone(&rx);
+                thread::Builder::new()
+                    .name(format!("pool-worker-{i}"))
+                    .spawn(move || {
+                        loop {
+                            let job = rx.lock().unwrap().recv();
+                            match job {
+                                Ok(job) => job(),
+                                Err(_) => break,
+                            }
+                        }
+                    })
+                    .unwrap();
+            }
+
+            Self {
+                jobs: Mutex::new(tx),
+                served_by: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
+
+    impl Spawn for FixedPool {
+        fn spawn(&self, f: Job) {
+            let served_by = Arc::clone(&self.served_by);
+            let job: Job = Box::new(move || {
+                let name = thread::current().name().unwrap_or_default().to_string();
+                served_by.lock().unwrap().push(name);
+                f();
+            });
+            self.jobs.lock().unwrap().send(job).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_single_connection_order_preserved() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            SmtpServer::new("test.local")
+                .serve_on(listener, tx, ThreadPerConnection)
+                .unwrap();
+        });
+
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+
+        send_command(stream, "HELO client.local").unwrap();
+        for i in 0..5 {
+            send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+            send_command(stream, "RCPT TO:<recipient@example.com>").unwrap();
+            send_command(stream, "DATA").unwrap();
+            let response = send_command(stream, &format!("Subject: {i}\r\n.")).unwrap();
+            assert!(response.starts_with("250"));
+        }
+        send_command(stream, "QUIT").unwrap();
+
+        let subjects: Vec<String> = (0..5)
+            .map(|_| {
+                let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+                email.get_subject().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(subjects, ["0", "1", "2", "3", "4"]);
+    }
+
+    #[test]
+    fn test_serve_on_pool() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        let pool = FixedPool::new(2);
+        let served_by = Arc::clone(&pool.served_by);
+
+        thread::spawn(move || {
+            SmtpServer::new("test.local")
+                .serve_on(listener, tx, pool)
+                .unwrap();
+        });
+
+        for i in 0..3 {
+            assert!(send_message(&addr, &format!("Pooled {i}")).starts_with("250"));
+        }
+
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_millis(500)).unwrap();
+        }
+
+        let served_by = served_by.lock().unwrap();
+        assert_eq!(served_by.len(), 3);
+        assert!(
+            served_by
+                .iter()
+                .all(|name| name.starts_with("pool-worker-"))
+        );
+    }
+
+    #[test]
+    fn test_start_multi() {
+        let (tx, rx) = mpsc::channel();
+        let handle = SmtpServer::new("test.local")
+            .start_multi(&["127.0.0.1:0", "[::1]:0"], tx)
+            .unwrap();
+        let [v4, v6] = handle.local_addrs() else {
+            panic!("expected two listeners");
+        };
+        assert!(v4.is_ipv4() && v6.is_ipv6());
+
+        assert!(send_message(&v4.to_string(), "over IPv4").starts_with("250"));
+        assert!(send_message(&v6.to_string(), "over IPv6").starts_with("250"));
+
+        let mut subjects: Vec<String> = (0..2)
+            .map(|_| {
+                let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+                email.get_subject().unwrap().to_string()
+            })
+            .collect();
+        subjects.sort();
+        assert_eq!(subjects, ["over IPv4", "over IPv6"]);
+
+        // Every listener is closed once the handle is dropped
+        let addrs = handle.local_addrs().to_vec();
+        handle.shutdown();
+        for addr in addrs {
+            assert!(TcpStream::connect(addr).is_err(), "{addr}");
+        }
+    }
+
+    #[test]
+    fn test_start_multi_max_messages() {
+        let (tx, rx) = mpsc::channel();
+        let handle = SmtpServer::new("test.local")
+            .with_max_messages(2)
+            .start_multi(&["127.0.0.1:0", "127.0.0.1:0"], tx)
+            .unwrap();
+        let addrs = handle.local_addrs().to_vec();
+
+        // The limit counts messages over both listeners together
+        assert!(send_message(&addrs[0].to_string(), "first").starts_with("250"));
+        assert!(send_message(&addrs[1].to_string(), "second").starts_with("250"));
+
+        // Both listeners close without waiting for the handle to be dropped
+        for addr in &addrs {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while TcpStream::connect(addr).is_ok() {
+                assert!(Instant::now() < deadline, "{addr} still accepting");
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        assert_eq!(rx.try_iter().count(), 2);
+        handle.shutdown();
+    }
+
+    #[test]
+    fn test_start_multi_unspecified_address() {
+        let (tx, _rx) = mpsc::channel();
+        let handle = SmtpServer::new("test.local")
+            .start_multi(&["0.0.0.0:0"], tx)
+            .unwrap();
+        let [addr] = handle.local_addrs() else {
+            panic!("expected one listener");
+        };
+        assert!(addr.ip().is_unspecified());
+
+        // Dropping the handle wakes the accept loop through loopback
+        let port = addr.port();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            drop(handle);
+            let _ = done_tx.send(());
+        });
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+
+    #[test]
+    fn test_start_multi_labeled() {
+        let (tx, rx) = mpsc::channel();
+        let handle = SmtpServer::new("test.local")
+            .start_multi_labeled(
+                &[("smtp", "127.0.0.1:0"), ("submission", "127.0.0.1:0")],
+                tx,
+            )
+            .unwrap();
+        let [smtp, submission] = handle.local_addrs() else {
+            panic!("expected two listeners");
+        };
+
+        assert!(send_message(&submission.to_string(), "submitted").starts_with("250"));
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_subject(), Some("submitted"));
+        assert_eq!(email.listener_label.as_deref(), Some("submission"));
+
+        assert!(send_message(&smtp.to_string(), "relayed").starts_with("250"));
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_subject(), Some("relayed"));
+        assert_eq!(email.listener_label.as_deref(), Some("smtp"));
+    }
+
+    #[test]
+    fn test_data_terminator_split_across_writes() {
+        let (addr, rx) = start_test_server();
+        let mut reader = begin_data(&addr);
+        let stream = reader.get_mut();
+
+        stream
+            .write_all(b"Subject: Split\r\n\r\nBody\r\n.")
+            .unwrap();
+        stream.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(b"\r\n").unwrap();
+
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("250"));
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_subject(), Some("Split"));
+        assert!(email.contains_text("Body"));
+    }
+
+    #[test]
+    fn test_strip_line_ending() {
+        assert_eq!(strip_line_ending(b".\r\n"), b".");
+        assert_eq!(strip_line_ending(b".\n"), b".");
+        assert_eq!(strip_line_ending(b"."), b".");
+        assert_eq!(strip_line_ending(b" . \r\n"), b" . ");
+    }
+
+    #[test]
+    fn test_metrics() {
+        let server = SmtpServer::new("test.local");
+        let (addr, rx) = start_test_server_with(server.clone());
+
+        for i in 0..3 {
+            assert!(send_message(&addr, &format!("Message {i}")).starts_with("250"));
+        }
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        }
+
+        let metrics = server.metrics();
+        assert_eq!(metrics.connections, 3);
+        assert_eq!(metrics.messages, 3);
+        assert!(metrics.bytes_received > 0);
+        assert_eq!(metrics.rejected_commands, 0);
+
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+        assert!(send_command(stream, "XYZZY").unwrap().starts_with("500"));
+        assert!(
+            send_command(stream, "RCPT TO:<a@example.com>")
+                .unwrap()
+                .starts_with("503")
+        );
+        send_command(stream, "QUIT").unwrap();
+
+        assert_eq!(server.metrics().rejected_commands, 2);
+    }
+
+    #[test]
+    fn test_command_interceptor() {
+        let server = SmtpServer::new("test.local").with_command_interceptor(Arc::new(
+            |command, _session| {
+                command
+                    .to_ascii_uppercase()
+                    .starts_with("VRFY")
+                    .then(|| SmtpResponse::new("250", "<user@example.com>"))
+            },
+        ));
+        let (addr, rx) = start_test_server_with(server);
+
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+
+        let response = send_command(stream, "VRFY user").unwrap();
+        assert_eq!(response, "250 <user@example.com>");
+
+        // Everything else passes through to the built-in handler
+        assert!(
+            send_command(stream, "MAIL FROM:<sender@example.com>")
+                .unwrap()
+                .starts_with("503")
+        );
+        send_command(stream, "HELO client.local").unwrap();
+        send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+        send_command(stream, "RCPT TO:<recipient@example.com>").unwrap();
+        send_command(stream, "DATA").unwrap();
+        let response = send_command(stream, "Subject: Intercepted\r\n.").unwrap();
+        assert!(response.starts_with("250"));
+
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_subject(), Some("Intercepted"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_start_unix() {
+        let path = std::env::temp_dir().join(format!("mogimail-{}.sock", std::process::id()));
+        let (tx, rx) = mpsc::channel();
+
+        let server_path = path.clone();
+        thread::spawn(move || {
+            SmtpServer::new("test.local")
+                .start_unix(&server_path, tx)
+                .unwrap();
+        });
+
+        // Wait for the socket file to be bound
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut stream = loop {
+            match UnixStream::connect(&path) {
+                Ok(stream) => break stream,
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("failed to connect: {e}"),
+            }
+        };
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        assert!(greeting.starts_with("220"));
+
+        let mut send = |line: &str| {
+            stream.write_all(format!("{line}\r\n").as_bytes()).unwrap();
+            let mut reply = String::new();
+            reader.read_line(&mut reply).unwrap();
+            reply
+        };
+
+        assert!(send("HELO client.local").starts_with("250"));
+        assert!(send("MAIL FROM:<sender@example.com>").starts_with("250"));
+        assert!(send("RCPT TO:<recipient@example.com>").starts_with("250"));
+        assert!(send("DATA").starts_with("354"));
+        assert!(send("Subject: Unix\r\n.").starts_with("250"));
+        assert!(send("QUIT").starts_with("221"));
+
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_subject(), Some("Unix"));
+        assert!(email.has_recipient("recipient@example.com"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_greeting_delay_skipped_for_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("mogimail-delay-{}.sock", std::process::id()));
+        let (tx, _rx) = mpsc::channel();
+        let called = Arc::new(AtomicBool::new(false));
+
+        let server_path = path.clone();
+        let server_called = Arc::clone(&called);
+        thread::spawn(move || {
+            SmtpServer::new("test.local")
+                .with_greeting_delay_fn(Arc::new(move |_| {
+                    server_called.store(true, Ordering::SeqCst);
+                    Duration::from_secs(5)
+                }))
+                .start_unix(&server_path, tx)
+                .unwrap();
+        });
+
+        // Wait for the socket file to be bound
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let stream = loop {
+            match UnixStream::connect(&path) {
+                Ok(stream) => break stream,
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("failed to connect: {e}"),
+            }
+        };
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+
+        assert!(greeting.starts_with("220"));
+        assert!(!called.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_connection_ids() {
+        let (addr, rx) = start_test_server();
+
+        for i in 0..3 {
+            assert!(send_message(&addr, &format!("Message {i}")).starts_with("250"));
+        }
+
+        let ids: Vec<_> = (0..3)
+            .map(|_| {
+                rx.recv_timeout(Duration::from_secs(1))
+                    .unwrap()
+                    .connection_id
+            })
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                Some(ConnectionId(1)),
+                Some(ConnectionId(2)),
+                Some(ConnectionId(3))
+            ]
+        );
+
+        // A new server starts counting again
+        let (addr, rx) = start_test_server();
+        send_message(&addr, "Fresh");
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.connection_id, Some(ConnectionId(1)));
+    }
+
+    #[test]
+    fn test_message_id_injected_when_absent() {
+        let server = SmtpServer::new("test.local").with_message_id(true);
+        let (addr, rx) = start_test_server_with(server);
+
+        send_message(&addr, "Without");
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        let id = email.get_header("Message-ID").unwrap();
+        assert!(id.starts_with('<') && id.ends_with("@test.local>"), "{id}");
+        assert!(email.data.starts_with("Message-ID: <"));
+        assert!(email.raw.starts_with(b"Message-ID: <"));
+        assert_eq!(email.get_subject(), Some("Without"));
+
+        send_data(&addr, "Message-ID: <client@example.com>\r\n.");
+
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_header("Message-ID"), Some("<client@example.com>"));
+        assert_eq!(email.data.matches("Message-ID").count(), 1);
+    }
+
+    #[test]
+    fn test_message_id_disabled_by_default() {
+        let (addr, rx) = start_test_server();
+        send_message(&addr, "Plain");
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_header("Message-ID"), None);
+    }
+
+    #[test]
+    fn test_unterminated_dot_at_eof() {
+        let (addr, rx) = start_test_server();
+        let mut reader = begin_data(&addr);
+        let stream = reader.get_mut();
+
+        // The final `.` is only delimited by the end of the stream
+        stream.write_all(b"Subject: EOF\r\n\r\nBody\r\n.").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("250"), "{response}");
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_body(), Some("Body"));
+    }
+
+    #[test]
+    fn test_data_lines_metadata() {
+        let (addr, rx) = start_test_server();
+        let mut reader = begin_data(&addr);
+        let stream = reader.get_mut();
+        stream
+            .write_all(b"Subject: Lines\r\n\r\nbare\n..stuffed\r\nlast\r\n.\r\n")
+            .unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("250"));
+
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        let lines: Vec<_> = email
+            .data_lines
+            .iter()
+            .map(|line| (line.content.as_str(), line.had_crlf, line.was_dot_stuffed))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                ("Subject: Lines", true, false),
+                ("", true, false),
+                ("bare", false, false),
+                (".stuffed", true, true),
+                ("last", true, false),
+            ]
+        );
+        assert_eq!(email.get_body(), Some("bare\n.stuffed\nlast"));
+    }
+
+    #[test]
+    fn test_data_line_whitespace_preserved() {
+        let (addr, rx) = start_test_server();
+        let (response, _stream) = send_data(
+            &addr,
+            "Subject: Flowed\r\n\r\nsoft break \r\n  indented\r\nhard break\r\n.",
+        );
+        assert!(response.starts_with("250"));
+
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(
+            email.get_body(),
+            Some("soft break \n  indented\nhard break")
+        );
+        assert_eq!(email.data_lines[2].content, "soft break ");
+        assert_eq!(
+            email.raw,
+            b"Subject: Flowed\r\n\r\nsoft break \r\n  indented\r\nhard break\r\n"
+        );
+        assert_eq!(email.raw_size(), 56);
+    }
+
+    #[test]
+    fn test_connection_closed_during_data() {
+        let (addr, rx) = start_test_server();
+        let mut reader = begin_data(&addr);
+        let stream = reader.get_mut();
+        stream
+            .write_all(b"Subject: Half sent\r\n\r\nThe rest never arri")
+            .unwrap();
+        drop(reader);
+
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        // The server carries on with the next connection
+        send_message(&addr, "Complete");
+        let email = rx.recv_timeout(Duration::from_millis(500)).unwrap();
+        assert_eq!(email.get_subject(), Some("Complete"));
+    }
+
+    #[test]
+    fn test_empty_message() {
+        let server = SmtpServer::new("test.local").with_strict_header_block(true);
+        let (addr, rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+
+        send_command(stream, "HELO client.local").unwrap();
+        let response = send_command(stream, "MAIL FROM:<sender@example.com> SIZE=0").unwrap();
+        assert_eq!(response, "250 OK");
+        send_command(stream, "RCPT TO:<recipient@example.com>").unwrap();
+        send_command(stream, "DATA").unwrap();
+        let response = send_command(stream, ".").unwrap();
+        assert_eq!(response, "250 OK");
+
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.data, "");
+        assert!(email.raw.is_empty());
+        assert!(email.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_only_bare_dot_terminates_data() {
+        let (addr, rx) = start_test_server();
+        let (response, _stream) =
+            send_data(&addr, "Subject: Dots\r\n\r\n . \r\n.\t\r\nlastline.\r\n.");
+        assert!(response.starts_with("250"));
+
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.get_body(), Some(" . \n\t\nlastline."));
+        assert_eq!(email.data_lines[2].content, " . ");
+    }
+
+    #[test]
+    fn test_dot_in_header_block_lenient() {
+        let (addr, rx) = start_test_server();
+
+        let (response, stream) = send_data(&addr, "Subject: Partial\r\nFrom: a@example.com\r\n.");
+        assert!(response.starts_with("250"));
+
+        // The test server handles one connection at a time
+        drop(stream);
+
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_subject(), Some("Partial"));
+        assert_eq!(email.warnings, ["DATA terminated within header block"]);
+
+        let (response, _stream) = send_data(&addr, "Subject: Complete\r\n\r\nBody\r\n.");
+        assert!(response.starts_with("250"));
+
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_body(), Some("Body"));
+        assert!(email.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_dot_in_header_block_strict() {
+        let server = SmtpServer::new("test.local").with_strict_header_block(true);
+        let (addr, rx) = start_test_server_with(server);
+
+        let (response, mut stream) = send_data(&addr, "Subject: Partial\r\n.");
+        assert_eq!(response, "554 Malformed message: no body");
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // The session is usable for another transaction
+        send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
+        send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
+        send_command(&mut stream, "DATA").unwrap();
+        let response = send_command(&mut stream, "Subject: Complete\r\n\r\nBody\r\n.").unwrap();
+        assert!(response.starts_with("250"));
+
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_body(), Some("Body"));
+    }
+
+    #[test]
+    fn test_scripted_responses() {
+        let server = SmtpServer::new("test.local").with_scripted_responses(vec![
+            (CommandMatcher::nth("MAIL", 0), SmtpResponse::ok()),
+            (
+                CommandMatcher::nth("MAIL", 1),
+                SmtpResponse::insufficient_storage(),
+            ),
+        ]);
+        let (addr, _rx) = start_test_server_with(server);
+
+        let (_, mut first) = read_greeting(&addr);
+        let first = first.get_mut();
+        send_command(first, "HELO client.local").unwrap();
+        let response = send_command(first, "MAIL FROM:<sender@example.com>").unwrap();
+        assert_eq!(response, "250 OK");
+        // The scripted 250 still starts the transaction
+        let response = send_command(first, "RCPT TO:<recipient@example.com>").unwrap();
+        assert!(response.starts_with("250"), "{response}");
+        // The test server handles one connection at a time
+        send_command(first, "QUIT").unwrap();
+
+        let (_, mut second) = read_greeting(&addr);
+        let second = second.get_mut();
+        send_command(second, "HELO client.local").unwrap();
+        let response = send_command(second, "MAIL FROM:<sender@example.com>").unwrap();
+        assert!(response.starts_with("452 "), "{response}");
+
+        // The script is exhausted and the rejected MAIL was not executed
+        let response = send_command(second, "MAIL FROM:<sender@example.com>").unwrap();
+        assert_eq!(response, "250 OK");
+    }
+
+    #[test]
+    fn test_scripted_responses_with_interceptor() {
+        let intercepted = Arc::new(AtomicBool::new(false));
+        let server = SmtpServer::new("test.local")
+            .with_command_interceptor(Arc::new(move |command, _session| {
+                // Answer only the first MAIL
+                (command.starts_with("MAIL") && !intercepted.swap(true, Ordering::SeqCst))
+                    .then(|| SmtpResponse::new("250", "Intercepted"))
+            }))
+            .with_scripted_responses(vec![
+                (
+                    CommandMatcher::verb("MAIL"),
+                    SmtpResponse::new("250", "Scripted"),
+                ),
+                (
+                    CommandMatcher::verb("RCPT"),
+                    SmtpResponse::new("250", "Scripted"),
+                ),
+            ]);
+        let (addr, _rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+        send_command(stream, "HELO client.local").unwrap();
+
+        // The interceptor answers first, leaving the script untouched
+        let response = send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+        assert_eq!(response, "250 Intercepted");
+
+        let response = send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+        assert_eq!(response, "250 Scripted");
+
+        // A failing command does not use up the scripted reply
+        let response = send_command(stream, "RCPT TO:recipient").unwrap();
+        assert!(response.starts_with("501"), "{response}");
+        let response = send_command(stream, "RCPT TO:<recipient@example.com>").unwrap();
+        assert_eq!(response, "250 Scripted");
+    }
+
+    #[test]
+    fn test_command_matcher() {
+        assert!(CommandMatcher::verb("mail").matches("MAIL", 3));
+        assert!(CommandMatcher::nth("RCPT", 1).matches("rcpt", 1));
+        assert!(!CommandMatcher::nth("RCPT", 1).matches("RCPT", 0));
+        assert!(!CommandMatcher::verb("MAIL").matches("RCPT", 0));
+    }
+
+    #[test]
+    fn test_response_interceptor() {
+        let count = Arc::new(Mutex::new(0));
+        let seen = Arc::clone(&count);
+        let server =
+            SmtpServer::new("test.local").with_response_interceptor(Box::new(move |response| {
+                let mut count = seen.lock().unwrap();
+                if response.code == "250" {
+                    *count += 1;
+                    // HELO and MAIL come first, so the third 250 answers RCPT
+                    if *count == 3 {
+                        return SmtpResponse::new("451", "Injected failure");
+                    }
+                }
+                response.clone()
+            }));
+        let (addr, _rx) = start_test_server_with(server);
+
+        let (greeting, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+        assert!(greeting.starts_with("220"));
+
+        assert!(
+            send_command(stream, "HELO client.local")
+                .unwrap()
+                .starts_with("250")
+        );
+        assert!(
+            send_command(stream, "MAIL FROM:<sender@example.com>")
+                .unwrap()
+                .starts_with("250")
+        );
+        assert_eq!(
+            send_command(stream, "RCPT TO:<one@example.com>").unwrap(),
+            "451 Injected failure"
+        );
+        assert!(
+            send_command(stream, "RCPT TO:<two@example.com>")
+                .unwrap()
+                .starts_with("250")
+        );
+    }
+
+    #[test]
+    fn test_bare_cr_stripped_by_default() {
+        let (addr, rx) = start_test_server();
+        let (response, _stream) = send_data(&addr, "Subject: CR\r\n\r\nfoo\rbar\r\n.");
+        assert!(response.starts_with("250"), "{response}");
+
+        let email = rx.recv_timeout(Duration::from_millis(200)).unwrap();
+        assert_eq!(email.get_body(), Some("foobar"));
+    }
+
+    #[test]
+    fn test_bare_cr_rejected_in_strict_mode() {
+        let server = SmtpServer::new("test.local").with_strict_crlf(true);
+        let (addr, rx) = start_test_server_with(server);
+        let (response, mut stream) = send_data(&addr, "Subject: CR\r\n\r\nfoo\rbar\r\nQUIT\r\n.");
+        assert_eq!(response, "500 Invalid line ending: bare CR");
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        // The rest of the message was discarded, not run as commands
+        assert_eq!(send_command(&mut stream, "NOOP").unwrap(), "250 OK");
+    }
+
+    /// Send a message whose `.` terminator ends with a bare LF
+    #[test]
+    fn test_lf_terminator_accepted_by_default() {
+        // Intended, so that DATA can be ended by hand over telnet
+        let (response, email) = send_lf_terminated(SmtpServer::new("test.local"));
+        assert!(response.starts_with("250"), "{response}");
+        assert_eq!(email.unwrap().get_body(), Some("Body"));
+    }
+
+    #[test]
+    fn test_lf_terminator_rejected_in_strict_mode() {
+        let server = SmtpServer::new("test.local").with_strict_crlf(true);
+        let (response, email) = send_lf_terminated(server);
+        assert_eq!(response, "500 Invalid line ending: bare LF");
+        assert!(email.is_none());
+    }
+
+    #[test]
+    fn test_line_overflow_reject() {
+        let (response, email) = send_long_line(LineOverflowPolicy::Reject);
+        assert!(response.starts_with("552 Line too long"), "{response}");
+        assert!(email.is_none());
+    }
+
+    #[test]
+    fn test_try_new_hostname() {
+        for hostname in ["mail.example.com", "localhost", "[127.0.0.1]", "[IPv6:::1]"] {
+            assert!(SmtpServer::try_new(hostname).is_ok(), "{hostname}");
+        }
+        for hostname in [
+            "bad host name",
+            "",
+            "example..com",
+            "-bad.example",
+            "[300.0.0.1]",
+        ] {
+            assert!(
+                matches!(SmtpServer::try_new(hostname), Err(InvalidHostname(_))),
+                "{hostname}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_command_line_overflow() {
+        let (addr, _rx) = start_test_server();
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+
+        let long_command = "HELO ".to_string() + &"a".repeat(SmtpLimits::COMMAND_LINE_MAX_LENGTH);
+        let response = send_command(stream, &long_command).unwrap();
+        assert!(
+            response.starts_with("500 Command line too long"),
+            "{response}"
+        );
+
+        send_command(stream, "QUIT").unwrap();
+    }
+
+    #[test]
+    fn test_line_overflow_truncate() {
+        let (response, email) = send_long_line(LineOverflowPolicy::Truncate);
+        assert!(response.starts_with("250"), "{response}");
+        let email = email.unwrap();
+        let body = email.get_body().unwrap().to_string();
+        assert_eq!(body, "a".repeat(SmtpLimits::TEXT_LINE_MAX_LENGTH - 2));
+        // The stored line is exactly the limit, CRLF included
+        let line = email
+            .raw
+            .split_inclusive(|&b| b == b'\n')
+            .next_back()
+            .unwrap();
+        assert_eq!(line.len(), SmtpLimits::TEXT_LINE_MAX_LENGTH);
+    }
+
+    #[test]
+    fn test_line_overflow_accept() {
+        let (response, email) = send_long_line(LineOverflowPolicy::Accept);
+        assert!(response.starts_with("250"), "{response}");
+        assert_eq!(email.unwrap().get_body(), Some("a".repeat(1500).as_str()));
+    }
+
+    #[test]
+    fn test_max_data_size() {
+        let server = SmtpServer::new("test.local").with_max_data_size(100);
+        assert_eq!(server.limits().max_data_size, 100);
+        assert_eq!(server.limits().max_recipients, SmtpLimits::MAX_RECIPIENTS);
+        let (addr, _rx) = start_test_server_with(server);
+
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+        send_command(stream, "HELO client.local").unwrap();
+        let response = send_command(stream, "MAIL FROM:<sender@example.com> SIZE=101").unwrap();
+        assert!(response.starts_with("552"), "{response}");
+        send_command(stream, "QUIT").unwrap();
+
+        let data = format!("Subject: Big\r\n\r\n{}\r\n.", "a".repeat(100));
+        let (response, _stream) = send_data(&addr, &data);
+        assert_eq!(response, "552 Too much mail data (max 100 bytes)");
+    }
+
+    #[cfg(feature = "ehlo")]
+    #[test]
+    fn test_max_data_size_advertised() {
+        let server = SmtpServer::new("test.local").with_max_data_size(2048);
+        let (addr, _rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+
+        let lines = send_ehlo(&mut reader);
+        assert!(
+            lines.iter().any(|line| line == "250-SIZE 2048"),
+            "{lines:?}"
+        );
+    }
+
+    #[cfg(feature = "ehlo")]
+    #[test]
+    fn test_custom_capabilities() {
+        let server = SmtpServer::new("test.local")
+            .with_capabilities(vec!["8BITMIME".to_string(), "DSN".to_string()]);
+        let (addr, _rx) = start_test_server_with(server);
+
+        let (_, mut reader) = read_greeting(&addr);
+
+        let lines = send_ehlo(&mut reader);
+        assert_eq!(
+            lines,
+            [
+                "250-test.local Hello client.local",
+                "250-8BITMIME",
+                "250 DSN",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_on_email_callback() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        let server = SmtpServer::new("test.local").with_on_email(Box::new(move |email| {
+            sink.lock()
+                .unwrap()
+                .push(email.get_subject().unwrap_or_default().to_string());
+        }));
+        let (addr, rx) = start_test_server_with(server);
+
+        assert!(send_message(&addr, "Callback").starts_with("250"));
+        assert_eq!(*seen.lock().unwrap(), ["Callback"]);
+
+        // The channel still receives the email
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_subject(), Some("Callback"));
+    }
+
+    #[test]
+    fn test_data_validator_rejects() {
+        let server =
+            SmtpServer::new("test.local").with_data_validator(Box::new(|email| {
+                match email.get_body() {
+                    Some(body) if body.contains("BANNED") => {
+                        Err(("554".to_string(), "Content rejected".to_string()))
+                    }
+                    _ => Ok(()),
+                }
+            }));
+        let (addr, rx) = start_test_server_with(server);
+
+        let (response, mut stream) = send_data(&addr, "Subject: Spam\r\n\r\nBANNED words\r\n.");
+        assert_eq!(response, "554 Content rejected");
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // The session is usable for the next message
+        send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
+        send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
+        send_command(&mut stream, "DATA").unwrap();
+        let response = send_command(&mut stream, "Subject: Ham\r\n\r\nHello\r\n.").unwrap();
+        assert_eq!(response, "250 OK");
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.get_header("Subject"), Some("Ham"));
+    }
+
+    #[test]
+    fn test_rejection_sender() {
+        let (rejection_tx, rejection_rx) = mpsc::channel();
+        let server = SmtpServer::new("test.local")
+            .with_data_validator(Box::new(|_| {
+                Err(("554".to_string(), "Content rejected".to_string()))
+            }))
+            .with_rejection_sender(rejection_tx);
+        let (addr, rx) = start_test_server_with(server);
+
+        let (response, _stream) = send_data(&addr, "Subject: Spam\r\n\r\nBANNED words\r\n.");
+        assert_eq!(response, "554 Content rejected");
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        let rejected = rejection_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(rejected.from.as_deref(), Some("sender@example.com"));
+        assert_eq!(rejected.to, vec!["recipient@example.com"]);
+        assert_eq!(rejected.code, "554");
+        assert_eq!(rejected.message, "Content rejected");
+        assert_eq!(
+            rejected.raw.as_deref(),
+            Some(b"Subject: Spam\r\n\r\nBANNED words\r\n".as_slice())
+        );
+    }
+
+    #[test]
+    fn test_rejection_sender_line_too_long() {
+        let (rejection_tx, rejection_rx) = mpsc::channel();
+        let server = SmtpServer::new("test.local").with_rejection_sender(rejection_tx);
+        let (addr, _rx) = start_test_server_with(server);
+
+        let data = format!("Subject: Long\r\n\r\n{}\r\n.", "a".repeat(1500));
+        let (response, _stream) = send_data(&addr, &data);
+        assert!(response.starts_with("552"), "{response}");
+
+        // The content received up to the offending line is reported
+        let rejected = rejection_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(rejected.code, "552");
+        assert_eq!(
+            rejected.raw.as_deref(),
+            Some(b"Subject: Long\r\n\r\n".as_slice())
+        );
+    }
+
+    #[test]
+    fn test_session_observer() {
+        let counts = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&counts);
+        let server =
+            SmtpServer::new("test.local").with_session_observer(Box::new(move |session| {
+                observed.lock().unwrap().push(session.recipient_count())
+            }));
+        let (addr, _rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+
+        send_command(stream, "HELO client.local").unwrap();
+        send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+        send_command(stream, "RCPT TO:<a@example.com>").unwrap();
+        send_command(stream, "RCPT TO:<b@example.com>").unwrap();
+
+        assert_eq!(*counts.lock().unwrap(), vec![0, 0, 1, 2]);
+        send_command(stream, "QUIT").unwrap();
+    }
+
+    #[test]
+    fn test_on_email_callback_panic() {
+        let server =
+            SmtpServer::new("test.local").with_on_email(Box::new(|_| panic!("callback failure")));
+        let (addr, rx) = start_test_server_with(server);
+
+        assert!(send_message(&addr, "First").starts_with("250"));
+        assert!(send_message(&addr, "Second").starts_with("250"));
+        assert_eq!(rx.iter().take(2).count(), 2);
+    }
+
+    #[test]
+    fn test_connection_filter_allows_loopback() {
+        let server = SmtpServer::new("test.local")
+            .with_connection_filter(Box::new(|peer| peer.ip().is_loopback()));
+        let (addr, _rx) = start_test_server_with(server);
+
+        let (greeting, _reader) = read_greeting(&addr);
+        assert!(greeting.starts_with("220"), "{greeting}");
+    }
+
+    #[test]
+    fn test_greeting_override_refuses_service() {
+        let greeting = SmtpResponse::new("554", "No SMTP service here");
+        let server = SmtpServer::new("test.local").with_greeting_override(greeting);
+        let (addr, _rx) = start_test_server_with(server);
+
+        let (greeting, mut reader) = read_greeting(&addr);
+        assert_eq!(greeting.trim_end(), "554 No SMTP service here");
+
+        // The server closes the connection
+        let mut rest = String::new();
+        assert_eq!(reader.read_line(&mut rest).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_greeting_override_accepts_commands() {
+        let greeting = SmtpResponse::new("220", "mx.example.com ESMTP ready");
+        let server = SmtpServer::new("test.local").with_greeting_override(greeting);
+        let (addr, _rx) = start_test_server_with(server);
+
+        let (greeting, mut reader) = read_greeting(&addr);
+        assert_eq!(greeting.trim_end(), "220 mx.example.com ESMTP ready");
+        let response = send_command(reader.get_mut(), "HELO client.local").unwrap();
+        assert!(response.starts_with("250"), "{response}");
+    }
+
+    #[test]
+    fn test_connection_filter_rejects() {
+        let server = SmtpServer::new("test.local").with_connection_filter(Box::new(|_| false));
+        let (addr, _rx) = start_test_server_with(server);
+
+        let (greeting, mut reader) = read_greeting(&addr);
+        assert_eq!(greeting.trim_end(), "554 Access denied");
+
+        // The server closes the connection
+        let mut rest = String::new();
+        assert_eq!(reader.read_line(&mut rest).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_store_reject_new() {
+        let store = MailStore::with_capacity(1, Eviction::RejectNew);
+        let (addr, rx) =
+            start_test_server_with(SmtpServer::new("test.local").with_store(store.clone()));
+
+        assert!(send_message(&addr, "first").starts_with("250"));
+        assert_eq!(send_message(&addr, "second"), "452 Mailbox full");
+
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.emails()[0].get_subject(), Some("first"));
+
+        // The rejected email is not delivered to the channel either
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_ok());
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn test_store_drop_oldest() {
+        let store = MailStore::with_capacity(1, Eviction::DropOldest);
+        let (addr, _rx) =
+            start_test_server_with(SmtpServer::new("test.local").with_store(store.clone()));
+
+        assert!(send_message(&addr, "first").starts_with("250"));
+        assert!(send_message(&addr, "second").starts_with("250"));
+
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.emails()[0].get_subject(), Some("second"));
+    }
+
+    #[test]
+    fn test_pipelined_rcpt_batch() {
+        let (addr, _rx) = start_test_server();
+
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+
+        send_command(stream, "HELO client.local").unwrap();
+        send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+
+        // Three RCPTs and DATA in a single write
+        stream
+            .write_all(
+                b"RCPT TO:<one@example.com>\r\n\
+                  RCPT TO:<two@example.com>\r\n\
+                  RCPT TO:<three@example.com>\r\n\
+                  DATA\r\n",
+            )
+            .unwrap();
+        stream.flush().unwrap();
+
+        let codes: Vec<String> = (0..4)
+            .map(|_| {
+                let mut response = String::new();
+                reader.read_line(&mut response).unwrap();
+                response[..3].to_string()
+            })
+            .collect();
+        assert_eq!(codes, ["250", "250", "250", "354"]);
+    }
+
+    #[test]
+    fn test_pipelined_commands_single_write() {
+        let (addr, _rx) = start_test_server();
+
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+
+        stream
+            .write_all(b"HELO x\r\nMAIL FROM:<a@b>\r\nRCPT TO:<c@d>\r\n")
+            .unwrap();
+        stream.flush().unwrap();
+
+        let responses: Vec<String> = (0..3)
+            .map(|_| {
+                let mut response = String::new();
+                reader.read_line(&mut response).unwrap();
+                response.trim_end().to_string()
+            })
+            .collect();
+        assert_eq!(responses[0], "250 test.local Hello x");
+        assert_eq!(responses[1], "250 OK");
+        assert_eq!(responses[2], "250 OK");
+    }
+
+    #[test]
+    fn test_8bitmime_latin1_body() {
+        let (addr, rx) = start_test_server();
+
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+
+        send_command(stream, "HELO client.local").unwrap();
+        let response = send_command(stream, "MAIL FROM:<a@b> BODY=8BITMIME").unwrap();
+        assert!(response.starts_with("250"));
+        send_command(stream, "RCPT TO:<recipient@example.com>").unwrap();
+        send_command(stream, "DATA").unwrap();
+
+        // "Café" encoded as Latin-1
+        stream.write_all(b"Subject: Caf\xe9\r\n").unwrap();
+        stream.write_all(b"Body: \xe0 la carte\r\n").unwrap();
+        stream.write_all(b".\r\n").unwrap();
+        stream.flush().unwrap();
+
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("250"));
+
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.raw, b"Subject: Caf\xe9\r\nBody: \xe0 la carte\r\n");
+        assert!(email.data.starts_with("Subject: Caf\u{FFFD}"));
+    }
+
+    #[cfg(feature = "smtputf8")]
+    #[test]
+    fn test_smtputf8_address() {
+        let (addr, rx) = start_test_server();
+
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+        let mut send = |command: &str| send_command(stream, command).unwrap();
+
+        assert!(send("HELO client.local").starts_with("250"));
+        assert!(send("MAIL FROM:<用户@例子.中国> SMTPUTF8").starts_with("250"));
+        assert!(send("RCPT TO:<tëst@exämple.com>").starts_with("250"));
+        assert!(send("DATA").starts_with("354"));
+        assert!(send("Subject: UTF-8\r\n.").starts_with("250"));
+
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.from, "用户@例子.中国");
+        assert_eq!(email.to, vec!["tëst@exämple.com"]);
+        assert!(email.smtputf8);
+    }
+
+    #[cfg(feature = "ehlo")]
+    #[test]
+    fn test_ehlo_command() {
+        let (addr, rx) = start_test_server();
+
+        // Connect to server
+        let mut stream = TcpStream::connect(&addr).unwrap();
+
+        // Read greeting
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        assert!(greeting.starts_with("220"));
+
+        // Send EHLO
+        let response = send_command(&mut stream, "EHLO client.local").unwrap();
+        assert!(response.starts_with("250"));
+
+        // Send MAIL FROM
+        let response = send_command(&mut stream, "MAIL FROM:<test@example.com>").unwrap();
+        assert!(response.starts_with("250"));
+
+        // Send RCPT TO
+        let response = send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
+        assert!(response.starts_with("250"));
+
+        // Send DATA
+        let response = send_command(&mut stream, "DATA").unwrap();
+        assert!(response.starts_with("354"));
+
+        // Send email content
+        writeln!(stream, "Subject: EHLO Test Email").unwrap();
+        writeln!(stream).unwrap();
+        writeln!(stream, "This is a test.").unwrap();
+        writeln!(stream, ".").unwrap();
+        stream.flush().unwrap();
+
+        // Read final response
+        let mut final_response = String::new();
+        reader.read_line(&mut final_response).unwrap();
+        assert!(final_response.starts_with("250"));
+
+        // Send QUIT
+        let response = send_command(&mut stream, "QUIT").unwrap();
+        assert!(response.starts_with("221"));
+
+        // Wait for email to be processed
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.from, "test@example.com");
+        assert_eq!(email.to, vec!["recipient@example.com"]);
+        assert!(email.data.contains("Subject: EHLO Test Email"));
+        assert!(email.data.contains("This is a test."));
+    }
+
+    #[test]
+    fn test_greeting_delay_banner_timeout() {
+        let server = SmtpServer::new("test.local").with_greeting_delay(Duration::from_millis(300));
+        let (addr, _rx) = start_test_server_with(server);
+
+        let stream = TcpStream::connect(&addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut greeting = String::new();
+        let err = reader.read_line(&mut greeting).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+
+        // The greeting still arrives after the delay
+        reader.get_ref().set_read_timeout(None).unwrap();
+        reader.read_line(&mut greeting).unwrap();
+        assert!(greeting.starts_with("220"));
+    }
+
+    #[test]
+    fn test_greeting_delay_per_peer() {
+        let server =
+            SmtpServer::new("test.local").with_greeting_delay_fn(Arc::new(|peer: SocketAddr| {
+                if peer.ip().is_loopback() {
+                    Duration::from_millis(300)
+                } else {
+                    Duration::ZERO
+                }
+            }));
+        let (addr, _rx) = start_test_server_with(server);
+
+        let started = Instant::now();
+        let (greeting, _reader) = read_greeting(&addr);
+
+        assert!(greeting.starts_with("220"));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_greeting_delay_skipped_for_other_peers() {
+        let server =
+            SmtpServer::new("test.local").with_greeting_delay_fn(Arc::new(|peer: SocketAddr| {
+                if peer.ip().is_loopback() {
+                    Duration::ZERO
+                } else {
+                    Duration::from_secs(5)
+                }
+            }));
+        let (addr, _rx) = start_test_server_with(server);
+
+        let started = Instant::now();
+        let (greeting, _reader) = read_greeting(&addr);
+
+        assert!(greeting.starts_with("220"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(feature = "chunking")]
+    #[test]
+    fn test_bdat_chunks() {
+        let (addr, rx) = start_test_server();
+
+        let (_, mut reader) = read_greeting(&addr);
+
+        let capabilities = send_ehlo(&mut reader);
+        assert!(capabilities.iter().any(|line| line.contains("CHUNKING")));
+
+        let mut send = |command: &str| {
+            reader.get_mut().write_all(command.as_bytes()).unwrap();
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            response
+        };
+
+        assert!(send("MAIL FROM:<sender@example.com>\r\n").starts_with("250"));
+        assert!(send("RCPT TO:<recipient@example.com>\r\n").starts_with("250"));
+
+        let first = "Subject: Chunked\r\n\r\nHello, ";
+        let second = "world!\r\n";
+
+        let response = send(&format!("BDAT {}\r\n{first}", first.len()));
+        assert!(response.starts_with("250"));
+
+        // DATA cannot be mixed with BDAT in one transaction
+        assert!(send("DATA\r\n").starts_with("503"));
+
+        let response = send(&format!("BDAT {} LAST\r\n{second}", second.len()));
+        assert!(response.starts_with("250"));
+
+        assert!(send("QUIT\r\n").starts_with("221"));
+
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.from, "sender@example.com");
+        assert_eq!(email.data, "Subject: Chunked\n\nHello, world!");
+        assert_eq!(email.get_body(), Some("Hello, world!"));
+    }
+
+    #[cfg(feature = "chunking")]
+    #[test]
+    fn test_bdat_huge_size_after_first_chunk() {
+        let (addr, rx) = start_test_server();
+        let (_, mut reader) = read_greeting(&addr);
+        let mut stream = reader.get_ref().try_clone().unwrap();
+
+        let mut send = |command: &str| {
+            write!(stream, "{command}").unwrap();
+            stream.flush().unwrap();
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            response
+        };
+
+        assert!(send("HELO client.local\r\n").starts_with("250"));
+        assert!(send("MAIL FROM:<sender@example.com>\r\n").starts_with("250"));
+        assert!(send("RCPT TO:<recipient@example.com>\r\n").starts_with("250"));
+        assert!(send("BDAT 5\r\nHello").starts_with("250"));
+
+        // The size would overflow once added to the first chunk
+        write!(stream, "BDAT {} LAST\r\nrest", usize::MAX).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("552"), "{response}");
+
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[cfg(feature = "chunking")]
+    #[test]
+    fn test_bdat_huge_size_within_limit() {
+        let server = SmtpServer::new("test.local").with_max_data_size(usize::MAX);
+        let (addr, rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+
+        send_command(stream, "HELO client.local").unwrap();
+        send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+        send_command(stream, "RCPT TO:<recipient@example.com>").unwrap();
+
+        // Nothing is allocated for the announced size up front
+        write!(stream, "BDAT {} LAST\r\nshort", usize::MAX / 4).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert!(rest.is_empty(), "{rest}");
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // The server carries on with the next connection
+        assert!(send_message(&addr, "After").starts_with("250"));
+    }
+
+    #[test]
+    fn test_capture_transcript() {
+        let server = SmtpServer::new("test.local").with_capture_transcript(true);
+        let (addr, rx) = start_test_server_with(server);
+
+        let (response, mut stream) = send_data(&addr, "Subject: Transcript\r\n\r\nBody\r\n.");
+        assert!(response.starts_with("250"));
+
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        let client: Vec<_> = email
+            .transcript
+            .iter()
+            .filter(|(direction, _)| *direction == Direction::Client)
+            .map(|(_, line)| line.as_str())
+            .collect();
+        assert_eq!(
+            client[..4],
+            [
+                "HELO client.local",
+                "MAIL FROM:<sender@example.com>",
+                "RCPT TO:<recipient@example.com>",
+                "DATA"
+            ]
+        );
+        assert_eq!(client.last(), Some(&"."));
+        assert_eq!(email.transcript[0].0, Direction::Server);
+        assert!(email.transcript[0].1.starts_with("220"));
+        let (direction, reply) = email.transcript.last().unwrap();
+        assert_eq!(*direction, Direction::Server);
+        assert_eq!(reply, "250 OK");
+
+        // The next transaction starts a fresh transcript
+        send_command(&mut stream, "MAIL FROM:<a@example.com>").unwrap();
+        send_command(&mut stream, "RSET").unwrap();
+        send_command(&mut stream, "MAIL FROM:<b@example.com>").unwrap();
+        send_command(&mut stream, "RCPT TO:<c@example.com>").unwrap();
+        send_command(&mut stream, "DATA").unwrap();
+        send_command(&mut stream, "Subject: Second\r\n\r\nBody\r\n.").unwrap();
+
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert!(
+            email
+                .transcript
+                .iter()
+                .all(|(_, line)| !line.contains("a@example.com"))
+        );
+        assert_eq!(email.transcript[1].1, "MAIL FROM:<b@example.com>");
+    }
+
+    #[test]
+    fn test_transcript_records_reply_sent() {
+        let count = AtomicU64::new(0);
+        let server = SmtpServer::new("test.local")
+            .with_capture_transcript(true)
+            .with_response_interceptor(Box::new(move |response| {
+                // Number every reply, so that each rendering differs
+                let n = count.fetch_add(1, Ordering::SeqCst);
+                SmtpResponse::new(&response.code, &format!("{} #{n}", response.message))
+            }));
+        let (addr, rx) = start_test_server_with(server);
+
+        let (response, _stream) = send_data(&addr, "Subject: Transcript\r\n\r\nBody\r\n.");
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        let (direction, reply) = email.transcript.last().unwrap();
+        assert_eq!(*direction, Direction::Server);
+        assert_eq!(*reply, response);
+    }
+
+    #[test]
+    fn test_noop_message() {
+        let server =
+            SmtpServer::new("test.local").with_noop_message("2.0.0 Still alive".to_string());
+        let (addr, _rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+
+        let response = send_command(reader.get_mut(), "NOOP").unwrap();
+        assert_eq!(response, "250 2.0.0 Still alive");
+        let response = send_command(reader.get_mut(), "NOOP probe").unwrap();
+        assert_eq!(response, "250 2.0.0 Still alive");
+    }
+
+    #[test]
+    fn test_long_reply_continued() {
+        let message = "word ".repeat(120);
+        let server = SmtpServer::new("test.local").with_noop_message(message.clone());
+        let (addr, _rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+
+        reader.get_mut().write_all(b"NOOP\r\n").unwrap();
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert!(line.len() <= SmtpLimits::REPLY_LINE_MAX_LENGTH);
+            let line = line.strip_suffix("\r\n").unwrap();
+            text.push_str(&line[4..]);
+            if line.starts_with("250 ") {
+                break;
+            }
+            assert!(line.starts_with("250-"));
+        }
+        assert_eq!(text, message);
+    }
+
+    #[test]
+    fn test_size_in_reply() {
+        let server = SmtpServer::new("test.local").with_size_in_reply(true);
+        let (addr, rx) = start_test_server_with(server);
+
+        // 14 + 2 + 2 + 4 + 2 bytes, not counting the terminating dot
+        let (response, _stream) = send_data(&addr, "Subject: Sized\r\n\r\nBody\r\n.");
+        assert_eq!(response, "250 OK 24 bytes received");
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_ok());
+    }
+
+    #[test]
+    fn test_size_in_reply_bare_lf() {
+        let server = SmtpServer::new("test.local").with_size_in_reply(true);
+        let (addr, rx) = start_test_server_with(server);
+
+        // 14 + 1 + 1 + 4 + 2 bytes, each line counted with its own ending
+        let (response, _stream) = send_data(&addr, "Subject: Sized\n\nBody\r\n.");
+        assert_eq!(response, "250 OK 22 bytes received");
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.raw_size(), 22);
+    }
+
+    #[test]
+    fn test_serve_until() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel();
+
+        let server =
+            thread::spawn(move || SmtpServer::new("test.local").serve_until(listener, tx, stop_rx));
+
+        send_message(&addr, "Before stop");
+        let email = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_subject(), Some("Before stop"));
+
+        stop_tx.send(()).unwrap();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_spool_large_message() {
+        let dir = std::env::temp_dir().join(format!("mogimail-spool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = SmtpServer::new("test.local")
+            .with_spool_dir(dir.clone())
+            .with_message_id(true);
+        let (addr, rx) = start_test_server_with(server);
+
+        let mut reader = begin_data(&addr);
+        let stream = reader.get_mut();
+
+        // About 20 MB of body, twice the in-memory limit
+        let line = format!("{}\r\n", "x".repeat(998));
+        let lines = 20_000;
+        let mut content = String::from("Subject: Large\r\n\r\n");
+        content.push_str(&line.repeat(lines));
+        content.push_str(".\r\n");
+        stream.write_all(content.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("250"));
+
+        let email = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(email.is_spooled());
+        assert_eq!(email.get_subject(), Some("Large"));
+        assert!(email.data.len() < 100);
+
+        // The Message-ID added after spooling is read before the file and
+        // counts toward the size
+        let message_id = format!(
+            "Message-ID: {}\r\n",
+            email.get_header("Message-ID").unwrap()
+        );
+        let expected = message_id.len() + content.len() - 3;
+        assert_eq!(email.raw_size(), expected);
+        let mut received = Vec::new();
+        email
+            .content_reader()
+            .unwrap()
+            .read_to_end(&mut received)
+            .unwrap();
+        assert_eq!(received.len(), expected);
+        let content = received.strip_prefix(message_id.as_bytes()).unwrap();
+        assert!(content.starts_with(b"Subject: Large\r\n\r\nxxx"));
+
+        let mut body = Vec::new();
+        email.body_reader().unwrap().read_to_end(&mut body).unwrap();
+        assert_eq!(body, line.repeat(lines).as_bytes());
+
+        drop(email);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_spool_within_headers() {
+        let dir = std::env::temp_dir().join(format!("mogimail-spool-hdr-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = SmtpServer::new("test.local").with_spool_dir(dir.clone());
+        let (addr, rx) = start_test_server_with(server);
+
+        let mut reader = begin_data(&addr);
+        let stream = reader.get_mut();
+
+        // The header section alone goes past the spool threshold
+        let mut content = String::from("Subject: Many headers\r\n");
+        let filler = format!("X-Filler: {}\r\n", "x".repeat(988));
+        content.push_str(&filler.repeat(1_100));
+        content.push_str("\r\nBody\r\n.\r\n");
+        stream.write_all(content.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("250"), "{response}");
+
+        let email = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(email.is_spooled());
+        assert_eq!(email.get_subject(), Some("Many headers"));
+        assert!(email.header_count("X-Filler") > 1_000);
+
+        drop(email);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_spool_size() {
+        let dir = std::env::temp_dir().join(format!("mogimail-spool-max-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server = SmtpServer::new("test.local")
+            .with_spool_dir(dir.clone())
+            .with_max_spool_size(2_000_000);
+        let (addr, rx) = start_test_server_with(server);
+
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
+        send_command(stream, "HELO client.local").unwrap();
+        let response = send_command(stream, "MAIL FROM:<sender@example.com> SIZE=2000001").unwrap();
+        assert!(response.starts_with("552"), "{response}");
+        send_command(stream, "MAIL FROM:<sender@example.com> SIZE=2000000").unwrap();
+        send_command(stream, "RCPT TO:<recipient@example.com>").unwrap();
+        send_command(stream, "DATA").unwrap();
+
+        // About 3 MB of body, spooled after the first MiB
+        let line = format!("{}\r\n", "x".repeat(998));
+        let mut content = String::from("Subject: Large\r\n\r\n");
+        content.push_str(&line.repeat(3_000));
+        content.push_str(".\r\n");
+        stream.write_all(content.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert_eq!(response, "552 Too much mail data (max 2000000 bytes)\r\n");
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        send_command(reader.get_mut(), "QUIT").unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_recipients() {
+        let replies = rcpt_replies(2, 3);
+        assert!(replies[0].starts_with("250"));
+        assert!(replies[1].starts_with("250"));
+        assert_eq!(replies[2], "552 Too many recipients (max 2)");
+    }
+
+    #[test]
+    fn test_max_recipients_zero() {
+        let replies = rcpt_replies(0, 1);
+        assert_eq!(replies[0], "552 Too many recipients (max 0)");
+    }
+
+    #[test]
+    fn test_verbatim_data() {
+        let server = SmtpServer::new("test.local").with_verbatim_data(true);
+        let (addr, rx) = start_test_server_with(server);
+        let mut reader = begin_data(&addr);
+        let stream = reader.get_mut();
+        stream
+            .write_all(b"Subject: Signed \r\n\r\nTrailing  \r\n..dot\nmid\rCR\r\n\r\n.\r\n")
+            .unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        assert!(response.starts_with("250"), "{response}");
+
+        let email = rx.recv_timeout(Duration::from_millis(200)).unwrap();
+        assert_eq!(
+            email.raw,
+            b"Subject: Signed \r\n\r\nTrailing  \r\n.dot\nmid\rCR\r\n\r\n"
+        );
+        assert!(email.verbatim);
+        assert_eq!(email.get_subject(), Some("Signed"));
+    }
 
-    fn start_test_server() -> (String, mpsc::Receiver<Email>) {
+    #[test]
+    fn test_max_messages() {
         let listener = TcpListener::bind("127.0.0.1:0").unwrap();
         let addr = listener.local_addr().unwrap().to_string();
-        let server = SmtpServer::new("test.local");
         let (tx, rx) = mpsc::channel();
-
-        // Start server in background thread
+        let (done_tx, done_rx) = mpsc::channel();
         thread::spawn(move || {
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        let command_handler = SmtpCommandHandler::new("test.local");
-                        if let Err(e) = server.handle_client(stream, &command_handler, &tx) {
-                            eprintln!("Error handling client: {e}");
-                        }
-                    }
-                    Err(_) => break,
-                }
-            }
+            let server = SmtpServer::new("test.local").with_max_messages(2);
+            let result = server.start_with_listener(listener, tx);
+            done_tx.send(result.is_ok()).unwrap();
         });
 
-        (addr, rx)
-    }
-
-    fn send_command(stream: &mut TcpStream, command: &str) -> Result<String, std::io::Error> {
-        writeln!(stream, "{command}")?;
-        stream.flush()?;
+        assert!(send_message(&addr, "first").starts_with("250"));
+        assert!(send_message(&addr, "second").starts_with("250"));
+        assert!(done_rx.recv_timeout(Duration::from_secs(1)).unwrap());
 
-        let mut reader = BufReader::new(stream);
-        let mut response = String::new();
-        reader.read_line(&mut response)?;
-        Ok(response.trim().to_string())
+        // The listener is gone, so the third message cannot be sent
+        assert!(TcpStream::connect(&addr).is_err());
+        assert_eq!(rx.iter().count(), 2);
     }
 
     #[test]
-    fn test_server_creation() {
-        let server = SmtpServer::new("test.local");
-        assert_eq!(server.hostname, "test.local");
+    fn test_max_messages_serve_on() {
+        let server = SmtpServer::new("test.local").with_max_messages(1);
+
+        // Messages accepted by a clone do not count toward the limit
+        let (earlier_addr, earlier_rx) = start_test_server_with(server.clone());
+        assert!(send_message(&earlier_addr, "earlier").starts_with("250"));
+        earlier_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let result = server.serve_on(listener, tx, ThreadPerConnection);
+            done_tx.send(result.is_ok()).unwrap();
+        });
+
+        // The server stops right after the message, without another client
+        assert!(send_message(&addr, "only").starts_with("250"));
+        assert!(done_rx.recv_timeout(Duration::from_secs(1)).unwrap());
+        assert!(TcpStream::connect(&addr).is_err());
+        assert_eq!(rx.recv().unwrap().get_subject(), Some("only"));
     }
 
     #[test]
-    fn test_complete_smtp_session() {
-        let (addr, rx) = start_test_server();
-
-        // Connect to server
-        let mut stream = TcpStream::connect(&addr).unwrap();
+    fn test_sync_sender_backpressure() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::sync_channel(1);
+        thread::spawn(move || {
+            SmtpServer::new("test.local")
+                .start_with_listener_sync_sender(listener, tx)
+                .unwrap()
+        });
 
-        // Read greeting
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
-        let mut greeting = String::new();
-        reader.read_line(&mut greeting).unwrap();
-        assert!(greeting.starts_with("220"));
+        let (_, mut reader) = read_greeting(&addr.to_string());
+        send_command(reader.get_mut(), "HELO client.local").unwrap();
 
-        // Send HELO
-        let response = send_command(&mut stream, "HELO client.local").unwrap();
-        assert!(response.starts_with("250"));
+        let send = |reader: &mut BufReader<TcpStream>, subject: &str| {
+            let stream = reader.get_mut();
+            send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+            send_command(stream, "RCPT TO:<recipient@example.com>").unwrap();
+            send_command(stream, "DATA").unwrap();
+            write!(stream, "Subject: {subject}\r\n\r\nBody\r\n.\r\n").unwrap();
+        };
+        let reply = |reader: &mut BufReader<TcpStream>| {
+            let mut response = String::new();
+            reader.read_line(&mut response).map(|_| response)
+        };
 
-        // Send MAIL FROM
-        let response = send_command(&mut stream, "MAIL FROM:<test@example.com>").unwrap();
-        assert!(response.starts_with("250"));
+        // The first email fits in the channel
+        send(&mut reader, "first");
+        assert!(reply(&mut reader).unwrap().starts_with("250"));
 
-        // Send RCPT TO
-        let response = send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
-        assert!(response.starts_with("250"));
+        // The second is held back until the consumer makes room
+        send(&mut reader, "second");
+        reader
+            .get_mut()
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(reply(&mut reader).is_err());
+        reader.get_mut().set_read_timeout(None).unwrap();
+        assert_eq!(rx.recv().unwrap().get_subject(), Some("first"));
+        assert!(reply(&mut reader).unwrap().starts_with("250"));
 
-        // Send DATA
-        let response = send_command(&mut stream, "DATA").unwrap();
-        assert!(response.starts_with("354"));
+        send(&mut reader, "third");
+        assert_eq!(rx.recv().unwrap().get_subject(), Some("second"));
+        assert!(reply(&mut reader).unwrap().starts_with("250"));
+        assert_eq!(rx.recv().unwrap().get_subject(), Some("third"));
 
-        // Send email content
-        writeln!(stream, "Subject: Test Email").unwrap();
-        writeln!(stream).unwrap();
-        writeln!(stream, "This is a test email.").unwrap();
-        writeln!(stream, ".").unwrap();
-        stream.flush().unwrap();
+        send_command(reader.get_mut(), "QUIT").unwrap();
+    }
 
-        // Read final response
-        let mut final_response = String::new();
-        reader.read_line(&mut final_response).unwrap();
-        assert!(final_response.starts_with("250"));
+    #[test]
+    fn test_normalize_addresses() {
+        let server = SmtpServer::new("test.local").with_normalize_addresses(true);
+        let (addr, rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
 
-        // Send QUIT
-        let response = send_command(&mut stream, "QUIT").unwrap();
-        assert!(response.starts_with("221"));
+        send_command(stream, "HELO client.local").unwrap();
+        send_command(stream, "MAIL FROM:<User@EXAMPLE.COM>").unwrap();
+        send_command(stream, "RCPT TO:<Rcpt@Example.Org>").unwrap();
+        send_command(stream, "DATA").unwrap();
+        send_command(stream, "Subject: Case\r\n\r\nBody\r\n.").unwrap();
 
-        // Wait for email to be processed
         let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
-        assert_eq!(email.from, "test@example.com");
-        assert_eq!(email.to, vec!["recipient@example.com"]);
-        assert!(email.data.contains("Subject: Test Email"));
-        assert!(email.data.contains("This is a test email."));
+        assert_eq!(email.from, "User@example.com");
+        assert_eq!(email.to, vec!["Rcpt@example.org"]);
     }
 
     #[test]
-    fn test_error_handling() {
-        let (addr, _rx) = start_test_server();
-
-        // Connect to server
-        let mut stream = TcpStream::connect(&addr).unwrap();
+    fn test_send_via_replays_email() {
+        let (first_addr, first_rx) = start_test_server();
+        let (second_addr, second_rx) = start_test_server();
 
-        // Read greeting
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
-        let mut greeting = String::new();
-        reader.read_line(&mut greeting).unwrap();
-        assert!(greeting.starts_with("220"));
+        let (response, _stream) = send_data(
+            &first_addr,
+            "Subject: Replay\r\n\r\n..leading dot\r\nlast line\r\n.",
+        );
+        assert!(response.starts_with("250"));
+        let captured = first_rx.recv_timeout(Duration::from_millis(100)).unwrap();
 
-        // Send invalid command
-        let response = send_command(&mut stream, "INVALID").unwrap();
-        assert!(response.starts_with("500"));
+        captured.send_via(&second_addr).unwrap();
+        let replayed = second_rx.recv_timeout(Duration::from_millis(500)).unwrap();
+        assert_eq!(replayed.from, captured.from);
+        assert_eq!(replayed.to, captured.to);
+        assert_eq!(replayed.raw, captured.raw);
+        assert_eq!(replayed.get_body(), Some(".leading dot\nlast line"));
+    }
 
-        // Try MAIL without HELO
-        let response = send_command(&mut stream, "MAIL FROM:<test@example.com>").unwrap();
-        assert!(response.starts_with("503") || response.starts_with("500"));
+    #[test]
+    fn test_send_via_rejected() {
+        let (addr, _rx) =
+            start_test_server_with(SmtpServer::new("test.local").with_max_recipients(0));
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Rejected\n\nBody".to_string(),
+        );
 
-        // Send QUIT
-        let response = send_command(&mut stream, "QUIT").unwrap();
-        assert!(response.starts_with("221"));
+        let err = email.send_via(&addr).unwrap_err();
+        assert!(matches!(err, SmtpError::UnexpectedReply(reply) if reply.starts_with("552")));
     }
 
     #[test]
-    fn test_multiple_recipients() {
-        let (addr, rx) = start_test_server();
+    fn test_send_via_timeout() {
+        // Accepts connections but never greets
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let email = Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            "Subject: Stalled\n\nBody".to_string(),
+        );
 
-        // Connect to server
-        let mut stream = TcpStream::connect(&addr).unwrap();
+        let start = Instant::now();
+        let err = email
+            .send_via_with_timeout(&addr, Duration::from_millis(100))
+            .unwrap_err();
+        assert!(matches!(err, SmtpError::Io(_)), "{err:?}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
 
-        // Read greeting
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
-        let mut greeting = String::new();
-        reader.read_line(&mut greeting).unwrap();
+    #[test]
+    fn test_rcpt_validator() {
+        let server =
+            SmtpServer::new("test.local").with_rcpt_validator(Box::new(|rcpt, session| {
+                assert_eq!(session.from.as_deref(), Some("sender@example.com"));
+                if rcpt.ends_with("@allowed.test") {
+                    Ok(())
+                } else {
+                    Err(("550".to_string(), "Recipient not allowed".to_string()))
+                }
+            }));
+        let (addr, rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
 
-        // Complete SMTP session with multiple recipients
-        send_command(&mut stream, "HELO client.local").unwrap();
-        send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
-        send_command(&mut stream, "RCPT TO:<recipient1@example.com>").unwrap();
-        send_command(&mut stream, "RCPT TO:<recipient2@example.com>").unwrap();
-        send_command(&mut stream, "DATA").unwrap();
+        send_command(stream, "HELO client.local").unwrap();
+        send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+        let replies: Vec<_> = ["a@allowed.test", "b@denied.test", "c@allowed.test"]
+            .iter()
+            .map(|rcpt| send_command(stream, &format!("RCPT TO:<{rcpt}>")).unwrap())
+            .collect();
+        assert_eq!(replies, ["250 OK", "550 Recipient not allowed", "250 OK"]);
 
-        writeln!(stream, "Subject: Multiple Recipients").unwrap();
-        writeln!(stream).unwrap();
-        writeln!(stream, "Test message for multiple recipients").unwrap();
-        writeln!(stream, ".").unwrap();
-        stream.flush().unwrap();
+        send_command(stream, "DATA").unwrap();
+        send_command(stream, "Subject: Filtered\r\n\r\nBody\r\n.").unwrap();
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(email.to, vec!["a@allowed.test", "c@allowed.test"]);
+        assert_eq!(
+            email.rejected_recipients().collect::<Vec<_>>(),
+            ["b@denied.test"]
+        );
+    }
 
-        // Read response
-        let mut response = String::new();
-        reader.read_line(&mut response).unwrap();
-        assert!(response.starts_with("250"));
+    #[test]
+    fn test_stray_line_in_command_state() {
+        let (addr, _rx) = start_test_server();
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
 
-        send_command(&mut stream, "QUIT").unwrap();
+        let response = send_command(stream, "This is not a command").unwrap();
+        assert!(response.starts_with("500 "), "{response}");
 
-        // Wait for email to be processed
-        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
-        assert_eq!(email.to.len(), 2);
-        assert!(email.to.contains(&"recipient1@example.com".to_string()));
-        assert!(email.to.contains(&"recipient2@example.com".to_string()));
+        // A second reply for the same line would be read here instead
+        let response = send_command(stream, "HELO client.local").unwrap();
+        assert!(response.starts_with("250 "), "{response}");
+        let response = send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+        assert_eq!(response, "250 OK");
     }
 
     #[test]
-    fn test_rset_command() {
-        let (addr, rx) = start_test_server();
+    fn test_echo_data_checksum() {
+        let server = SmtpServer::new("test.local").with_echo_data_checksum(true);
+        let (addr, rx) = start_test_server_with(server);
 
-        // Connect to server
-        let mut stream = TcpStream::connect(&addr).unwrap();
+        // CRC-32 of "Subject: Sum\r\n\r\n.dot\r\n"
+        let (response, _stream) = send_data(&addr, "Subject: Sum\r\n\r\n..dot\r\n.");
+        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
+        let expected = email.content_crc32().unwrap();
+        assert_eq!(response, format!("250 OK checksum={expected:08x}"));
+        assert_eq!(response, "250 OK checksum=121e4937");
+    }
 
-        // Read greeting
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
-        let mut greeting = String::new();
-        reader.read_line(&mut greeting).unwrap();
+    #[test]
+    fn test_bare_helo_strict() {
+        let (addr, _rx) = start_test_server();
+        let (_, mut reader) = read_greeting(&addr);
+        let response = send_command(reader.get_mut(), "HELO").unwrap();
+        assert_eq!(response, "501 Syntax error: HELO requires domain argument");
+    }
 
-        // Start transaction
-        send_command(&mut stream, "HELO client.local").unwrap();
-        send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
-        send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
+    #[test]
+    fn test_optional_angle_brackets() {
+        let server = SmtpServer::new("test.local").with_optional_angle_brackets(true);
+        let (addr, rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
 
-        // Reset transaction
-        let response = send_command(&mut stream, "RSET").unwrap();
-        assert!(response.starts_with("250"));
+        send_command(stream, "HELO client.local").unwrap();
+        let response = send_command(stream, "MAIL FROM:sender@example.com").unwrap();
+        assert_eq!(response, "250 OK");
+        let response = send_command(stream, "RCPT TO:recipient@example.com").unwrap();
+        assert_eq!(response, "250 OK");
+        send_command(stream, "DATA").unwrap();
+        send_command(stream, "Subject: Bare\r\n\r\nBody\r\n.").unwrap();
 
-        // Should be able to start new transaction
-        send_command(&mut stream, "MAIL FROM:<newsender@example.com>").unwrap();
-        send_command(&mut stream, "RCPT TO:<newrecipient@example.com>").unwrap();
-        send_command(&mut stream, "DATA").unwrap();
+        let email = rx.recv_timeout(Duration::from_millis(200)).unwrap();
+        assert_eq!(email.from, "sender@example.com");
+        assert_eq!(email.to, ["recipient@example.com"]);
+        assert_eq!(email.warnings.len(), 2);
+    }
 
-        writeln!(stream, "Subject: After Reset").unwrap();
-        writeln!(stream).unwrap();
-        writeln!(stream, "This message came after RSET").unwrap();
-        writeln!(stream, ".").unwrap();
-        stream.flush().unwrap();
+    #[test]
+    fn test_bare_helo_lenient() {
+        let server = SmtpServer::new("test.local").with_lenient_helo(true);
+        let (addr, rx) = start_test_server_with(server);
+        let (_, mut reader) = read_greeting(&addr);
+        let stream = reader.get_mut();
 
-        // Read response
-        let mut response = String::new();
-        reader.read_line(&mut response).unwrap();
+        let response = send_command(stream, "HELO").unwrap();
+        assert_eq!(response, "250 test.local Hello [127.0.0.1]");
+        send_command(stream, "MAIL FROM:<sender@example.com>").unwrap();
+        send_command(stream, "RCPT TO:<recipient@example.com>").unwrap();
+        send_command(stream, "DATA").unwrap();
+        let response = send_command(stream, "Subject: Bare\r\n\r\nBody\r\n.").unwrap();
         assert!(response.starts_with("250"));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_ok());
+    }
 
-        send_command(&mut stream, "QUIT").unwrap();
-
-        // Should only receive the email after RSET
+    #[test]
+    fn test_transcript_disabled_by_default() {
+        let (addr, rx) = start_test_server();
+        send_message(&addr, "Quiet");
         let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
-        assert_eq!(email.from, "newsender@example.com");
-        assert_eq!(email.to, vec!["newrecipient@example.com"]);
-
-        // Should not receive any more emails
-        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        assert!(email.transcript.is_empty());
     }
 
-    #[cfg(feature = "ehlo")]
+    #[cfg(feature = "tracing")]
     #[test]
-    fn test_ehlo_command() {
-        let (addr, rx) = start_test_server();
+    fn test_tracing_span_and_events() {
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
 
-        // Connect to server
-        let mut stream = TcpStream::connect(&addr).unwrap();
+        #[derive(Default)]
+        struct Recorder {
+            spans: Mutex<Vec<&'static str>>,
+            log: Mutex<Vec<String>>,
+        }
 
-        // Read greeting
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
-        let mut greeting = String::new();
-        reader.read_line(&mut greeting).unwrap();
-        assert!(greeting.starts_with("220"));
+        struct Message(String);
 
-        // Send EHLO
-        let response = send_command(&mut stream, "EHLO client.local").unwrap();
-        assert!(response.starts_with("250"));
+        impl tracing::field::Visit for Message {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
 
-        // Send MAIL FROM
-        let response = send_command(&mut stream, "MAIL FROM:<test@example.com>").unwrap();
-        assert!(response.starts_with("250"));
+        impl Subscriber for &'static Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
 
-        // Send RCPT TO
-        let response = send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
-        assert!(response.starts_with("250"));
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(span.metadata().name());
+                Id::from_u64(spans.len() as u64)
+            }
 
-        // Send DATA
-        let response = send_command(&mut stream, "DATA").unwrap();
-        assert!(response.starts_with("354"));
+            fn record(&self, _: &Id, _: &Record<'_>) {}
 
-        // Send email content
-        writeln!(stream, "Subject: EHLO Test Email").unwrap();
-        writeln!(stream).unwrap();
-        writeln!(stream, "This is a test.").unwrap();
-        writeln!(stream, ".").unwrap();
-        stream.flush().unwrap();
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
 
-        // Read final response
-        let mut final_response = String::new();
-        reader.read_line(&mut final_response).unwrap();
-        assert!(final_response.starts_with("250"));
+            fn event(&self, event: &Event<'_>) {
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                self.log.lock().unwrap().push(message.0);
+            }
 
-        // Send QUIT
-        let response = send_command(&mut stream, "QUIT").unwrap();
-        assert!(response.starts_with("221"));
+            fn enter(&self, span: &Id) {
+                let name = self.spans.lock().unwrap()[span.into_u64() as usize - 1];
+                self.log.lock().unwrap().push(format!("enter {name}"));
+            }
 
-        // Wait for email to be processed
-        let email = rx.recv_timeout(Duration::from_millis(100)).unwrap();
-        assert_eq!(email.from, "test@example.com");
-        assert_eq!(email.to, vec!["recipient@example.com"]);
-        assert!(email.data.contains("Subject: EHLO Test Email"));
-        assert!(email.data.contains("This is a test."));
+            fn exit(&self, _: &Id) {}
+        }
+
+        let recorder: &'static Recorder = Box::leak(Box::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let client = thread::spawn(move || {
+            let (response, mut stream) = send_data(&addr, "Subject: Traced\r\n\r\nBody\r\n.");
+            assert!(response.starts_with("250"));
+            stream.write_all(b"QUIT\r\n").unwrap();
+        });
+
+        // Serve the connection on this thread so the subscriber applies
+        let server = SmtpServer::new("test.local");
+        let (tx, rx) = mpsc::channel();
+        let (stream, _) = listener.accept().unwrap();
+        tracing::subscriber::with_default(recorder, || {
+            let handler = server.command_handler();
+            let id = server.next_connection_id();
+            server.handle_client(stream, id, &handler, &tx).unwrap();
+        });
+        client.join().unwrap();
+        assert!(rx.try_recv().is_ok());
+
+        let log = recorder.log.lock().unwrap();
+        assert_eq!(log.first().map(String::as_str), Some("enter smtp_conn"));
+        assert!(log.iter().any(|entry| entry == "command received"));
+        assert!(log.iter().any(|entry| entry == "response sent"));
+        assert!(log.iter().any(|entry| entry == "message accepted"));
     }
 }
@@ -1,7 +1,15 @@
 //! SMTP session state management
 
-use crate::smtp::email::Email;
+use crate::smtp::email::{DataLine, Direction, Email};
 use crate::smtp::error::{SmtpError, SmtpLimits};
+use crate::smtp::spool::Spool;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Size above which DATA content is moved to the spool directory, if set
+const SPOOL_THRESHOLD: usize = 1024 * 1024;
 
 /// Represents the current state of an SMTP session
 #[derive(Debug, Clone, PartialEq)]
@@ -16,38 +24,165 @@ pub enum SmtpState {
     RecipientsReceived,
     /// DATA command received - collecting email data
     DataMode,
+    /// At least one BDAT chunk received - collecting further chunks
+    #[cfg(feature = "chunking")]
+    ChunkMode,
+}
+
+/// Body type declared with the `BODY=` parameter of MAIL FROM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BodyType {
+    /// Plain 7-bit ASCII content (the default)
+    #[default]
+    SevenBit,
+    /// 8-bit MIME content sent with DATA
+    EightBitMime,
+    /// Binary MIME content, which must be sent with BDAT
+    #[cfg(feature = "binarymime")]
+    BinaryMime,
+}
+
+/// What to do with a DATA line longer than the text line limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineOverflowPolicy {
+    /// Reject the message with a line too long error (the default)
+    #[default]
+    Reject,
+    /// Cut the line so that, with its CRLF, it fits in
+    /// [`SmtpLimits::TEXT_LINE_MAX_LENGTH`] bytes, without splitting a UTF-8
+    /// character
+    Truncate,
+    /// Keep the whole line
+    Accept,
+}
+
+/// Identifies a client connection within a server
+///
+/// IDs are assigned in accept order, starting at 1 for each new
+/// [`SmtpServer`](crate::SmtpServer), so they are reproducible across runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(pub u64);
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A BDAT chunk announced by the client whose payload has not been read yet
+#[cfg(feature = "chunking")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PendingChunk {
+    /// Number of octets that follow the command
+    pub size: usize,
+    /// Whether this is the final chunk of the message
+    pub last: bool,
 }
 
 /// Manages the state and data for a single SMTP session
 #[derive(Debug)]
 pub struct SmtpSession {
+    /// ID of the connection this session runs on, if served by a server
+    pub connection_id: Option<ConnectionId>,
+    /// Label of the listener that accepted the connection, if any
+    pub listener_label: Option<String>,
+    /// Address of the connected client, if known
+    pub peer_addr: Option<SocketAddr>,
     /// Current state of the session
     pub state: SmtpState,
     /// Sender address from MAIL FROM command
     pub from: Option<String>,
     /// List of recipients from RCPT TO commands
     pub to: Vec<String>,
+    /// Every RCPT TO address attempted, in order, with whether it was accepted
+    pub recipient_status: Vec<(String, bool)>,
     /// Email data lines collected during DATA mode
     pub data: Vec<String>,
+    /// Per-line details of the data collected during DATA mode
+    pub data_lines: Vec<DataLine>,
+    /// Raw bytes of the collected data lines, each with its line ending
+    pub raw_data: Vec<u8>,
     /// Whether we're currently in data collection mode
     pub in_data_mode: bool,
     /// Total size of data collected so far
     pub data_size: usize,
     /// Client domain from HELO command
     pub client_domain: Option<String>,
+    /// Body type declared by MAIL FROM
+    pub body_type: BodyType,
+    /// Whether MAIL FROM carried the `SMTPUTF8` parameter
+    pub smtputf8: bool,
+    /// How DATA lines over the text line limit are handled
+    pub line_overflow_policy: LineOverflowPolicy,
+    /// Maximum number of recipients accepted per transaction
+    pub max_recipients: usize,
+    /// Maximum total size of message content, unless it is spooled
+    pub max_data_size: usize,
+    /// Whether the domain of each address is lowercased
+    pub normalize_addresses: bool,
+    /// Whether bare CRs within data lines are kept
+    pub verbatim_data: bool,
+    /// Source route given before the sender mailbox, e.g. `@relay1,@relay2`
+    pub sender_route: Option<String>,
+    /// Anomalies noticed during the current transaction
+    pub warnings: Vec<String>,
+    /// Whether lines and replies are recorded in `transcript`
+    pub capture_transcript: bool,
+    /// Lines and replies exchanged since the last reset
+    pub transcript: Vec<(Direction, String)>,
+    /// Directory that large DATA content is written to, if any
+    pub spool_dir: Option<PathBuf>,
+    /// Maximum size of spooled message content, if limited
+    pub max_spool_size: Option<usize>,
+    /// Spool file receiving the current message content, once it is large
+    pub(crate) spool: Option<Spool>,
+    /// Reply to the end of data, rendered once for the delivered email's
+    /// transcript and sent as is
+    pub(crate) rendered_reply: Option<String>,
+    /// Raw message content received via BDAT chunks
+    #[cfg(feature = "chunking")]
+    pub chunk_data: Vec<u8>,
+    /// BDAT chunk waiting for its payload to be read
+    #[cfg(feature = "chunking")]
+    pub pending_chunk: Option<PendingChunk>,
 }
 
 impl SmtpSession {
     /// Create a new SMTP session
     pub fn new() -> Self {
         Self {
+            connection_id: None,
+            listener_label: None,
+            peer_addr: None,
             state: SmtpState::Initial,
             from: None,
             to: Vec::new(),
+            recipient_status: Vec::new(),
             data: Vec::new(),
+            data_lines: Vec::new(),
+            raw_data: Vec::new(),
             in_data_mode: false,
             data_size: 0,
             client_domain: None,
+            body_type: BodyType::default(),
+            smtputf8: false,
+            line_overflow_policy: LineOverflowPolicy::default(),
+            max_recipients: SmtpLimits::MAX_RECIPIENTS,
+            max_data_size: SmtpLimits::MAX_DATA_SIZE,
+            normalize_addresses: false,
+            verbatim_data: false,
+            sender_route: None,
+            warnings: Vec::new(),
+            capture_transcript: false,
+            transcript: Vec::new(),
+            spool_dir: None,
+            max_spool_size: None,
+            spool: None,
+            #[cfg(feature = "chunking")]
+            chunk_data: Vec::new(),
+            #[cfg(feature = "chunking")]
+            pending_chunk: None,
+            rendered_reply: None,
         }
     }
 
@@ -56,9 +191,23 @@ impl SmtpSession {
         self.state = SmtpState::GreetingReceived;
         self.from = None;
         self.to.clear();
+        self.recipient_status.clear();
         self.data.clear();
+        self.data_lines.clear();
+        self.raw_data.clear();
         self.in_data_mode = false;
         self.data_size = 0;
+        self.body_type = BodyType::default();
+        self.smtputf8 = false;
+        self.sender_route = None;
+        self.warnings.clear();
+        self.transcript.clear();
+        self.spool = None;
+        #[cfg(feature = "chunking")]
+        {
+            self.chunk_data.clear();
+            self.pending_chunk = None;
+        }
         // Keep client_domain as it's set by HELO
     }
 
@@ -67,47 +216,112 @@ impl SmtpSession {
         self.state = SmtpState::Initial;
         self.from = None;
         self.to.clear();
+        self.recipient_status.clear();
         self.data.clear();
+        self.data_lines.clear();
+        self.raw_data.clear();
         self.in_data_mode = false;
         self.data_size = 0;
         self.client_domain = None;
+        self.body_type = BodyType::default();
+        self.smtputf8 = false;
+        self.sender_route = None;
+        self.warnings.clear();
+        self.transcript.clear();
+        self.spool = None;
+        #[cfg(feature = "chunking")]
+        {
+            self.chunk_data.clear();
+            self.pending_chunk = None;
+        }
+    }
+
+    /// Clear the recipients, keeping the sender
+    ///
+    /// Not an SMTP command: this lets a test reuse a sender across several
+    /// recipient sets without a full [`reset`](Self::reset). Any content
+    /// collected for the old recipients is discarded. With a sender set, the
+    /// session is then ready for `RCPT` again.
+    pub fn clear_recipients(&mut self) {
+        self.to.clear();
+        self.recipient_status.clear();
+        self.data.clear();
+        self.data_lines.clear();
+        self.raw_data.clear();
+        self.in_data_mode = false;
+        self.data_size = 0;
+        self.spool = None;
+        #[cfg(feature = "chunking")]
+        {
+            self.chunk_data.clear();
+            self.pending_chunk = None;
+        }
+        if self.from.is_some() {
+            self.state = SmtpState::MailReceived;
+        }
     }
 
     /// Set the sender address
     pub fn set_sender(&mut self, sender: String) -> Result<(), SmtpError> {
-        if sender.len() > SmtpLimits::PATH_MAX_LENGTH {
+        if sender.chars().count() > SmtpLimits::PATH_MAX_LENGTH {
             return Err(SmtpError::PathTooLong {
                 max: SmtpLimits::PATH_MAX_LENGTH,
             });
         }
 
-        self.from = Some(sender);
+        self.from = Some(self.normalize(sender));
         self.to.clear();
+        self.recipient_status.clear();
         self.data.clear();
+        self.data_lines.clear();
+        self.raw_data.clear();
         self.data_size = 0;
+        self.body_type = BodyType::default();
+        self.smtputf8 = false;
+        self.sender_route = None;
         self.state = SmtpState::MailReceived;
         Ok(())
     }
 
+    /// Lowercase the domain of an address if normalization is enabled
+    ///
+    /// Only the domain is case-insensitive (RFC 5321 section 2.4), so the
+    /// local part is kept as given.
+    fn normalize(&self, address: String) -> String {
+        match address.rsplit_once('@') {
+            Some((local, domain)) if self.normalize_addresses => {
+                format!("{local}@{}", domain.to_lowercase())
+            }
+            _ => address,
+        }
+    }
+
     /// Add a recipient address
     pub fn add_recipient(&mut self, recipient: String) -> Result<(), SmtpError> {
-        if recipient.len() > SmtpLimits::PATH_MAX_LENGTH {
+        if recipient.chars().count() > SmtpLimits::PATH_MAX_LENGTH {
             return Err(SmtpError::PathTooLong {
                 max: SmtpLimits::PATH_MAX_LENGTH,
             });
         }
 
-        if self.to.len() >= SmtpLimits::MAX_RECIPIENTS {
+        if self.to.len() >= self.max_recipients {
             return Err(SmtpError::TooManyRecipients {
-                max: SmtpLimits::MAX_RECIPIENTS,
+                max: self.max_recipients,
             });
         }
 
+        let recipient = self.normalize(recipient);
+        self.recipient_status.push((recipient.clone(), true));
         self.to.push(recipient);
         self.state = SmtpState::RecipientsReceived;
         Ok(())
     }
 
+    /// Record a recipient address that was rejected
+    pub fn reject_recipient(&mut self, recipient: String) {
+        self.recipient_status.push((recipient, false));
+    }
+
     /// Start data collection mode
     pub fn start_data_mode(&mut self) -> Result<(), SmtpError> {
         if self.state != SmtpState::RecipientsReceived {
@@ -118,6 +332,8 @@ impl SmtpSession {
 
         self.in_data_mode = true;
         self.data.clear();
+        self.data_lines.clear();
+        self.raw_data.clear();
         self.data_size = 0;
         self.state = SmtpState::DataMode;
         Ok(())
@@ -125,51 +341,233 @@ impl SmtpSession {
 
     /// Add a line of data during data collection
     pub fn add_data_line(&mut self, line: String) -> Result<(), SmtpError> {
-        let line_size = line.len() + 2; // +2 for CRLF
+        self.add_data_bytes(line.as_bytes())
+    }
 
-        if line_size > SmtpLimits::TEXT_LINE_MAX_LENGTH {
-            return Err(SmtpError::LineTooLong {
-                max: SmtpLimits::TEXT_LINE_MAX_LENGTH,
-            });
+    /// Add a line of raw data bytes during data collection
+    ///
+    /// The bytes are kept verbatim in `raw_data`, followed by CRLF, and decoded lossily into
+    /// `data`, so 8-bit content survives even when it is not UTF-8. `line`
+    /// excludes its terminator and counts toward the size limit by its byte
+    /// length plus two for the CRLF.
+    pub fn add_data_bytes(&mut self, line: &[u8]) -> Result<(), SmtpError> {
+        self.add_data_bytes_with_ending(line, b"\r\n")
+    }
+
+    /// Add a line of raw data bytes received with the line ending `ending`
+    ///
+    /// The same as [`add_data_bytes`](Self::add_data_bytes), except that
+    /// `raw_data` keeps `ending` after the line rather than CRLF, and the line
+    /// counts toward the size limit with the length of `ending`.
+    pub fn add_data_bytes_with_ending(
+        &mut self,
+        line: &[u8],
+        ending: &[u8],
+    ) -> Result<(), SmtpError> {
+        let mut line = line;
+        if line.len() + 2 > SmtpLimits::TEXT_LINE_MAX_LENGTH {
+            match self.line_overflow_policy {
+                LineOverflowPolicy::Reject => {
+                    return Err(SmtpError::TextLineTooLong {
+                        max: SmtpLimits::TEXT_LINE_MAX_LENGTH,
+                    });
+                }
+                LineOverflowPolicy::Truncate => {
+                    line = truncate_line(line, SmtpLimits::TEXT_LINE_MAX_LENGTH - 2);
+                }
+                LineOverflowPolicy::Accept => {}
+            }
         }
 
-        if self.data_size + line_size > SmtpLimits::MAX_DATA_SIZE {
+        let line_size = line.len() + ending.len();
+
+        if self.spool.is_none()
+            && self.data_size + line_size > SPOOL_THRESHOLD
+            && let Some(dir) = &self.spool_dir
+        {
+            self.start_spooling(Spool::create(dir)?)?;
+        }
+        if let Some(spool) = &mut self.spool {
+            if let Some(max) = self.max_spool_size
+                && self.data_size + line_size > max
+            {
+                return Err(SmtpError::TooMuchData { max });
+            }
+            spool.write(line)?;
+            spool.write(ending)?;
+            self.data_size += line_size;
+            return Ok(());
+        }
+
+        if self.data_size + line_size > self.max_data_size {
             return Err(SmtpError::TooMuchData {
-                max: SmtpLimits::MAX_DATA_SIZE,
+                max: self.max_data_size,
             });
         }
 
-        self.data.push(line);
+        self.raw_data.extend_from_slice(line);
+        self.raw_data.extend_from_slice(ending);
+        self.data.push(String::from_utf8_lossy(line).into_owned());
         self.data_size += line_size;
         Ok(())
     }
 
+    /// Move the content received so far to `spool`
+    ///
+    /// Only the header section stays in memory, so header accessors keep
+    /// working on the resulting email. Without a blank line yet, all the
+    /// content received so far is taken to be headers.
+    fn start_spooling(&mut self, mut spool: Spool) -> Result<(), SmtpError> {
+        spool.write(&self.raw_data)?;
+
+        let header_lines = self
+            .data
+            .iter()
+            .position(String::is_empty)
+            .map_or(self.data.len(), |i| i + 1);
+        let header_bytes: usize = self
+            .raw_data
+            .split(|&b| b == b'\n')
+            .take(header_lines)
+            .map(|line| line.len() + 1)
+            .sum();
+        self.data.truncate(header_lines);
+        self.data_lines.truncate(header_lines);
+        self.raw_data.truncate(header_bytes);
+
+        self.spool = Some(spool);
+        Ok(())
+    }
+
+    /// Check if DATA content is being written to a spool file
+    pub fn is_spooling(&self) -> bool {
+        self.spool.is_some()
+    }
+
+    /// Announce a BDAT chunk whose payload will be read next
+    #[cfg(feature = "chunking")]
+    pub fn start_chunk(&mut self, size: usize, last: bool) -> Result<(), SmtpError> {
+        match self.state {
+            SmtpState::RecipientsReceived => {
+                self.chunk_data.clear();
+                self.data_size = 0;
+            }
+            SmtpState::ChunkMode => {}
+            _ => {
+                return Err(SmtpError::InvalidState(
+                    "BDAT command requires RCPT first".to_string(),
+                ));
+            }
+        }
+
+        // The payload follows even when the chunk is rejected, so the
+        // server still needs to know how much to discard
+        self.pending_chunk = Some(PendingChunk { size, last });
+        self.state = SmtpState::ChunkMode;
+
+        match self.data_size.checked_add(size) {
+            Some(total) if total <= self.max_data_size => Ok(()),
+            _ => Err(SmtpError::TooMuchData {
+                max: self.max_data_size,
+            }),
+        }
+    }
+
+    /// Append the payload of a BDAT chunk
+    #[cfg(feature = "chunking")]
+    pub fn add_chunk(&mut self, chunk: &[u8]) -> Result<(), SmtpError> {
+        let total = self
+            .data_size
+            .checked_add(chunk.len())
+            .filter(|total| *total <= self.max_data_size)
+            .ok_or(SmtpError::TooMuchData {
+                max: self.max_data_size,
+            })?;
+
+        self.chunk_data.extend_from_slice(chunk);
+        self.data_size = total;
+        Ok(())
+    }
+
     /// Finish data collection and create an email
     pub fn finish_data_collection(&mut self) -> Result<Email, SmtpError> {
-        if !self.in_data_mode {
+        if !self.is_collecting_data() {
             return Err(SmtpError::InvalidState(
                 "Not in data collection mode".to_string(),
             ));
         }
 
-        let from = self
-            .from
-            .as_ref()
-            .ok_or_else(|| SmtpError::InvalidState("No sender specified".to_string()))?;
+        #[cfg(feature = "chunking")]
+        if self.state == SmtpState::ChunkMode {
+            // Normalize line endings in `data` to match line-oriented DATA
+            // collection. Chunks may hold binary content, so `raw` keeps
+            // the bytes exactly as received.
+            let chunk_data = std::mem::take(&mut self.chunk_data);
+            let mut lines: Vec<&[u8]> = chunk_data
+                .split(|&b| b == b'\n')
+                .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                .collect();
+            if chunk_data.ends_with(b"\n") {
+                lines.pop();
+            }
+
+            self.data = lines
+                .iter()
+                .map(|line| String::from_utf8_lossy(line).into_owned())
+                .collect();
+            self.raw_data = chunk_data;
+        }
+
+        let from = self.from.as_ref().ok_or_else(|| {
+            SmtpError::InvalidState("No sender specified (missing MAIL FROM)".to_string())
+        })?;
 
         if self.to.is_empty() {
             return Err(SmtpError::InvalidState(
-                "No recipients specified".to_string(),
+                "No recipients specified (missing RCPT TO)".to_string(),
             ));
         }
 
-        let email = Email::new(from.clone(), self.to.clone(), self.data.join("\n"));
+        let mut email = Email::new(from.clone(), self.to.clone(), self.data.join("\n"));
+        email.raw = std::mem::take(&mut self.raw_data);
+        email.verbatim = self.verbatim_data;
+        #[cfg(feature = "chunking")]
+        if self.state == SmtpState::ChunkMode {
+            email.verbatim = true;
+        }
+        email.data_lines = std::mem::take(&mut self.data_lines);
+        email.smtputf8 = self.smtputf8;
+        email.connection_id = self.connection_id;
+        email.listener_label = self.listener_label.clone();
+        email.warnings = std::mem::take(&mut self.warnings);
+        email.transcript = std::mem::take(&mut self.transcript);
+        if let Some(spool) = self.spool.take() {
+            email.spool = Some(Arc::new(spool.finish()?));
+        }
+        email.recipient_status = std::mem::take(&mut self.recipient_status);
 
         self.in_data_mode = false;
         self.state = SmtpState::GreetingReceived;
         Ok(email)
     }
 
+    /// Check if message content is being collected via DATA or BDAT
+    fn is_collecting_data(&self) -> bool {
+        #[cfg(feature = "chunking")]
+        if self.state == SmtpState::ChunkMode {
+            return true;
+        }
+
+        self.in_data_mode
+    }
+
+    /// Record a line of the conversation if transcript capture is enabled
+    pub fn record_transcript(&mut self, direction: Direction, line: &str) {
+        if self.capture_transcript {
+            self.transcript.push((direction, line.to_string()));
+        }
+    }
+
     /// Set the client domain from HELO command
     pub fn set_client_domain(&mut self, domain: String) -> Result<(), SmtpError> {
         if domain.len() > SmtpLimits::DOMAIN_MAX_LENGTH {
@@ -180,7 +578,12 @@ impl SmtpSession {
 
         self.client_domain = Some(domain);
         self.state = SmtpState::GreetingReceived;
-        self.reset(); // Clear any existing transaction
+
+        // Clear any existing transaction, but keep the greeting exchange in
+        // the transcript of the next message
+        let transcript = std::mem::take(&mut self.transcript);
+        self.reset();
+        self.transcript = transcript;
         Ok(())
     }
 
@@ -195,7 +598,11 @@ impl SmtpSession {
                 self.state == SmtpState::MailReceived || self.state == SmtpState::RecipientsReceived
             }
             "DATA" => self.state == SmtpState::RecipientsReceived,
-            "RSET" => self.state != SmtpState::Initial,
+            #[cfg(feature = "chunking")]
+            "BDAT" => {
+                self.state == SmtpState::RecipientsReceived || self.state == SmtpState::ChunkMode
+            }
+            "RSET" => true, // RSET can be sent at any time (RFC 5321 section 4.1.1.5)
             "NOOP" => true, // NOOP can be sent at any time
             "QUIT" => true, // QUIT can be sent at any time
             _ => false,
@@ -224,6 +631,20 @@ impl Default for SmtpSession {
     }
 }
 
+/// Cut `line` to at most `max` bytes, backing off so that a UTF-8 character
+/// is not split
+fn truncate_line(line: &[u8], max: usize) -> &[u8] {
+    if line.len() <= max {
+        return line;
+    }
+    // A UTF-8 character is at most four bytes, so back off at most three
+    let mut end = max;
+    while end > max.saturating_sub(3) && (line[end] & 0xC0) == 0x80 {
+        end -= 1;
+    }
+    &line[..end]
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -274,6 +695,29 @@ mod tests {
         assert_eq!(session.state, SmtpState::MailReceived);
     }
 
+    #[test]
+    fn test_normalize_addresses() {
+        let mut session = SmtpSession::new();
+        session
+            .set_client_domain("client.local".to_string())
+            .unwrap();
+
+        session.set_sender("User@EXAMPLE.COM".to_string()).unwrap();
+        assert_eq!(session.from, Some("User@EXAMPLE.COM".to_string()));
+
+        session.normalize_addresses = true;
+        session.set_sender("User@EXAMPLE.COM".to_string()).unwrap();
+        assert_eq!(session.from, Some("User@example.com".to_string()));
+        session
+            .add_recipient("Rcpt@Example.Org".to_string())
+            .unwrap();
+        assert_eq!(session.to, vec!["Rcpt@example.org".to_string()]);
+
+        // The null sender has no domain
+        session.set_sender(String::new()).unwrap();
+        assert_eq!(session.from, Some(String::new()));
+    }
+
     #[test]
     fn test_sender_path_too_long() {
         let mut session = SmtpSession::new();
@@ -366,7 +810,7 @@ mod tests {
 
         let long_line = "a".repeat(SmtpLimits::TEXT_LINE_MAX_LENGTH + 1);
         let result = session.add_data_line(long_line);
-        assert!(matches!(result, Err(SmtpError::LineTooLong { .. })));
+        assert!(matches!(result, Err(SmtpError::TextLineTooLong { .. })));
     }
 
     #[test]
@@ -380,7 +824,7 @@ mod tests {
         assert!(!session.can_execute_command("MAIL"));
         assert!(!session.can_execute_command("RCPT"));
         assert!(!session.can_execute_command("DATA"));
-        assert!(!session.can_execute_command("RSET"));
+        assert!(session.can_execute_command("RSET"));
 
         // After HELO
         session
@@ -431,6 +875,31 @@ mod tests {
         assert_eq!(session.client_domain, Some("client.local".to_string()));
     }
 
+    #[test]
+    fn test_clear_recipients() {
+        let mut session = SmtpSession::new();
+        session
+            .set_client_domain("client.local".to_string())
+            .unwrap();
+        session
+            .set_sender("sender@example.com".to_string())
+            .unwrap();
+        session.add_recipient("a@example.com".to_string()).unwrap();
+        session.add_recipient("b@example.com".to_string()).unwrap();
+
+        session.clear_recipients();
+
+        assert_eq!(session.from, Some("sender@example.com".to_string()));
+        assert!(session.to.is_empty());
+        assert!(session.recipient_status.is_empty());
+        assert_eq!(session.state, SmtpState::MailReceived);
+
+        // The sender is reused for the next recipient set
+        session.add_recipient("c@example.com".to_string()).unwrap();
+        assert_eq!(session.to, vec!["c@example.com"]);
+        assert_eq!(session.state, SmtpState::RecipientsReceived);
+    }
+
     #[test]
     fn test_full_reset() {
         let mut session = SmtpSession::new();
@@ -472,4 +941,155 @@ mod tests {
             .unwrap();
         assert!(session.has_complete_transaction());
     }
+
+    #[cfg(feature = "chunking")]
+    #[test]
+    fn test_chunk_collection() {
+        let mut session = SmtpSession::new();
+        session
+            .set_client_domain("client.local".to_string())
+            .unwrap();
+        session
+            .set_sender("sender@example.com".to_string())
+            .unwrap();
+        session
+            .add_recipient("recipient@example.com".to_string())
+            .unwrap();
+
+        session.start_chunk(15, false).unwrap();
+        session.add_chunk(b"Subject: Test\r\n").unwrap();
+        assert_eq!(session.state, SmtpState::ChunkMode);
+        assert!(!session.can_execute_command("DATA"));
+        assert!(session.can_execute_command("BDAT"));
+
+        // Oversized chunks are rejected before their payload is read
+        assert!(matches!(
+            session.start_chunk(usize::MAX, false),
+            Err(SmtpError::TooMuchData { .. })
+        ));
+        assert!(session.pending_chunk.is_some());
+
+        session.start_chunk(11, true).unwrap();
+        session.add_chunk(b"\r\nTest body").unwrap();
+
+        let email = session.finish_data_collection().unwrap();
+        assert_eq!(email.data, "Subject: Test\n\nTest body");
+        assert_eq!(email.raw, b"Subject: Test\r\n\r\nTest body");
+        assert!(email.verbatim);
+    }
+
+    #[cfg(feature = "chunking")]
+    #[test]
+    fn test_binary_chunk_kept_exactly() {
+        let mut session = SmtpSession::new();
+        session
+            .set_client_domain("client.local".to_string())
+            .unwrap();
+        session
+            .set_sender("sender@example.com".to_string())
+            .unwrap();
+        session
+            .add_recipient("recipient@example.com".to_string())
+            .unwrap();
+
+        let content = b"Subject: Binary\r\n\r\n\x00\xff\r\x89PNG\r\n\x1a\n\r\n";
+        session.start_chunk(content.len(), true).unwrap();
+        session.add_chunk(content).unwrap();
+
+        let email = session.finish_data_collection().unwrap();
+        assert_eq!(email.raw, content);
+        assert_eq!(email.get_subject(), Some("Binary"));
+    }
+
+    #[test]
+    fn test_finish_without_recipients() {
+        let mut session = SmtpSession::new();
+        session
+            .set_client_domain("client.local".to_string())
+            .unwrap();
+        session
+            .set_sender("sender@example.com".to_string())
+            .unwrap();
+
+        // Force data mode as if the RCPT check had been skipped
+        session.state = SmtpState::DataMode;
+        session.in_data_mode = true;
+
+        match session.finish_data_collection() {
+            Err(SmtpError::InvalidState(msg)) => assert!(msg.contains("RCPT TO"), "{msg}"),
+            other => panic!("expected InvalidState, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_line_overflow_policies() {
+        let long_line = vec![b'a'; 1500];
+
+        let mut session = SmtpSession::new();
+        assert!(matches!(
+            session.add_data_bytes(&long_line),
+            Err(SmtpError::TextLineTooLong { .. })
+        ));
+        assert!(session.data.is_empty());
+
+        session.line_overflow_policy = LineOverflowPolicy::Truncate;
+        session.add_data_bytes(&long_line).unwrap();
+        assert_eq!(session.data[0].len(), SmtpLimits::TEXT_LINE_MAX_LENGTH - 2);
+        assert_eq!(session.raw_data.len(), SmtpLimits::TEXT_LINE_MAX_LENGTH);
+        assert_eq!(session.data_size, SmtpLimits::TEXT_LINE_MAX_LENGTH);
+
+        // A multi-byte character across the limit is dropped whole
+        let mut session = SmtpSession::new();
+        session.line_overflow_policy = LineOverflowPolicy::Truncate;
+        let mut line = vec![b'a'; SmtpLimits::TEXT_LINE_MAX_LENGTH - 3];
+        line.extend_from_slice("é".repeat(100).as_bytes());
+        session.add_data_bytes(&line).unwrap();
+        assert_eq!(
+            session.data[0],
+            "a".repeat(SmtpLimits::TEXT_LINE_MAX_LENGTH - 3)
+        );
+        assert!(!session.data[0].contains('\u{FFFD}'));
+
+        let mut session = SmtpSession::new();
+        session.line_overflow_policy = LineOverflowPolicy::Accept;
+        session.add_data_bytes(&long_line).unwrap();
+        assert_eq!(session.data[0].len(), 1500);
+        assert_eq!(session.raw_data, [&long_line[..], b"\r\n"].concat());
+    }
+
+    #[test]
+    fn test_data_size_counts_line_endings() {
+        let mut session = SmtpSession::new();
+        session
+            .add_data_bytes_with_ending(b"Subject: LF", b"\n")
+            .unwrap();
+        session.add_data_bytes_with_ending(b"", b"\n").unwrap();
+        session.add_data_bytes(b"Body").unwrap();
+
+        assert_eq!(session.data_size, session.raw_data.len());
+        assert_eq!(session.data_size, 12 + 1 + 6);
+    }
+
+    #[test]
+    fn test_data_bytes_preserve_raw() {
+        let mut session = SmtpSession::new();
+        session
+            .set_client_domain("client.local".to_string())
+            .unwrap();
+        session
+            .set_sender("sender@example.com".to_string())
+            .unwrap();
+        session
+            .add_recipient("recipient@example.com".to_string())
+            .unwrap();
+        session.start_data_mode().unwrap();
+
+        session.add_data_bytes(b"Subject: Caf\xe9").unwrap();
+        session.add_data_bytes(b"").unwrap();
+        session.add_data_bytes(b"na\xefve").unwrap();
+
+        let email = session.finish_data_collection().unwrap();
+        assert_eq!(email.raw, b"Subject: Caf\xe9\r\n\r\nna\xefve\r\n");
+        assert_eq!(email.data, "Subject: Caf\u{FFFD}\n\nna\u{FFFD}ve");
+    }
 }
@@ -0,0 +1,114 @@
+//! On-disk spooling of large message content
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of spool files created by this process, used to name them
+static SPOOL_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Message content being written to a spool file
+#[derive(Debug)]
+pub(crate) struct Spool {
+    writer: BufWriter<File>,
+    file: SpoolFile,
+}
+
+impl Spool {
+    /// Create a new spool file in `dir`
+    pub(crate) fn create(dir: &Path) -> io::Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let count = SPOOL_FILES.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!(
+            "mogimail-{}-{nanos}-{count}.eml",
+            std::process::id()
+        ));
+
+        let writer = BufWriter::new(File::create_new(&path)?);
+        Ok(Self {
+            writer,
+            file: SpoolFile { path, size: 0 },
+        })
+    }
+
+    /// Append content to the spool file
+    pub(crate) fn write(&mut self, content: &[u8]) -> io::Result<()> {
+        self.writer.write_all(content)?;
+        self.file.size += content.len();
+        Ok(())
+    }
+
+    /// Flush the content and hand over the finished file
+    pub(crate) fn finish(mut self) -> io::Result<SpoolFile> {
+        self.writer.flush()?;
+        Ok(self.file)
+    }
+}
+
+/// A complete spool file, removed when dropped
+#[derive(Debug)]
+pub(crate) struct SpoolFile {
+    path: PathBuf,
+    size: usize,
+}
+
+impl SpoolFile {
+    /// Path of the file on disk
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of content bytes in the file
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_spool_file_removed_on_drop() {
+        let dir = std::env::temp_dir();
+        let mut spool = Spool::create(&dir).unwrap();
+        spool.write(b"first\r\n").unwrap();
+        spool.write(b"second\r\n").unwrap();
+
+        let file = spool.finish().unwrap();
+        assert_eq!(file.size(), 15);
+        let mut content = String::new();
+        File::open(file.path())
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "first\r\nsecond\r\n");
+
+        let path = file.path().to_path_buf();
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_unfinished_spool_removed_on_drop() {
+        let mut spool = Spool::create(&std::env::temp_dir()).unwrap();
+        spool.write(b"partial").unwrap();
+        let path = spool.file.path().to_path_buf();
+        assert!(path.exists());
+
+        drop(spool);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,179 @@
+//! In-memory storage for received emails
+
+use crate::smtp::email::Email;
+use crate::smtp::error::SmtpError;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// What to do when a bounded store is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    /// Evict the oldest stored email to make room
+    DropOldest,
+    /// Reject the new email with `452 Mailbox full`
+    RejectNew,
+}
+
+/// Thread-safe in-memory store of received emails
+///
+/// Cloning a store yields another handle to the same emails.
+#[derive(Debug, Clone)]
+pub struct MailStore {
+    /// Stored emails, oldest first
+    emails: Arc<Mutex<VecDeque<Email>>>,
+    /// Maximum number of emails kept, if bounded
+    capacity: Option<usize>,
+    /// Behavior when the store is full
+    eviction: Eviction,
+}
+
+impl MailStore {
+    /// Create an unbounded store
+    pub fn new() -> Self {
+        Self {
+            emails: Arc::new(Mutex::new(VecDeque::new())),
+            capacity: None,
+            eviction: Eviction::RejectNew,
+        }
+    }
+
+    /// Create a store holding at most `capacity` emails
+    ///
+    /// Nothing is allocated up front, so a large capacity costs nothing
+    /// until emails arrive.
+    pub fn with_capacity(capacity: usize, eviction: Eviction) -> Self {
+        Self {
+            emails: Arc::new(Mutex::new(VecDeque::new())),
+            capacity: Some(capacity),
+            eviction,
+        }
+    }
+
+    /// Add an email, applying the eviction policy if the store is full
+    pub fn push(&self, email: Email) -> Result<(), SmtpError> {
+        let mut emails = self.lock();
+
+        if let Some(capacity) = self.capacity
+            && emails.len() >= capacity
+        {
+            match self.eviction {
+                Eviction::DropOldest => {
+                    if capacity == 0 {
+                        return Ok(());
+                    }
+                    emails.pop_front();
+                }
+                Eviction::RejectNew => return Err(SmtpError::MailboxFull),
+            }
+        }
+
+        emails.push_back(email);
+        Ok(())
+    }
+
+    /// Get a copy of the stored emails, oldest first
+    pub fn emails(&self) -> Vec<Email> {
+        self.lock().iter().cloned().collect()
+    }
+
+    /// Get the number of stored emails
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Check if the store is empty
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Remove all stored emails
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Lock the email queue, recovering from a poisoned lock
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Email>> {
+        self.emails.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for MailStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(n: usize) -> Email {
+        Email::new(
+            "sender@example.com".to_string(),
+            vec!["recipient@example.com".to_string()],
+            format!("Subject: {n}"),
+        )
+    }
+
+    #[test]
+    fn test_unbounded_store() {
+        let store = MailStore::new();
+        assert!(store.is_empty());
+
+        for n in 0..5 {
+            store.push(email(n)).unwrap();
+        }
+        assert_eq!(store.len(), 5);
+
+        store.clear();
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let store = MailStore::with_capacity(2, Eviction::DropOldest);
+
+        for n in 0..3 {
+            store.push(email(n)).unwrap();
+        }
+
+        let subjects: Vec<_> = store
+            .emails()
+            .iter()
+            .map(|email| email.get_subject().unwrap().to_string())
+            .collect();
+        assert_eq!(subjects, ["1", "2"]);
+    }
+
+    #[test]
+    fn test_reject_new() {
+        let store = MailStore::with_capacity(2, Eviction::RejectNew);
+
+        store.push(email(0)).unwrap();
+        store.push(email(1)).unwrap();
+        let result = store.push(email(2));
+
+        assert!(matches!(result, Err(SmtpError::MailboxFull)));
+        assert_eq!(result.unwrap_err().to_response_code(), "452");
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.emails()[0].get_subject(), Some("0"));
+    }
+
+    #[test]
+    fn test_large_capacity() {
+        let store = MailStore::with_capacity(usize::MAX, Eviction::RejectNew);
+
+        store.push(email(0)).unwrap();
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_clones_share_emails() {
+        let store = MailStore::new();
+        let handle = store.clone();
+
+        store.push(email(0)).unwrap();
+        assert_eq!(handle.len(), 1);
+    }
+}
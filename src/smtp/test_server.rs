@@ -0,0 +1,135 @@
+//! Ready-made server for integration tests
+
+use crate::smtp::email::Email;
+use crate::smtp::executor::ThreadPerConnection;
+use crate::smtp::server::{SmtpServer, wake_accept_loop};
+
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// An SMTP server running on an ephemeral loopback port
+///
+/// The server is stopped when this value is dropped.
+///
+/// ```rust,no_run
+/// use mogimail::TestServer;
+/// use std::time::Duration;
+///
+/// let server = TestServer::start();
+/// println!("send mail to 127.0.0.1:{}", server.port());
+/// let email = server.recv(Duration::from_secs(1));
+/// ```
+#[derive(Debug)]
+pub struct TestServer {
+    /// Address the server is listening on
+    addr: SocketAddr,
+    /// Receiving end of the server's email channel
+    receiver: mpsc::Receiver<Email>,
+    /// Set to stop the accept loop
+    stop: Arc<AtomicBool>,
+    /// Thread running the accept loop
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Start a default server on `127.0.0.1` with an ephemeral port
+    pub fn start() -> Self {
+        Self::start_with(SmtpServer::new("localhost"))
+    }
+
+    /// Start the given server on `127.0.0.1` with an ephemeral port
+    pub fn start_with(server: SmtpServer) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind test server");
+        let addr = listener
+            .local_addr()
+            .expect("failed to get test server address");
+        let (tx, receiver) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            if let Err(e) = server.serve_on_until(listener, tx, ThreadPerConnection, &thread_stop) {
+                eprintln!("Error running test server: {e}");
+            }
+        });
+
+        Self {
+            addr,
+            receiver,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Get the address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Get the port the server is listening on
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// Wait up to `timeout` for the next received email
+    pub fn recv(&self, timeout: Duration) -> Option<Email> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        // Wake the accept loop so it notices the stop flag
+        wake_accept_loop(self.addr);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_start_and_drop() {
+        let server = TestServer::start();
+        let addr = server.addr();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), server.port());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut send = |line: &str| {
+            if !line.is_empty() {
+                stream.write_all(format!("{line}\r\n").as_bytes()).unwrap();
+            }
+            let mut reply = String::new();
+            reader.read_line(&mut reply).unwrap();
+            reply
+        };
+
+        assert!(send("").starts_with("220"));
+        send("HELO client.local");
+        send("MAIL FROM:<sender@example.com>");
+        send("RCPT TO:<recipient@example.com>");
+        send("DATA");
+        assert!(send("Subject: Harness\r\n.").starts_with("250"));
+        send("QUIT");
+
+        let email = server.recv(Duration::from_secs(1)).unwrap();
+        assert_eq!(email.get_subject(), Some("Harness"));
+        assert!(server.recv(Duration::from_millis(50)).is_none());
+
+        drop(server);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
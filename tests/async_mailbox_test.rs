@@ -0,0 +1,68 @@
+#![cfg(feature = "tokio")]
+
+use mogimail::{AsyncMailbox, SmtpServer};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+fn send_message(addr: &str, subject: &str) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut send = |line: &str| {
+        if !line.is_empty() {
+            stream.write_all(format!("{line}\r\n").as_bytes()).unwrap();
+        }
+        let mut reply = String::new();
+        reader.read_line(&mut reply).unwrap();
+        reply
+    };
+
+    assert!(send("").starts_with("220"));
+    send("HELO client.local");
+    send("MAIL FROM:<sender@example.com>");
+    send("RCPT TO:<recipient@example.com>");
+    send("DATA");
+    assert!(send(&format!("Subject: {subject}\r\n\r\nBody\r\n.")).starts_with("250"));
+    send("QUIT");
+}
+
+#[test]
+fn async_wait_for_matching() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let server = SmtpServer::new("localhost").with_on_email(Box::new(move |email| {
+        let _ = tx.blocking_send(email.clone());
+    }));
+    thread::spawn(move || {
+        let (email_tx, _email_rx) = mpsc::channel();
+        server.start_with_listener(listener, email_tx)
+    });
+
+    thread::spawn(move || {
+        send_message(&addr, "Other");
+        send_message(&addr, "Wanted");
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let mut mailbox = AsyncMailbox::new(rx);
+        let email = mailbox
+            .wait_for_matching(Duration::from_secs(1), |email| {
+                email.get_subject() == Some("Wanted")
+            })
+            .await
+            .expect("no matching email received");
+        assert_eq!(email.get_subject(), Some("Wanted"));
+
+        // The email passed over is still available
+        let email = mailbox.wait_for(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(email.get_subject(), Some("Other"));
+        assert!(mailbox.wait_for(Duration::from_millis(50)).await.is_none());
+    });
+}
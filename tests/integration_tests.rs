@@ -1,6 +1,6 @@
 //! Integration tests for size limits, UTF-8 handling, and comprehensive SMTP scenarios
 
-use mogimail::{SmtpLimits, SmtpServer};
+use mogimail::{Mailbox, SmtpLimits, SmtpServer, assert_empty};
 use std::io::{BufRead, BufReader, Write};
 use std::net::{TcpListener, TcpStream};
 use std::sync::mpsc;
@@ -476,3 +476,118 @@ fn test_concurrent_connections_with_limits() {
     // Verify all emails were received
     assert_eq!(emails.len(), 5);
 }
+
+#[test]
+fn test_mailbox_drain() {
+    let (addr, rx) = start_test_server();
+    let mailbox = Mailbox::new(rx);
+    let mut stream = TcpStream::connect(&addr).unwrap();
+
+    // Read greeting
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut greeting = String::new();
+    reader.read_line(&mut greeting).unwrap();
+
+    send_command(&mut stream, "HELO client.local").unwrap();
+
+    for email_num in 0..3 {
+        send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
+        send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
+        send_command(&mut stream, "DATA").unwrap();
+        let response =
+            send_command(&mut stream, &format!("Subject: Drain {email_num}\r\n.")).unwrap();
+        assert!(response.starts_with("250"));
+    }
+
+    // Emails reach the channel before the final reply is sent
+    let emails = mailbox.drain();
+    assert_eq!(emails.len(), 3);
+    assert_eq!(emails[0].get_subject(), Some("Drain 0"));
+    assert_eq!(emails[2].get_subject(), Some("Drain 2"));
+
+    assert!(mailbox.drain().is_empty());
+
+    send_command(&mut stream, "QUIT").unwrap();
+}
+
+#[test]
+fn test_assert_empty_after_rset() {
+    let (addr, rx) = start_test_server();
+    let mut stream = TcpStream::connect(&addr).unwrap();
+
+    // Read greeting
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut greeting = String::new();
+    reader.read_line(&mut greeting).unwrap();
+
+    send_command(&mut stream, "HELO client.local").unwrap();
+    send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
+    send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
+    send_command(&mut stream, "RSET").unwrap();
+
+    assert!(assert_empty(&rx, Duration::from_millis(100)).is_ok());
+
+    let mailbox = Mailbox::new(rx);
+    send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
+    send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
+    send_command(&mut stream, "DATA").unwrap();
+    send_command(&mut stream, "Subject: Delivered\r\n.").unwrap();
+
+    let email = mailbox
+        .assert_empty(Duration::from_millis(500))
+        .unwrap_err();
+    assert_eq!(email.get_subject(), Some("Delivered"));
+
+    send_command(&mut stream, "QUIT").unwrap();
+}
+
+#[test]
+fn test_data_without_recipients() {
+    let (addr, rx) = start_test_server();
+    let mut stream = TcpStream::connect(&addr).unwrap();
+
+    // Read greeting
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut greeting = String::new();
+    reader.read_line(&mut greeting).unwrap();
+
+    send_command(&mut stream, "HELO client.local").unwrap();
+    send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
+
+    // DATA is out of sequence without a recipient
+    let response = send_command(&mut stream, "DATA").unwrap();
+    assert!(response.starts_with("503"), "Expected 503, got: {response}");
+
+    // The transaction is intact and can still be completed
+    let response = send_command(&mut stream, "RCPT TO:<recipient@example.com>").unwrap();
+    assert!(response.starts_with("250"));
+    let response = send_command(&mut stream, "DATA").unwrap();
+    assert!(response.starts_with("354"));
+    let response = send_command(&mut stream, "Subject: Late recipient\r\n\r\nBody\r\n.").unwrap();
+    assert!(response.starts_with("250"));
+
+    let email = rx.recv_timeout(Duration::from_millis(500)).unwrap();
+    assert_eq!(email.from, "sender@example.com");
+    assert_eq!(email.to, vec!["recipient@example.com"]);
+
+    send_command(&mut stream, "QUIT").unwrap();
+}
+
+#[test]
+fn test_mail_before_helo() {
+    let (addr, _rx) = start_test_server();
+    let mut stream = TcpStream::connect(&addr).unwrap();
+
+    // Read greeting
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut greeting = String::new();
+    reader.read_line(&mut greeting).unwrap();
+
+    let response = send_command(&mut stream, "MAIL FROM:<sender@example.com>").unwrap();
+    assert_eq!(
+        response,
+        "503 Bad sequence of commands: MAIL command requires HELO first"
+    );
+
+    send_command(&mut stream, "QUIT").unwrap();
+}
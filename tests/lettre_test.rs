@@ -38,3 +38,61 @@ fn basic_lettre_send() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[cfg(feature = "test-utils")]
+#[test]
+fn harness_lettre_send() -> Result<(), Box<dyn Error>> {
+    let server = mogimail::TestServer::start();
+
+    let message = Message::builder()
+        .from("花子 <hanako@example.com>".parse::<Mailbox>()?)
+        .to("太郎 <tarou@example.com>".parse::<Mailbox>()?)
+        .subject("件名")
+        .body("本文".to_owned())
+        .unwrap();
+
+    let mailer = SmtpTransport::builder_dangerous("127.0.0.1")
+        .port(server.port())
+        .build();
+
+    mailer.send(&message)?;
+
+    let email = server
+        .recv(Duration::from_secs(1))
+        .ok_or("no email received")?;
+    assert_eq!(email.from, "hanako@example.com");
+    assert_eq!(email.to, vec!["tarou@example.com"]);
+
+    Ok(())
+}
+
+#[cfg(all(feature = "smtputf8", feature = "test-utils"))]
+#[test]
+fn utf8_lettre_send() -> Result<(), Box<dyn Error>> {
+    let server = mogimail::TestServer::start();
+
+    let message = Message::builder()
+        .from("花子 <花子@例え.jp>".parse::<Mailbox>()?)
+        .to("配達 <配達@例え.jp>".parse::<Mailbox>()?)
+        .subject("件名")
+        .header(lettre::message::header::ContentTransferEncoding::EightBit)
+        .body("本文です".to_owned())
+        .unwrap();
+
+    let mailer = SmtpTransport::builder_dangerous("127.0.0.1")
+        .port(server.port())
+        .build();
+
+    mailer.send(&message)?;
+
+    let email = server
+        .recv(Duration::from_secs(1))
+        .ok_or("no email received")?;
+    assert_eq!(email.from, "花子@例え.jp");
+    assert_eq!(email.to, vec!["配達@例え.jp"]);
+    assert!(email.smtputf8);
+    assert_eq!(email.get_body(), Some("本文です"));
+    assert!(email.raw.ends_with("本文です\r\n".as_bytes()));
+
+    Ok(())
+}